@@ -0,0 +1,1025 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::csr;
+use crate::isa::{EncodeError, InstructionType, B, FENCE, I, J, R, REGISTER_NAMES, S, U};
+
+const OP_LUI: u8 = 0b0110111;
+const OP_AUIPC: u8 = 0b0010111;
+const OP_JAL: u8 = 0b1101111;
+const OP_JALR: u8 = 0b1100111;
+const OP_BRANCH: u8 = 0b1100011;
+const OP_LOAD: u8 = 0b0000011;
+const OP_STORE: u8 = 0b0100011;
+const OP_IMM: u8 = 0b0010011;
+const OP: u8 = 0b0110011;
+const OP_MISC_MEM: u8 = 0b0001111;
+const OP_SYSTEM: u8 = 0b1110011;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    UnknownRegister(String),
+    UnknownCsr(String),
+    UndefinedSymbol(String),
+    DuplicateLabel(String),
+    InvalidOperand(String), // Not a number, symbol, register or memory reference
+    MissingOperand,         // Empty operand between commas
+    OperandCount(usize, usize), // Expected, found
+    OutOfRange(i64),        // Value that does not fit its field
+    MisalignedOffset(i64),  // Branch or jump offset that is not a multiple of 2
+    Encode(EncodeError),    // Fields the encoder rejected
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "Unknown mnemonic: {}", name),
+            AsmErrorKind::UnknownDirective(name) => write!(f, "Unknown directive: {}", name),
+            AsmErrorKind::UnknownRegister(name) => write!(f, "Unknown register: {}", name),
+            AsmErrorKind::UnknownCsr(name) => write!(f, "Unknown CSR: {}", name),
+            AsmErrorKind::UndefinedSymbol(name) => write!(f, "Undefined symbol: {}", name),
+            AsmErrorKind::DuplicateLabel(name) => write!(f, "Duplicate label: {}", name),
+            AsmErrorKind::InvalidOperand(text) => write!(f, "Invalid operand: {}", text),
+            AsmErrorKind::MissingOperand => write!(f, "Missing operand"),
+            AsmErrorKind::OperandCount(expected, found) => {
+                write!(f, "Expected {} operands, found {}", expected, found)
+            }
+            AsmErrorKind::OutOfRange(value) => write!(f, "Value out of range: {}", value),
+            AsmErrorKind::MisalignedOffset(offset) => write!(f, "Misaligned offset: {}", offset),
+            AsmErrorKind::Encode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// An assembly error at a 1-based line and column of the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Operand<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Statement<'a> {
+    line: usize,
+    column: usize,
+    mnemonic: String,
+    operands: Vec<Operand<'a>>,
+    addr: u32,
+    size: u32,
+}
+
+// Instruction classes of the base mnemonics, with the fields that pick the operation.
+#[derive(Debug, Clone, Copy)]
+enum Class {
+    Op(u8, u8), // funct7, funct3
+    OpImm(u8),
+    Shift(u8, u8), // funct7, funct3
+    Load(u8),
+    Store(u8),
+    Branch(u8),
+    Csr(u8),
+    CsrImm(u8),
+}
+
+fn class(mnemonic: &str) -> Option<Class> {
+    let class = match mnemonic {
+        "add" => Class::Op(0b0000000, 0b000),
+        "sub" => Class::Op(0b0100000, 0b000),
+        "sll" => Class::Op(0b0000000, 0b001),
+        "slt" => Class::Op(0b0000000, 0b010),
+        "sltu" => Class::Op(0b0000000, 0b011),
+        "xor" => Class::Op(0b0000000, 0b100),
+        "srl" => Class::Op(0b0000000, 0b101),
+        "sra" => Class::Op(0b0100000, 0b101),
+        "or" => Class::Op(0b0000000, 0b110),
+        "and" => Class::Op(0b0000000, 0b111),
+        "mul" => Class::Op(0b0000001, 0b000),
+        "mulh" => Class::Op(0b0000001, 0b001),
+        "mulhsu" => Class::Op(0b0000001, 0b010),
+        "mulhu" => Class::Op(0b0000001, 0b011),
+        "div" => Class::Op(0b0000001, 0b100),
+        "divu" => Class::Op(0b0000001, 0b101),
+        "rem" => Class::Op(0b0000001, 0b110),
+        "remu" => Class::Op(0b0000001, 0b111),
+        "addi" => Class::OpImm(0b000),
+        "slti" => Class::OpImm(0b010),
+        "sltiu" => Class::OpImm(0b011),
+        "xori" => Class::OpImm(0b100),
+        "ori" => Class::OpImm(0b110),
+        "andi" => Class::OpImm(0b111),
+        "slli" => Class::Shift(0b0000000, 0b001),
+        "srli" => Class::Shift(0b0000000, 0b101),
+        "srai" => Class::Shift(0b0100000, 0b101),
+        "lb" => Class::Load(0b000),
+        "lh" => Class::Load(0b001),
+        "lw" => Class::Load(0b010),
+        "lbu" => Class::Load(0b100),
+        "lhu" => Class::Load(0b101),
+        "sb" => Class::Store(0b000),
+        "sh" => Class::Store(0b001),
+        "sw" => Class::Store(0b010),
+        "beq" => Class::Branch(0b000),
+        "bne" => Class::Branch(0b001),
+        "blt" => Class::Branch(0b100),
+        "bge" => Class::Branch(0b101),
+        "bltu" => Class::Branch(0b110),
+        "bgeu" => Class::Branch(0b111),
+        "csrrw" => Class::Csr(0b001),
+        "csrrs" => Class::Csr(0b010),
+        "csrrc" => Class::Csr(0b011),
+        "csrrwi" => Class::CsrImm(0b101),
+        "csrrsi" => Class::CsrImm(0b110),
+        "csrrci" => Class::CsrImm(0b111),
+        _ => return None,
+    };
+
+    Some(class)
+}
+
+fn op(funct7: u8, funct3: u8, rd: u8, rs1: u8, rs2: u8) -> InstructionType {
+    InstructionType::R(R {
+        funct7,
+        rs2,
+        rs1,
+        funct3,
+        rd,
+        opcode: OP,
+    })
+}
+
+fn itype(opcode: u8, funct3: u8, rd: u8, rs1: u8, imm: i16) -> InstructionType {
+    InstructionType::I(I {
+        imm,
+        rs1,
+        funct3,
+        rd,
+        opcode,
+    })
+}
+
+fn addi(rd: u8, rs1: u8, imm: i16) -> InstructionType {
+    itype(OP_IMM, 0b000, rd, rs1, imm)
+}
+
+fn jalr(rd: u8, rs1: u8, imm: i16) -> InstructionType {
+    itype(OP_JALR, 0b000, rd, rs1, imm)
+}
+
+fn branch(funct3: u8, rs1: u8, rs2: u8, imm: i16) -> InstructionType {
+    InstructionType::B(B {
+        imm,
+        rs2,
+        rs1,
+        funct3,
+        opcode: OP_BRANCH,
+    })
+}
+
+fn jal(rd: u8, imm: i32) -> InstructionType {
+    InstructionType::J(J {
+        imm,
+        rd,
+        opcode: OP_JAL,
+    })
+}
+
+fn upper(opcode: u8, rd: u8, imm: u32) -> InstructionType {
+    InstructionType::U(U { imm, rd, opcode })
+}
+
+// Split `value` into the parts loaded by LUI/AUIPC and ADDI, accounting for ADDI
+// sign-extending its immediate.
+fn hi(value: i64) -> i64 {
+    ((value + 0x800) >> 12) & 0xFFFFF
+}
+
+fn lo(value: i64) -> i64 {
+    ((value & 0xFFF) ^ 0x800) - 0x800
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$'
+}
+
+fn is_ident(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Drop a trailing `#` or `//` comment, ignoring either inside a string literal.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            '/' if !in_string && line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+// Split operands on commas outside string literals, keeping each one's column.
+fn split_operands(line: &str, start: usize) -> Vec<Operand<'_>> {
+    let text = &line[start..];
+    let mut operands = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut begin = 0;
+
+    let mut push = |begin: usize, end: usize| {
+        let part = &text[begin..end];
+        let leading = part.len() - part.trim_start().len();
+        operands.push(Operand {
+            text: part.trim(),
+            column: start + begin + leading + 1,
+        });
+    };
+
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                push(begin, i);
+                begin = i + 1;
+            }
+            _ => {}
+        }
+    }
+    push(begin, text.len());
+
+    operands
+}
+
+fn parse_string(op: &Operand, line: usize) -> Result<Vec<u8>, AsmError> {
+    let invalid = || AsmError {
+        line,
+        column: op.column,
+        kind: AsmErrorKind::InvalidOperand(op.text.to_string()),
+    };
+
+    let inner = op
+        .text
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next().ok_or_else(invalid)? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '"') => c,
+                _ => return Err(invalid()),
+            }
+        } else {
+            c
+        };
+
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+
+    Ok(bytes)
+}
+
+struct Assembler {
+    base: u32,
+    symbols: HashMap<String, u32>,
+    line: usize,
+}
+
+impl Assembler {
+    fn error(&self, column: usize, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.line,
+            column,
+            kind,
+        }
+    }
+
+    fn expect(&self, stmt: &Statement, count: usize) -> Result<(), AsmError> {
+        if stmt.operands.len() != count {
+            return Err(self.error(
+                stmt.column,
+                AsmErrorKind::OperandCount(count, stmt.operands.len()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn register(&self, op: &Operand) -> Result<u8, AsmError> {
+        let name = op.text.to_ascii_lowercase();
+        let number = match name.strip_prefix('x') {
+            // x0-x31, without leading zeros
+            Some(n) if n.parse::<u8>().is_ok_and(|r| r < 32 && r.to_string() == n) => {
+                n.parse().ok()
+            }
+            _ if name == "fp" => Some(8),
+            _ => REGISTER_NAMES
+                .iter()
+                .position(|n| *n == name)
+                .map(|n| n as u8),
+        };
+
+        number.ok_or_else(|| {
+            self.error(
+                op.column,
+                AsmErrorKind::UnknownRegister(op.text.to_string()),
+            )
+        })
+    }
+
+    // The CSR address, sign-extended the way it sits in an I-type immediate.
+    fn csr(&self, op: &Operand) -> Result<i16, AsmError> {
+        let csr = match csr::by_name(&op.text.to_ascii_lowercase()) {
+            Some(csr) => csr,
+            None => match parse_int(op.text) {
+                Some(value) if (0..=0xFFF).contains(&value) => value as u16,
+                Some(value) => return Err(self.error(op.column, AsmErrorKind::OutOfRange(value))),
+                None => {
+                    return Err(self.error(op.column, AsmErrorKind::UnknownCsr(op.text.to_string())))
+                }
+            },
+        };
+
+        Ok(((csr << 4) as i16) >> 4)
+    }
+
+    // A single number or symbol, optionally negated.
+    fn term(&self, text: &str, column: usize) -> Result<i64, AsmError> {
+        let leading = text.len() - text.trim_start().len();
+        let text = text.trim();
+        let column = column + leading;
+
+        if let Some(rest) = text.strip_prefix('-') {
+            return Ok(-self.term(rest, column + 1)?);
+        }
+
+        if text.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_int(text)
+                .ok_or_else(|| self.error(column, AsmErrorKind::InvalidOperand(text.to_string())));
+        }
+
+        if text.starts_with(is_ident_start) && text.chars().all(is_ident) {
+            return self
+                .symbols
+                .get(text)
+                .map(|addr| *addr as i64)
+                .ok_or_else(|| {
+                    self.error(column, AsmErrorKind::UndefinedSymbol(text.to_string()))
+                });
+        }
+
+        let kind = if text.is_empty() {
+            AsmErrorKind::MissingOperand
+        } else {
+            AsmErrorKind::InvalidOperand(text.to_string())
+        };
+        Err(self.error(column, kind))
+    }
+
+    // Evaluate `term (+|- term)*`, or `%hi(...)` / `%lo(...)` of one.
+    fn value(&self, op: &Operand) -> Result<i64, AsmError> {
+        for (prefix, part) in [("%hi(", hi as fn(i64) -> i64), ("%lo(", lo)] {
+            if let Some(inner) = op.text.strip_prefix(prefix) {
+                let inner = inner.strip_suffix(')').ok_or_else(|| {
+                    self.error(op.column, AsmErrorKind::InvalidOperand(op.text.to_string()))
+                })?;
+                let inner = Operand {
+                    text: inner,
+                    column: op.column + prefix.len(),
+                };
+                return Ok(part(self.value(&inner)?));
+            }
+        }
+
+        let text = op.text;
+        let mut total = 0i64;
+        let mut sign = 1;
+        let mut start = 0;
+        for (i, c) in text.char_indices() {
+            if (c == '+' || c == '-') && !text[start..i].trim().is_empty() {
+                total += sign * self.term(&text[start..i], op.column + start)?;
+                sign = if c == '-' { -1 } else { 1 };
+                start = i + 1;
+            }
+        }
+
+        Ok(total + sign * self.term(&text[start..], op.column + start)?)
+    }
+
+    fn ranged(&self, op: &Operand, min: i64, max: i64) -> Result<i64, AsmError> {
+        let value = self.value(op)?;
+        if value < min || value > max {
+            return Err(self.error(op.column, AsmErrorKind::OutOfRange(value)));
+        }
+
+        Ok(value)
+    }
+
+    fn imm12(&self, op: &Operand) -> Result<i16, AsmError> {
+        Ok(self.ranged(op, -2048, 2047)? as i16)
+    }
+
+    fn uimm5(&self, op: &Operand) -> Result<u8, AsmError> {
+        Ok(self.ranged(op, 0, 31)? as u8)
+    }
+
+    // PC-relative offset from `pc` to the target operand, checked against a field of
+    // `bits` bits whose lowest bit is implied zero.
+    fn offset(&self, op: &Operand, pc: u32, bits: u32) -> Result<i64, AsmError> {
+        let offset = self.value(op)? - pc as i64;
+        if offset % 2 != 0 {
+            return Err(self.error(op.column, AsmErrorKind::MisalignedOffset(offset)));
+        }
+
+        let limit = 1i64 << (bits - 1);
+        if offset < -limit || offset >= limit {
+            return Err(self.error(op.column, AsmErrorKind::OutOfRange(offset)));
+        }
+
+        Ok(offset)
+    }
+
+    // `offset(reg)`, where the offset may be omitted.
+    fn memory(&self, op: &Operand) -> Result<(i16, u8), AsmError> {
+        let invalid = || self.error(op.column, AsmErrorKind::InvalidOperand(op.text.to_string()));
+
+        let inner = op.text.strip_suffix(')').ok_or_else(invalid)?;
+        let open = inner.rfind('(').ok_or_else(invalid)?;
+        let reg = Operand {
+            text: inner[open + 1..].trim(),
+            column: op.column + open + 1,
+        };
+        let offset = Operand {
+            text: inner[..open].trim(),
+            column: op.column,
+        };
+
+        let imm = if offset.text.is_empty() {
+            0
+        } else {
+            self.imm12(&offset)?
+        };
+
+        Ok((imm, self.register(&reg)?))
+    }
+
+    // `li` takes one instruction when the value fits ADDI or has no low bits, two
+    // otherwise. Values that involve symbols always take two so the size is known
+    // before the symbols are.
+    fn li_size(&self, op: &Operand) -> u32 {
+        match op
+            .text
+            .starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        {
+            true => match self.value(op).map(|value| value as i32 as i64) {
+                Ok(value) if (-2048..=2047).contains(&value) || lo(value) == 0 => 4,
+                _ => 8,
+            },
+            false => 8,
+        }
+    }
+
+    fn size(&self, stmt: &Statement, addr: u32) -> Result<u32, AsmError> {
+        let count = stmt.operands.len() as u32;
+        let size = match stmt.mnemonic.as_str() {
+            "li" if count == 2 => self.li_size(&stmt.operands[1]),
+            "la" | "call" | "tail" => 8,
+            ".word" => 4 * count,
+            ".half" => 2 * count,
+            ".byte" => count,
+            ".ascii" | ".asciz" | ".string" => {
+                let mut size = 0;
+                for op in &stmt.operands {
+                    size += parse_string(op, self.line)?.len() as u32;
+                    if stmt.mnemonic != ".ascii" {
+                        size += 1;
+                    }
+                }
+                size
+            }
+            ".zero" | ".space" => {
+                self.expect(stmt, 1)?;
+                self.ranged(&stmt.operands[0], 0, u32::MAX as i64)? as u32
+            }
+            ".align" | ".p2align" | ".balign" => {
+                self.expect(stmt, 1)?;
+                let align = match stmt.mnemonic.as_str() {
+                    ".balign" => self.ranged(&stmt.operands[0], 1, 1 << 16)? as u32,
+                    _ => 1 << self.ranged(&stmt.operands[0], 0, 16)?,
+                };
+                if !align.is_power_of_two() {
+                    return Err(self.error(
+                        stmt.operands[0].column,
+                        AsmErrorKind::InvalidOperand(stmt.operands[0].text.to_string()),
+                    ));
+                }
+                addr.next_multiple_of(align) - addr
+            }
+            ".equ" | ".set" | ".globl" | ".global" | ".text" | ".data" | ".bss" | ".section"
+            | ".type" | ".size" | ".option" | ".file" => 0,
+            name if name.starts_with('.') => {
+                return Err(self.error(
+                    stmt.column,
+                    AsmErrorKind::UnknownDirective(name.to_string()),
+                ))
+            }
+            _ => 4,
+        };
+
+        Ok(size)
+    }
+
+    fn define(&mut self, name: &str, value: u32, column: usize) -> Result<(), AsmError> {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(self.error(column, AsmErrorKind::DuplicateLabel(name.to_string())));
+        }
+
+        Ok(())
+    }
+
+    // Lay out every statement and record the address of every label.
+    fn first_pass<'a>(&mut self, source: &'a str) -> Result<Vec<Statement<'a>>, AsmError> {
+        let mut statements = Vec::new();
+        let mut addr = self.base;
+
+        for (number, line) in source.lines().enumerate() {
+            self.line = number + 1;
+            let code = strip_comment(line);
+            let mut pos = 0;
+
+            // Any number of `label:` prefixes.
+            loop {
+                let rest = &code[pos..];
+                let start = pos + rest.len() - rest.trim_start().len();
+                let len = code[start..]
+                    .find(|c: char| !is_ident(c))
+                    .unwrap_or(code.len() - start);
+                let name = &code[start..start + len];
+                if len == 0
+                    || !name.starts_with(is_ident_start)
+                    || !code[start + len..].starts_with(':')
+                {
+                    pos = start;
+                    break;
+                }
+
+                self.define(name, addr, start + 1)?;
+                pos = start + len + 1;
+            }
+
+            if code[pos..].trim().is_empty() {
+                continue;
+            }
+
+            let len = code[pos..]
+                .find(char::is_whitespace)
+                .unwrap_or(code.len() - pos);
+            let mnemonic = code[pos..pos + len].to_ascii_lowercase();
+            let operands = if code[pos + len..].trim().is_empty() {
+                Vec::new()
+            } else {
+                split_operands(code, pos + len)
+            };
+
+            let mut stmt = Statement {
+                line: self.line,
+                column: pos + 1,
+                mnemonic,
+                operands,
+                addr,
+                size: 0,
+            };
+
+            if let Some(op) = stmt.operands.iter().find(|op| op.text.is_empty()) {
+                return Err(self.error(op.column, AsmErrorKind::MissingOperand));
+            }
+
+            if stmt.mnemonic == ".equ" || stmt.mnemonic == ".set" {
+                self.expect(&stmt, 2)?;
+                let value = self.value(&stmt.operands[1])?;
+                self.define(stmt.operands[0].text, value as u32, stmt.operands[0].column)?;
+            }
+
+            stmt.size = self.size(&stmt, addr)?;
+            addr = addr.wrapping_add(stmt.size);
+            statements.push(stmt);
+        }
+
+        Ok(statements)
+    }
+
+    fn data(&self, stmt: &Statement, width: usize) -> Result<Vec<u8>, AsmError> {
+        let (min, max) = match width {
+            4 => (i32::MIN as i64, u32::MAX as i64),
+            2 => (i16::MIN as i64, u16::MAX as i64),
+            _ => (i8::MIN as i64, u8::MAX as i64),
+        };
+
+        let mut bytes = Vec::new();
+        for op in &stmt.operands {
+            let value = self.ranged(op, min, max)? as u32;
+            bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+
+        Ok(bytes)
+    }
+
+    fn directive(&self, stmt: &Statement) -> Result<Vec<u8>, AsmError> {
+        let bytes = match stmt.mnemonic.as_str() {
+            ".word" => self.data(stmt, 4)?,
+            ".half" => self.data(stmt, 2)?,
+            ".byte" => self.data(stmt, 1)?,
+            ".ascii" | ".asciz" | ".string" => {
+                let mut bytes = Vec::new();
+                for op in &stmt.operands {
+                    bytes.extend(parse_string(op, self.line)?);
+                    if stmt.mnemonic != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                bytes
+            }
+            _ => vec![0; stmt.size as usize],
+        };
+
+        Ok(bytes)
+    }
+
+    // Expand pseudo-instructions and pick the fields of base instructions.
+    fn instruction(&self, stmt: &Statement) -> Result<Vec<InstructionType>, AsmError> {
+        let ops = &stmt.operands;
+        let pc = stmt.addr;
+
+        if let Some(class) = class(&stmt.mnemonic) {
+            self.expect(
+                stmt,
+                if matches!(class, Class::Load(_) | Class::Store(_)) {
+                    2
+                } else {
+                    3
+                },
+            )?;
+
+            let inst = match class {
+                Class::Op(funct7, funct3) => op(
+                    funct7,
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.register(&ops[1])?,
+                    self.register(&ops[2])?,
+                ),
+                Class::OpImm(funct3) => itype(
+                    OP_IMM,
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.register(&ops[1])?,
+                    self.imm12(&ops[2])?,
+                ),
+                Class::Shift(funct7, funct3) => itype(
+                    OP_IMM,
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.register(&ops[1])?,
+                    ((funct7 as i16) << 5) | self.uimm5(&ops[2])? as i16,
+                ),
+                Class::Load(funct3) => {
+                    let rd = self.register(&ops[0])?;
+                    let (imm, rs1) = self.memory(&ops[1])?;
+                    itype(OP_LOAD, funct3, rd, rs1, imm)
+                }
+                Class::Store(funct3) => {
+                    let rs2 = self.register(&ops[0])?;
+                    let (imm, rs1) = self.memory(&ops[1])?;
+                    InstructionType::S(S {
+                        imm,
+                        rs2,
+                        rs1,
+                        funct3,
+                        opcode: OP_STORE,
+                    })
+                }
+                Class::Branch(funct3) => branch(
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.register(&ops[1])?,
+                    self.offset(&ops[2], pc, 13)? as i16,
+                ),
+                Class::Csr(funct3) => itype(
+                    OP_SYSTEM,
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.register(&ops[2])?,
+                    self.csr(&ops[1])?,
+                ),
+                Class::CsrImm(funct3) => itype(
+                    OP_SYSTEM,
+                    funct3,
+                    self.register(&ops[0])?,
+                    self.uimm5(&ops[2])?,
+                    self.csr(&ops[1])?,
+                ),
+            };
+
+            return Ok(vec![inst]);
+        }
+
+        let insts = match stmt.mnemonic.as_str() {
+            "lui" | "auipc" => {
+                self.expect(stmt, 2)?;
+                let opcode = if stmt.mnemonic == "lui" {
+                    OP_LUI
+                } else {
+                    OP_AUIPC
+                };
+                vec![upper(
+                    opcode,
+                    self.register(&ops[0])?,
+                    self.ranged(&ops[1], 0, 0xFFFFF)? as u32,
+                )]
+            }
+            "jal" if ops.len() == 1 => vec![jal(1, self.offset(&ops[0], pc, 21)? as i32)],
+            "jal" => {
+                self.expect(stmt, 2)?;
+                vec![jal(
+                    self.register(&ops[0])?,
+                    self.offset(&ops[1], pc, 21)? as i32,
+                )]
+            }
+            "jalr" => match ops.len() {
+                1 => vec![jalr(1, self.register(&ops[0])?, 0)],
+                2 if ops[1].text.ends_with(')') => {
+                    let (imm, rs1) = self.memory(&ops[1])?;
+                    vec![jalr(self.register(&ops[0])?, rs1, imm)]
+                }
+                2 => vec![jalr(self.register(&ops[0])?, self.register(&ops[1])?, 0)],
+                _ => {
+                    self.expect(stmt, 3)?;
+                    vec![jalr(
+                        self.register(&ops[0])?,
+                        self.register(&ops[1])?,
+                        self.imm12(&ops[2])?,
+                    )]
+                }
+            },
+            "fence" => {
+                let (pred, succ) = match ops.len() {
+                    0 => (0b1111, 0b1111),
+                    _ => {
+                        self.expect(stmt, 2)?;
+                        (self.fence_set(&ops[0])?, self.fence_set(&ops[1])?)
+                    }
+                };
+                vec![InstructionType::FENCE(FENCE {
+                    fm: 0,
+                    pred,
+                    succ,
+                    rs1: 0,
+                    funct3: 0,
+                    rd: 0,
+                    opcode: OP_MISC_MEM,
+                })]
+            }
+            "fence.i" => {
+                self.expect(stmt, 0)?;
+                vec![InstructionType::FENCE(FENCE {
+                    fm: 0,
+                    pred: 0,
+                    succ: 0,
+                    rs1: 0,
+                    funct3: 0b001,
+                    rd: 0,
+                    opcode: OP_MISC_MEM,
+                })]
+            }
+            "ecall" | "ebreak" | "mret" | "wfi" => {
+                self.expect(stmt, 0)?;
+                let imm = match stmt.mnemonic.as_str() {
+                    "ecall" => 0,
+                    "ebreak" => 1,
+                    "mret" => 0x302,
+                    _ => 0x105,
+                };
+                vec![itype(OP_SYSTEM, 0b000, 0, 0, imm)]
+            }
+
+            // Pseudo-instructions
+            "nop" => {
+                self.expect(stmt, 0)?;
+                vec![addi(0, 0, 0)]
+            }
+            "li" => {
+                self.expect(stmt, 2)?;
+                let rd = self.register(&ops[0])?;
+                let value = self.ranged(&ops[1], i32::MIN as i64, u32::MAX as i64)?;
+                let value = value as i32 as i64;
+                match stmt.size {
+                    4 if lo(value) == value => vec![addi(rd, 0, value as i16)],
+                    4 => vec![upper(OP_LUI, rd, hi(value) as u32)],
+                    _ => vec![
+                        upper(OP_LUI, rd, hi(value) as u32),
+                        addi(rd, rd, lo(value) as i16),
+                    ],
+                }
+            }
+            "la" | "call" | "tail" => {
+                let (rd, target) = match stmt.mnemonic.as_str() {
+                    "la" => {
+                        self.expect(stmt, 2)?;
+                        (self.register(&ops[0])?, &ops[1])
+                    }
+                    _ => {
+                        self.expect(stmt, 1)?;
+                        (if stmt.mnemonic == "call" { 1 } else { 6 }, &ops[0])
+                    }
+                };
+                let offset = (self.value(target)? - pc as i64) as i32 as i64;
+                let auipc = upper(OP_AUIPC, rd, hi(offset) as u32);
+                match stmt.mnemonic.as_str() {
+                    "la" => vec![auipc, addi(rd, rd, lo(offset) as i16)],
+                    "call" => vec![auipc, jalr(1, rd, lo(offset) as i16)],
+                    _ => vec![auipc, jalr(0, rd, lo(offset) as i16)],
+                }
+            }
+            "mv" | "not" | "neg" | "seqz" | "snez" => {
+                self.expect(stmt, 2)?;
+                let rd = self.register(&ops[0])?;
+                let rs = self.register(&ops[1])?;
+                match stmt.mnemonic.as_str() {
+                    "mv" => vec![addi(rd, rs, 0)],
+                    "not" => vec![itype(OP_IMM, 0b100, rd, rs, -1)],
+                    "neg" => vec![op(0b0100000, 0b000, rd, 0, rs)],
+                    "seqz" => vec![itype(OP_IMM, 0b011, rd, rs, 1)],
+                    _ => vec![op(0b0000000, 0b011, rd, 0, rs)],
+                }
+            }
+            "j" => {
+                self.expect(stmt, 1)?;
+                vec![jal(0, self.offset(&ops[0], pc, 21)? as i32)]
+            }
+            "jr" => {
+                self.expect(stmt, 1)?;
+                vec![jalr(0, self.register(&ops[0])?, 0)]
+            }
+            "ret" => {
+                self.expect(stmt, 0)?;
+                vec![jalr(0, 1, 0)]
+            }
+            "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => {
+                self.expect(stmt, 2)?;
+                let rs = self.register(&ops[0])?;
+                let imm = self.offset(&ops[1], pc, 13)? as i16;
+                vec![match stmt.mnemonic.as_str() {
+                    "beqz" => branch(0b000, rs, 0, imm),
+                    "bnez" => branch(0b001, rs, 0, imm),
+                    "blez" => branch(0b101, 0, rs, imm),
+                    "bgez" => branch(0b101, rs, 0, imm),
+                    "bltz" => branch(0b100, rs, 0, imm),
+                    _ => branch(0b100, 0, rs, imm),
+                }]
+            }
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                self.expect(stmt, 3)?;
+                let rs = self.register(&ops[0])?;
+                let rt = self.register(&ops[1])?;
+                let imm = self.offset(&ops[2], pc, 13)? as i16;
+                let funct3 = match stmt.mnemonic.as_str() {
+                    "bgt" => 0b100,
+                    "ble" => 0b101,
+                    "bgtu" => 0b110,
+                    _ => 0b111,
+                };
+                vec![branch(funct3, rt, rs, imm)]
+            }
+            "csrr" => {
+                self.expect(stmt, 2)?;
+                let csr = self.csr(&ops[1])?;
+                vec![itype(OP_SYSTEM, 0b010, self.register(&ops[0])?, 0, csr)]
+            }
+            "csrw" | "csrs" | "csrc" => {
+                self.expect(stmt, 2)?;
+                let funct3 = match stmt.mnemonic.as_str() {
+                    "csrw" => 0b001,
+                    "csrs" => 0b010,
+                    _ => 0b011,
+                };
+                let csr = self.csr(&ops[0])?;
+                vec![itype(OP_SYSTEM, funct3, 0, self.register(&ops[1])?, csr)]
+            }
+            "csrwi" | "csrsi" | "csrci" => {
+                self.expect(stmt, 2)?;
+                let funct3 = match stmt.mnemonic.as_str() {
+                    "csrwi" => 0b101,
+                    "csrsi" => 0b110,
+                    _ => 0b111,
+                };
+                let csr = self.csr(&ops[0])?;
+                vec![itype(OP_SYSTEM, funct3, 0, self.uimm5(&ops[1])?, csr)]
+            }
+            name => {
+                return Err(self.error(stmt.column, AsmErrorKind::UnknownMnemonic(name.to_string())))
+            }
+        };
+
+        Ok(insts)
+    }
+
+    // FENCE predecessor/successor sets such as `rw` or `iorw`.
+    fn fence_set(&self, op: &Operand) -> Result<u8, AsmError> {
+        let mut set = 0;
+        for c in op.text.to_ascii_lowercase().chars() {
+            set |= match c {
+                'i' => 0b1000,
+                'o' => 0b0100,
+                'r' => 0b0010,
+                'w' => 0b0001,
+                _ => {
+                    return Err(
+                        self.error(op.column, AsmErrorKind::InvalidOperand(op.text.to_string()))
+                    )
+                }
+            };
+        }
+
+        Ok(set)
+    }
+
+    fn second_pass(&mut self, statements: &[Statement]) -> Result<Vec<u8>, AsmError> {
+        let mut output = Vec::new();
+
+        for stmt in statements {
+            self.line = stmt.line;
+            if stmt.mnemonic.starts_with('.') {
+                output.extend(self.directive(stmt)?);
+                continue;
+            }
+
+            for inst in self.instruction(stmt)? {
+                let word = inst
+                    .encode()
+                    .map_err(|e| self.error(stmt.column, AsmErrorKind::Encode(e)))?;
+                output.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+// Assemble RV32I source text into a little-endian image that starts at `base`, which is
+// where labels are resolved relative to.
+//
+// Example:
+// let program = asm::assemble("li a0, 1\nloop: addi a0, a0, 1\nj loop", 0)?;
+// cpu.load(&program);
+pub fn assemble(source: &str, base: u32) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler {
+        base,
+        symbols: HashMap::new(),
+        line: 0,
+    };
+
+    let statements = assembler.first_pass(source)?;
+    assembler.second_pass(&statements)
+}
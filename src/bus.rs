@@ -0,0 +1,189 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    Unmapped,  // No device covers the whole access
+    ReadOnly,  // Write to a device that cannot be written
+    BadAccess, // The device does not support this access width or offset
+    Overlap,   // A mapping is empty, overlaps another one or runs past the address space
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::Unmapped => write!(f, "Unmapped address"),
+            BusError::ReadOnly => write!(f, "Write to read-only device"),
+            BusError::BadAccess => write!(f, "Unsupported device access"),
+            BusError::Overlap => write!(f, "Invalid or overlapping device mapping"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+// A memory-mapped device. Offsets are relative to the base address the device is attached
+// at, and `size` is always 1, 2 or 4 bytes. Values are little-endian and zero-extended.
+pub trait Device {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError>;
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError>;
+
+    // Host-side write used to load program images; unlike `write` it may fill read-only
+    // devices such as ROM.
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        for (i, byte) in data.iter().enumerate() {
+            self.write(offset + i as u32, 1, *byte as u32)?;
+        }
+        Ok(())
+    }
+
+    // Called once per step, after the instruction, for devices that change on their own.
+    fn tick(&mut self) {}
+
+    // Called when the machine stops, for devices to complete their output, such as a file
+    // header that needs the final length.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// An interrupt line from a device into an interrupt controller. The device drives the
+// level; clones share the same wire.
+#[derive(Debug, Clone, Default)]
+pub struct Irq(Rc<Cell<bool>>);
+
+impl Irq {
+    pub fn set(&self, level: bool) {
+        self.0.set(level);
+    }
+
+    pub fn level(&self) -> bool {
+        self.0.get()
+    }
+}
+
+// Signals devices drive into the hart: interrupt-pending bits for mip, and the mtime that
+// the `time` CSR reads once a timer is attached. The hart drives back the number of
+// instructions it has retired. Clones share the same wires.
+#[derive(Debug, Clone, Default)]
+pub struct Lines {
+    mip: Rc<Cell<u32>>,
+    mtime: Rc<Cell<Option<u64>>>,
+    retired: Rc<Cell<u64>>,
+}
+
+impl Lines {
+    // Raise or lower the mip `bits`.
+    pub fn set(&self, bits: u32, level: bool) {
+        let mip = self.mip.get();
+        self.mip.set(if level { mip | bits } else { mip & !bits });
+    }
+
+    pub fn mip(&self) -> u32 {
+        self.mip.get()
+    }
+
+    pub fn set_mtime(&self, mtime: u64) {
+        self.mtime.set(Some(mtime));
+    }
+
+    pub fn mtime(&self) -> Option<u64> {
+        self.mtime.get()
+    }
+
+    pub fn retire(&self) {
+        self.retired.set(self.retired.get().wrapping_add(1));
+    }
+
+    // Instructions retired since reset. Unlike minstret, software cannot write it.
+    pub fn retired(&self) -> u64 {
+        self.retired.get()
+    }
+}
+
+struct Region {
+    base: u32,
+    size: u32,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    // Offset of `addr` into this region if all `len` bytes starting there fall inside it.
+    fn offset(&self, addr: u32, len: usize) -> Option<u32> {
+        let offset = addr.checked_sub(self.base)?;
+        let end = (offset as u64) + (len as u64);
+        (end <= self.size as u64).then_some(offset)
+    }
+}
+
+// Routes physical addresses to the devices attached at them.
+pub struct Bus {
+    regions: Vec<Region>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            regions: Vec::new(),
+        }
+    }
+
+    // Map `device` at `base..base + size`.
+    pub fn attach(
+        &mut self,
+        base: u32,
+        size: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        let end = base as u64 + size as u64;
+        let overlaps = self.regions.iter().any(|region| {
+            let region_end = region.base as u64 + region.size as u64;
+            (base as u64) < region_end && (region.base as u64) < end
+        });
+
+        if size == 0 || end > 1 << 32 || overlaps {
+            return Err(BusError::Overlap);
+        }
+
+        self.regions.push(Region { base, size, device });
+        Ok(())
+    }
+
+    fn region(&mut self, addr: u32, len: usize) -> Result<(&mut Region, u32), BusError> {
+        self.regions
+            .iter_mut()
+            .find_map(|region| region.offset(addr, len).map(|offset| (region, offset)))
+            .ok_or(BusError::Unmapped)
+    }
+
+    pub fn read(&mut self, addr: u32, size: usize) -> Result<u32, BusError> {
+        let (region, offset) = self.region(addr, size)?;
+        region.device.read(offset, size)
+    }
+
+    pub fn write(&mut self, addr: u32, size: usize, value: u32) -> Result<(), BusError> {
+        let (region, offset) = self.region(addr, size)?;
+        region.device.write(offset, size, value)
+    }
+
+    pub fn tick(&mut self) {
+        for region in &mut self.regions {
+            region.device.tick();
+        }
+    }
+
+    // Finish every device, returning the errors of those that failed.
+    pub fn finish(&mut self) -> Vec<String> {
+        self.regions
+            .iter_mut()
+            .filter_map(|region| region.device.finish().err())
+            .collect()
+    }
+
+    pub fn load(&mut self, addr: u32, data: &[u8]) -> Result<(), BusError> {
+        let (region, offset) = self.region(addr, data.len())?;
+        region.device.load(offset, data)
+    }
+}
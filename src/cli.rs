@@ -0,0 +1,934 @@
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::asm;
+use crate::commit::{CommitLog, Filter};
+use crate::compliance::{self, Outcome, Report};
+use crate::config::Config;
+use crate::cpu::CPU;
+use crate::devices::clint::{self, Clint, Timebase};
+use crate::devices::framebuffer::{self, DumpFormat, Framebuffer};
+use crate::devices::plic::{self, Plic};
+use crate::devices::sound::{self, Sound, WavWriter};
+use crate::devices::uart::{self, Uart};
+use crate::difftest;
+use crate::disasm::{self, Syntax};
+use crate::elf::Elf;
+use crate::gdb::{GdbStub, Session};
+use crate::monitor::{Flow, Monitor};
+use crate::semihost::Semihosting;
+use crate::syscall::{self, Syscalls};
+use crate::tui::{self, Tui};
+
+pub const USAGE: &str = "\
+Usage: rv801 <command> [options] <file> [guest arguments]
+
+Commands:
+    run <file>              Run an ELF, raw binary or hex text image
+    trace <file>            Run, printing every instruction as it executes
+    monitor <file>          Step through a program from an interactive monitor
+    tui <file>              Watch a program run in a full-screen terminal view
+    diff <file>             Check a run against a --reference commit log
+    test <file|dir>         Run riscv-tests or riscv-arch-test ELFs and report each result
+    asm <file>              Assemble a source file to hex text on stdout
+    disasm <file>           Disassemble an ELF, raw binary or hex text image
+
+Options:
+    --memory <size>         RAM size in bytes, or with a K/M/G suffix (default 64K)
+    --ram-base <addr>       RAM base address (default 0, or the lowest ELF segment)
+    --base <addr>           Load address of raw and hex images (default: RAM base)
+    --entry <addr>          Start address (default: ELF entry point or load address)
+    --rom <addr>=<file>     Map the bytes of <file> read-only at <addr>; may be repeated
+    --max-instructions <n>  Stop after executing n instructions
+    --exit-on-nop           Stop at the first NOP or zero word
+    --syscalls              Serve Linux system calls made with ECALL
+    --semihosting           Serve semihosting calls made with slli/ebreak/srai
+    --sandbox <dir>         Directory the guest's files live in (default: current)
+    --uart <stdio|path>     Map an NS16550A at 0x10000000 on the terminal, a file or a pty;
+                            on the terminal, Ctrl-A x quits
+    --clint <instret|wall>  Map a CLINT at 0x02000000, its mtime counting instructions or
+                            following the host clock at 10 MHz
+    --plic <sources>        Map a PLIC at 0x0C000000 driving MEIP; the UART is source 10
+    --fb <width>x<height>   Map a framebuffer at 0x30000000, its pixels at 0x30100000
+    --fb-dump <dir>         Write every presented frame to <dir> as frame-NNNNN.png
+    --fb-ppm                Dump frames as PPM rather than PNG
+    --fb-view               Draw presented frames on the terminal in 24-bit color; there
+                            is no windowed view
+    --sound                 Map a tone generator and PCM ring at 0x31000000
+    --wav <file>            Record the mixed sound output to <file> (implies --sound)
+    --gdb <[host:]port>     run: wait for GDB to connect and run under its control
+    --script <file>         monitor: run the commands in <file> before reading stdin
+    --log-commits           Log every retired instruction in Spike's --log-commits format
+    --log <file>            Write the commit log to <file> instead of stderr
+    --log-range <start:end> Only log instructions with start <= pc < end
+    --log-window <skip:n>   Only log n instructions after skipping the first skip
+    --log-disasm            Precede each commit with its disassembly, as with Spike's -l
+    --reference <file>      diff: Spike --log-commits or Sail trace to compare against
+    --signature <path>      test: write the signature to <path>, or to <path>/<test>.signature
+                            when testing a directory
+    -q, --quiet             Don't print the register dump when the program stops
+    -o, --output <file>     asm: write a raw binary to <file> instead
+    --numeric               disasm, trace: print x10 rather than a0
+    --pseudo                disasm, trace: print pseudo-instructions such as li and ret
+";
+
+// Exit codes for failures of the emulator itself rather than of the guest.
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_LIMIT: u8 = 124; // --max-instructions reached, as with timeout(1)
+pub const EXIT_QUIT: u8 = 130; // Ctrl-A x on the console, as for Ctrl-C in a shell
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub file: String,
+    pub memory: Option<u32>,
+    pub ram_base: Option<u32>,
+    pub base: Option<u32>,
+    pub entry: Option<u32>,
+    pub roms: Vec<(u32, String)>,
+    pub max_instructions: Option<u64>,
+    pub exit_on_nop: bool,
+    pub syscalls: bool,
+    pub semihosting: bool,
+    pub sandbox: Option<String>,
+    pub uart: Option<String>,
+    pub clint: Option<Timebase>,
+    pub plic: Option<u32>,
+    pub fb: Option<(u32, u32)>,
+    pub fb_dump: Option<String>,
+    pub fb_ppm: bool,
+    pub fb_view: bool,
+    pub sound: bool,
+    pub wav: Option<String>,
+    pub gdb: Option<String>,
+    pub script: Option<String>,
+    pub log_commits: bool,
+    pub log: Option<String>,
+    pub log_filter: Filter,
+    pub log_disasm: bool,
+    pub reference: Option<String>,
+    pub signature: Option<String>,
+    pub args: Vec<String>,
+    pub quiet: bool,
+    pub output: Option<String>,
+    pub syntax: Syntax,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run(Options),
+    Trace(Options),
+    Monitor(Options),
+    Tui(Options),
+    Diff(Options),
+    Test(Options),
+    Asm(Options),
+    Disasm(Options),
+    Help,
+}
+
+// A program image in one of the formats `run` accepts.
+#[derive(Debug, Clone)]
+pub enum Image {
+    Elf(Elf),
+    Binary(Vec<u8>),
+}
+
+pub fn parse_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_addr(flag: &str, text: &str) -> Result<u32, String> {
+    parse_number(text)
+        .and_then(|value| u32::try_from(value).ok())
+        .ok_or_else(|| format!("Invalid address for {}: {}", flag, text))
+}
+
+// "a:b" where either side may be left out, as for --log-range and --log-window.
+fn parse_pair(flag: &str, text: &str) -> Result<(Option<u64>, Option<u64>), String> {
+    let invalid = || format!("Invalid range for {}: {}", flag, text);
+    let (first, second) = text.split_once(':').ok_or_else(invalid)?;
+    let side = |side: &str| match side {
+        "" => Ok(None),
+        _ => parse_number(side).map(Some).ok_or_else(invalid),
+    };
+
+    Ok((side(first)?, side(second)?))
+}
+
+fn parse_size(flag: &str, text: &str) -> Result<u32, String> {
+    let (digits, shift) = match text.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&text[..i], 10),
+        Some((i, 'm' | 'M')) => (&text[..i], 20),
+        Some((i, 'g' | 'G')) => (&text[..i], 30),
+        _ => (text, 0),
+    };
+
+    parse_number(digits)
+        .and_then(|value| value.checked_shl(shift))
+        .and_then(|value| u32::try_from(value).ok())
+        .filter(|value| *value > 0)
+        .ok_or_else(|| format!("Invalid size for {}: {}", flag, text))
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Ok(Command::Help),
+    };
+
+    if matches!(command, "help" | "-h" | "--help") {
+        return Ok(Command::Help);
+    }
+
+    let mut options = Options::default();
+    let mut file = None;
+    let mut args = rest.iter();
+
+    while let Some(arg) = args.next() {
+        // Everything after the file belongs to the guest.
+        if file.is_some() {
+            options.args.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            args.next()
+                .map(|value| value.as_str())
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+
+        match arg.as_str() {
+            "--memory" => options.memory = Some(parse_size(arg, value()?)?),
+            "--ram-base" => options.ram_base = Some(parse_addr(arg, value()?)?),
+            "--base" => options.base = Some(parse_addr(arg, value()?)?),
+            "--entry" => options.entry = Some(parse_addr(arg, value()?)?),
+            "--rom" => {
+                let text = value()?;
+                let (addr, path) = text
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid ROM for {}: {}", arg, text))?;
+                options
+                    .roms
+                    .push((parse_addr(arg, addr)?, path.to_string()));
+            }
+            "--max-instructions" => {
+                let text = value()?;
+                let max = parse_number(text)
+                    .ok_or_else(|| format!("Invalid count for {}: {}", arg, text))?;
+                options.max_instructions = Some(max);
+            }
+            "--exit-on-nop" => options.exit_on_nop = true,
+            "--syscalls" => options.syscalls = true,
+            "--semihosting" => options.semihosting = true,
+            "--sandbox" => options.sandbox = Some(value()?.to_string()),
+            "--uart" => options.uart = Some(value()?.to_string()),
+            "--plic" => {
+                let text = value()?;
+                let sources = parse_number(text)
+                    .filter(|n| (1..=plic::MAX_SOURCES as u64).contains(n))
+                    .ok_or_else(|| format!("Invalid source count for {}: {}", arg, text))?;
+                options.plic = Some(sources as u32);
+            }
+            "--fb" => {
+                let text = value()?;
+                let size = text
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((parse_number(w)?, parse_number(h)?)))
+                    .filter(|&(w, h)| w <= u32::MAX as u64 && h <= u32::MAX as u64)
+                    .map(|(w, h)| (w as u32, h as u32))
+                    .filter(|&(w, h)| Framebuffer::fits(w, h))
+                    .ok_or_else(|| format!("Invalid resolution for {}: {}", arg, text))?;
+                options.fb = Some(size);
+            }
+            "--fb-dump" => options.fb_dump = Some(value()?.to_string()),
+            "--fb-ppm" => options.fb_ppm = true,
+            "--fb-view" => options.fb_view = true,
+            "--sound" => options.sound = true,
+            "--wav" => {
+                options.sound = true;
+                options.wav = Some(value()?.to_string());
+            }
+            "--clint" => {
+                options.clint = match value()? {
+                    "instret" => Some(Timebase::Instructions),
+                    "wall" => Some(Timebase::WallClock),
+                    text => return Err(format!("Invalid timebase for {}: {}", arg, text)),
+                }
+            }
+            "--gdb" => options.gdb = Some(value()?.to_string()),
+            "--script" => options.script = Some(value()?.to_string()),
+            "--log-commits" => options.log_commits = true,
+            "--log" => options.log = Some(value()?.to_string()),
+            "--log-range" => {
+                let (start, end) = parse_pair(arg, value()?)?;
+                options.log_filter.start = start.unwrap_or(0) as u32;
+                options.log_filter.end = end.map(|end| end as u32);
+            }
+            "--log-window" => {
+                let (skip, count) = parse_pair(arg, value()?)?;
+                options.log_filter.skip = skip.unwrap_or(0);
+                options.log_filter.count = count;
+            }
+            "--log-disasm" => options.log_disasm = true,
+            "--reference" => options.reference = Some(value()?.to_string()),
+            "--signature" => options.signature = Some(value()?.to_string()),
+            "-q" | "--quiet" => options.quiet = true,
+            "-o" | "--output" => options.output = Some(value()?.to_string()),
+            "--numeric" => options.syntax.abi_names = false,
+            "--pseudo" => options.syntax.pseudo = true,
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("Unknown option: {}", flag))
+            }
+            path => file = Some(path.to_string()),
+        }
+    }
+
+    options.file = file.ok_or_else(|| format!("Missing file for {}", command))?;
+
+    match command {
+        "run" => Ok(Command::Run(options)),
+        "trace" => Ok(Command::Trace(options)),
+        "monitor" => Ok(Command::Monitor(options)),
+        "tui" => Ok(Command::Tui(options)),
+        "diff" if options.reference.is_none() => Err("Missing --reference for diff".to_string()),
+        "diff" => Ok(Command::Diff(options)),
+        "test" => Ok(Command::Test(options)),
+        "asm" => Ok(Command::Asm(options)),
+        "disasm" => Ok(Command::Disasm(options)),
+        _ => Err(format!("Unknown command: {}", command)),
+    }
+}
+
+// Hex text is one word per line, as written by `asm`.
+fn parse_hex(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut bytes = Vec::new();
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let digits = line.strip_prefix("0x").unwrap_or(line);
+        if digits.len() > 8 {
+            return None;
+        }
+
+        let word = u32::from_str_radix(digits, 16).ok()?;
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+
+    if bytes.is_empty() {
+        return None;
+    }
+
+    Some(bytes)
+}
+
+impl Image {
+    // ELF by its magic number, hex text if every line is a hex word, raw binary otherwise.
+    pub fn detect(data: &[u8]) -> Result<Self, String> {
+        if Elf::is_elf(data) {
+            return Elf::parse(data).map(Image::Elf).map_err(|e| e.to_string());
+        }
+
+        match parse_hex(data) {
+            Some(bytes) => Ok(Image::Binary(bytes)),
+            None => Ok(Image::Binary(data.to_vec())),
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        Image::detect(&data)
+    }
+}
+
+// Set when the user types Ctrl-A x on a `--uart stdio` console.
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+// Ctrl-A starts a console escape: Ctrl-A x quits and Ctrl-A Ctrl-A sends one Ctrl-A, as
+// in QEMU. Anything else after Ctrl-A is dropped.
+const ESCAPE: u8 = 0x01;
+
+// Keys typed on the terminal, with the console escapes taken out.
+pub struct ConsoleInput<R> {
+    input: R,
+    escaped: bool,
+}
+
+impl<R: Read> ConsoleInput<R> {
+    pub fn new(input: R) -> Self {
+        ConsoleInput {
+            input,
+            escaped: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ConsoleInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.input.read(buf)?;
+            if count == 0 {
+                return Ok(0);
+            }
+
+            let mut kept = 0;
+            for i in 0..count {
+                match (mem::take(&mut self.escaped), buf[i]) {
+                    (false, ESCAPE) => self.escaped = true,
+                    (true, b'x') => {
+                        QUIT.store(true, Ordering::Relaxed);
+                        return Ok(kept);
+                    }
+                    (false, byte) | (true, byte @ ESCAPE) => {
+                        buf[kept] = byte;
+                        kept += 1;
+                    }
+                    (true, _) => {}
+                }
+            }
+
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+// The terminal as a serial console: keys reach the guest as they are typed, unechoed and
+// with Ctrl-C among them, while output still turns \n into \r\n as QEMU leaves it. Put
+// back as it was on drop.
+struct Console {
+    saved: String,
+}
+
+impl Console {
+    fn enter() -> io::Result<Self> {
+        let saved = tui::stty(&["-g"])?;
+        tui::stty(&["raw", "-echo", "opost"])?;
+        Ok(Console { saved })
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        tui::stty(&[&self.saved]).ok();
+    }
+}
+
+// A UART on the terminal, or on `target`. Character devices such as a pty also feed the
+// receiver; anything else is a file that collects the output.
+fn open_uart(target: &str) -> Result<Uart, String> {
+    if target == "stdio" {
+        let input = if io::stdin().is_terminal() {
+            uart::spawn_reader(ConsoleInput::new(io::stdin()))
+        } else {
+            uart::spawn_reader(io::stdin())
+        };
+        return Ok(Uart::new(Box::new(io::stdout()), Some(input)));
+    }
+
+    let error = |e: io::Error| format!("Unable to open {}: {}", target, e);
+    let is_device = fs::metadata(target).is_ok_and(|m| m.file_type().is_char_device());
+    if is_device {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(target)
+            .map_err(error)?;
+        let input = uart::spawn_reader(file.try_clone().map_err(error)?);
+        Ok(Uart::new(Box::new(file), Some(input)))
+    } else {
+        let file = fs::File::create(target).map_err(error)?;
+        Ok(Uart::new(Box::new(file), None))
+    }
+}
+
+// The monitor and the full-screen view read the terminal themselves, so the UART would
+// race them for keys and draw over the screen.
+fn check_terminal_free(options: &Options, command: &str) -> Result<(), String> {
+    match options.uart.as_deref() {
+        Some("stdio") => Err(format!(
+            "{} uses the terminal; put the UART on a file or a pty instead of stdio",
+            command
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Build a CPU with the memory map from `options` and load `image` into it.
+pub fn machine(options: &Options, image: &Image) -> Result<CPU, String> {
+    let ram_base = options.ram_base.unwrap_or(match image {
+        Image::Elf(elf) => elf.segments.iter().map(|s| s.addr).min().unwrap_or(0) & !0xFFF,
+        Image::Binary(_) => 0,
+    });
+
+    // Raw images start where they are loaded; ELF images move pc to their entry point.
+    let base = options.base.unwrap_or(ram_base);
+    let mut config = Config::new();
+    let ram_size = options.memory.unwrap_or(config.ram_size);
+    config = config.ram(ram_base, ram_size).reset_pc(base);
+    for (addr, path) in &options.roms {
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        config = config.rom(*addr, data);
+    }
+    let mut cpu = CPU::with_config(config).map_err(|e| format!("Invalid memory map: {}", e))?;
+    cpu.exit_on_nop = options.exit_on_nop;
+
+    let image_end = match image {
+        Image::Elf(elf) => {
+            cpu.load_elf(elf).map_err(|e| e.to_string())?;
+            elf.segments
+                .iter()
+                .map(|s| s.addr.wrapping_add(s.mem_size))
+                .max()
+                .unwrap_or(ram_base)
+        }
+        Image::Binary(data) => {
+            cpu.bus
+                .load(base, data)
+                .map_err(|e| format!("Unable to load image at 0x{:08X}: {}", base, e))?;
+            base.wrapping_add(data.len() as u32)
+        }
+    };
+
+    let root = options.sandbox.as_deref().unwrap_or(".");
+    if options.syscalls {
+        let syscalls = Syscalls::new(Path::new(root), image_end.next_multiple_of(16))
+            .map_err(|e| format!("Invalid sandbox {}: {}", root, e))?;
+        cpu.syscalls = Some(syscalls);
+
+        // The stack starts at the top of RAM with the program name and arguments.
+        let mut args = vec![options.file.clone()];
+        args.extend(options.args.iter().cloned());
+        let top = ram_base.wrapping_add(ram_size).wrapping_sub(16);
+        syscall::setup_stack(&mut cpu, top, &args)
+            .map_err(|e| format!("Unable to set up the stack: {}", e))?;
+    }
+
+    if options.semihosting {
+        let mut cmdline = vec![options.file.as_str()];
+        cmdline.extend(options.args.iter().map(String::as_str));
+        let semihosting = Semihosting::new(Path::new(root), &cmdline.join(" "))
+            .map_err(|e| format!("Invalid sandbox {}: {}", root, e))?;
+        cpu.semihosting = Some(semihosting);
+    }
+
+    let plic = options
+        .plic
+        .map(|sources| Plic::new(cpu.lines.clone(), sources));
+
+    if let Some(target) = &options.uart {
+        let mut uart = open_uart(target)?;
+        if let Some(plic) = &plic {
+            let irq = plic
+                .source(uart::IRQ)
+                .ok_or_else(|| format!("The UART needs a PLIC with {} sources", uart::IRQ))?;
+            uart.connect(irq);
+        }
+        cpu.bus
+            .attach(uart::BASE, uart::SIZE, Box::new(uart))
+            .map_err(|e| format!("Unable to map the UART: {}", e))?;
+    }
+
+    if let Some(plic) = plic {
+        cpu.bus
+            .attach(plic::BASE, plic::SIZE, Box::new(plic))
+            .map_err(|e| format!("Unable to map the PLIC: {}", e))?;
+    }
+
+    if let Some((width, height)) = options.fb {
+        let mut fb = Framebuffer::new(width, height);
+        let format = if options.fb_ppm {
+            DumpFormat::Ppm
+        } else {
+            DumpFormat::Png
+        };
+        if let Some(dir) = &options.fb_dump {
+            fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir, e))?;
+            fb.dump = Some((dir.into(), format));
+        }
+        if options.fb_view {
+            fb.view = Some(Box::new(io::stdout()));
+        }
+        cpu.bus
+            .attach(framebuffer::BASE, framebuffer::SIZE, Box::new(fb))
+            .map_err(|e| format!("Unable to map the framebuffer: {}", e))?;
+    }
+
+    if options.sound {
+        let wav = match &options.wav {
+            Some(path) => {
+                let file = fs::File::create(path)
+                    .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+                let wav = WavWriter::new(Box::new(io::BufWriter::new(file)))
+                    .map_err(|e| format!("Unable to write {}: {}", path, e))?;
+                Some(wav)
+            }
+            None => None,
+        };
+        cpu.bus
+            .attach(sound::BASE, sound::SIZE, Box::new(Sound::new(wav)))
+            .map_err(|e| format!("Unable to map the sound device: {}", e))?;
+    }
+
+    if let Some(timebase) = options.clint {
+        let clint = Clint::new(cpu.lines.clone(), timebase);
+        cpu.bus
+            .attach(clint::BASE, clint::SIZE, Box::new(clint))
+            .map_err(|e| format!("Unable to map the CLINT: {}", e))?;
+    }
+
+    if options.log_commits {
+        let out: Box<dyn Write> = match &options.log {
+            Some(path) => {
+                let file = fs::File::create(path)
+                    .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+                Box::new(io::BufWriter::new(file))
+            }
+            None => Box::new(io::stderr()),
+        };
+        let mut log = CommitLog::new(out, options.log_filter);
+        log.disasm = options.log_disasm;
+        cpu.commit_log = Some(log);
+    }
+
+    if let Some(entry) = options.entry {
+        cpu.pc = entry as usize;
+    }
+
+    Ok(cpu)
+}
+
+// Let the devices complete their output, such as a WAV header, and report any that could
+// not. The guest's exit status stands either way.
+fn finish(cpu: &mut CPU) {
+    for error in cpu.bus.finish() {
+        eprintln!("{}", error);
+    }
+}
+
+// Step until the program stops, optionally listing each instruction before it executes.
+// Returns the exit code for the process.
+pub fn simulate(cpu: &mut CPU, options: &Options, trace: bool) -> u8 {
+    let mut executed = 0u64;
+
+    loop {
+        if options.max_instructions.is_some_and(|max| executed >= max) {
+            eprintln!("Stopped after {} instructions", executed);
+            return EXIT_LIMIT;
+        }
+
+        let pc = cpu.pc as u32;
+        if trace {
+            if let Ok(raw) = cpu.bus.read(pc, 4) {
+                println!("{}", disasm::line(pc, raw, options.syntax));
+            }
+        }
+
+        executed += 1;
+        if let Err(e) = cpu.step() {
+            eprintln!("Unhandled exception at 0x{:08X}: {}", pc, e);
+            return 1;
+        }
+
+        if let Some(code) = cpu.exit_code {
+            return code;
+        }
+
+        if cpu.exit_on_nop && cpu.last_inst.is_some_and(|inst| inst.is_nop()) {
+            return 0;
+        }
+
+        if QUIT.load(Ordering::Relaxed) {
+            eprintln!("Stopped from the console");
+            return EXIT_QUIT;
+        }
+    }
+}
+
+// Run under GDB until it lets the program finish, detaches or kills it.
+fn debug(cpu: &mut CPU, options: &Options, addr: &str) -> Result<u8, String> {
+    let addr = if addr.contains(':') {
+        addr.to_string()
+    } else {
+        format!("127.0.0.1:{}", addr)
+    };
+
+    let session = GdbStub::new(cpu)
+        .listen(addr.as_str())
+        .map_err(|e| format!("GDB connection on {} failed: {}", addr, e))?;
+
+    Ok(match session {
+        Session::Exited(code) => code,
+        Session::Detached => simulate(cpu, options, false),
+        Session::Killed => 1,
+    })
+}
+
+// Run the --script, then take commands from stdin until it ends or the user quits.
+fn monitor(options: &Options) -> Result<u8, String> {
+    check_terminal_free(options, "monitor")?;
+    let image = Image::read(&options.file)?;
+    let mut cpu = machine(options, &image)?;
+    let mut monitor = Monitor::new(&mut cpu);
+    monitor.syntax = options.syntax;
+
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    let mut out = io::stdout();
+    let result = match &options.script {
+        Some(script) => monitor.source(script, &mut out),
+        None => Ok(Flow::Continue),
+    }
+    .and_then(|flow| match flow {
+        Flow::Continue => monitor.repl(&mut stdin.lock(), &mut out, prompt),
+        Flow::Quit => Ok(()),
+    });
+    result.map_err(|e| format!("Monitor failed: {}", e))?;
+
+    finish(&mut cpu);
+    Ok(cpu.exit_code.unwrap_or(0))
+}
+
+fn tui(options: &Options) -> Result<u8, String> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err("tui needs a terminal".to_string());
+    }
+    check_terminal_free(options, "tui")?;
+
+    let image = Image::read(&options.file)?;
+    let mut cpu = machine(options, &image)?;
+    let mut tui = Tui::new(&mut cpu);
+    tui.syntax = options.syntax;
+    tui.run().map_err(|e| format!("Terminal failed: {}", e))?;
+
+    finish(&mut cpu);
+    Ok(cpu.exit_code.unwrap_or(0))
+}
+
+// Run the program in lockstep with the --reference log, stopping at the first difference.
+fn diff(options: &Options) -> Result<u8, String> {
+    let path = options.reference.as_deref().unwrap_or_default();
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let reference = difftest::parse_trace(&text).map_err(|e| format!("{}: {}", path, e))?;
+
+    let image = Image::read(&options.file)?;
+    let mut cpu = machine(options, &image)?;
+    let code = match difftest::compare(&mut cpu, &reference) {
+        Ok(count) => {
+            println!("{} instructions match {}", count, path);
+            0
+        }
+        Err(divergence) => {
+            print!("{}", divergence);
+            1
+        }
+    };
+
+    finish(&mut cpu);
+    Ok(code)
+}
+
+// Run one test ELF with the tohost convention. `path` is the test, not `options.file`,
+// which may be the directory it is in.
+fn test_one(options: &Options, path: &Path) -> Result<Report, String> {
+    let name = path.display().to_string();
+    let elf = match Image::read(&name)? {
+        Image::Elf(elf) => elf,
+        Image::Binary(_) => return Err(format!("{} is not an ELF file", name)),
+    };
+
+    let image = Image::Elf(elf.clone());
+    let mut options = options.clone();
+    let ram_base = elf.segments.iter().map(|s| s.addr).min().unwrap_or(0) & !0xFFF;
+    let base = *options.ram_base.get_or_insert(ram_base);
+    if options.memory.is_none() {
+        options.memory = Some(compliance::memory_size(&elf, base, Config::new().ram_size));
+    }
+
+    let mut cpu = machine(&options, &image)?;
+    let max = options
+        .max_instructions
+        .unwrap_or(compliance::MAX_INSTRUCTIONS);
+    let report = compliance::run(&mut cpu, &elf, max);
+    finish(&mut cpu);
+    Ok(report)
+}
+
+// Run a test, or every ELF file in a directory in name order, and report how each ended.
+fn test(options: &Options) -> Result<u8, String> {
+    let root = Path::new(&options.file);
+    let tests = if root.is_dir() {
+        let entries =
+            fs::read_dir(root).map_err(|e| format!("Unable to read {}: {}", options.file, e))?;
+        let mut tests: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| fs::read(path).is_ok_and(|data| path.is_file() && Elf::is_elf(&data)))
+            .collect();
+        tests.sort();
+        tests
+    } else {
+        vec![root.to_path_buf()]
+    };
+
+    if tests.is_empty() {
+        return Err(format!("No ELF files in {}", options.file));
+    }
+
+    let mut passed = 0;
+    for path in &tests {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let report = test_one(options, path).unwrap_or_else(|e| Report {
+            outcome: Outcome::Error(e),
+            instructions: 0,
+            signature: None,
+        });
+
+        if report.outcome == Outcome::Pass {
+            passed += 1;
+        }
+        println!(
+            "{:<32} {:<24} {:>10} instructions",
+            name,
+            report.outcome.to_string(),
+            report.instructions
+        );
+
+        if let (Some(target), Some(words)) = (&options.signature, &report.signature) {
+            let target = match root.is_dir() {
+                true => Path::new(target).join(format!("{}.signature", name)),
+                false => Path::new(target).to_path_buf(),
+            };
+            fs::write(&target, compliance::format_signature(words))
+                .map_err(|e| format!("Unable to write {}: {}", target.display(), e))?;
+        }
+    }
+
+    println!("{} of {} tests passed", passed, tests.len());
+    Ok(if passed == tests.len() { 0 } else { 1 })
+}
+
+fn run(options: &Options, trace: bool) -> Result<u8, String> {
+    let image = Image::read(&options.file)?;
+    let mut cpu = machine(options, &image)?;
+    let console = match options.uart.as_deref() {
+        Some("stdio") if io::stdin().is_terminal() => {
+            Some(Console::enter().map_err(|e| format!("Unable to set up the terminal: {}", e))?)
+        }
+        _ => None,
+    };
+    let code = match &options.gdb {
+        Some(addr) => debug(&mut cpu, options, addr)?,
+        None => simulate(&mut cpu, options, trace),
+    };
+    drop(console);
+    finish(&mut cpu);
+
+    if !options.quiet {
+        cpu.print_state();
+    }
+
+    Ok(code)
+}
+
+fn assemble(options: &Options) -> Result<u8, String> {
+    let source = fs::read_to_string(&options.file)
+        .map_err(|e| format!("Unable to read {}: {}", options.file, e))?;
+    let program = asm::assemble(&source, options.base.unwrap_or(0))
+        .map_err(|e| format!("{}:{}", options.file, e))?;
+
+    match &options.output {
+        Some(path) => {
+            fs::write(path, &program).map_err(|e| format!("Unable to write {}: {}", path, e))?
+        }
+        None => {
+            for word in program.chunks(4) {
+                let mut bytes = [0; 4];
+                bytes[..word.len()].copy_from_slice(word);
+                println!("{:08x}", u32::from_le_bytes(bytes));
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+fn disassemble(options: &Options) -> Result<u8, String> {
+    let image = Image::read(&options.file)?;
+    let mut cpu = machine(options, &image)?;
+
+    // Executable segments of an ELF; the whole image otherwise. An end past the top of
+    // the address space saturates, which still covers a last word at 0xFFFFFFFC.
+    let ranges: Vec<(u32, u32)> = match &image {
+        Image::Elf(elf) => elf
+            .segments
+            .iter()
+            .filter(|segment| segment.flags & 0x1 != 0)
+            .map(|segment| {
+                (
+                    segment.addr,
+                    segment.addr.saturating_add(segment.data.len() as u32),
+                )
+            })
+            .collect(),
+        Image::Binary(data) => {
+            let base = options.base.or(options.ram_base).unwrap_or(0);
+            vec![(base, base.saturating_add(data.len() as u32))]
+        }
+    };
+
+    for (start, end) in ranges {
+        let lines = disasm::disassemble_range(&mut cpu.bus, start, end, options.syntax)
+            .map_err(|e| format!("Unable to read 0x{:08X}-0x{:08X}: {}", start, end, e))?;
+
+        for (addr, line) in (start..=u32::MAX).step_by(4).zip(lines) {
+            if let Some((symbol, 0)) = cpu.symbols.lookup(addr) {
+                println!("\n{}:", symbol.name);
+            }
+            println!("{}", line);
+        }
+    }
+
+    Ok(0)
+}
+
+// Run the command line `args` (without the program name) and return the exit code.
+pub fn main(args: &[String]) -> u8 {
+    let command = match parse(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let result = match &command {
+        Command::Run(options) => run(options, false),
+        Command::Trace(options) => run(options, true),
+        Command::Monitor(options) => monitor(options),
+        Command::Tui(options) => tui(options),
+        Command::Diff(options) => diff(options),
+        Command::Test(options) => test(options),
+        Command::Asm(options) => assemble(options),
+        Command::Disasm(options) => disassemble(options),
+        Command::Help => {
+            print!("{}", USAGE);
+            Ok(0)
+        }
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_USAGE
+        }
+    }
+}
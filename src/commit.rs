@@ -0,0 +1,192 @@
+use std::io::Write;
+
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::disasm::{self, Syntax};
+use crate::isa::{Instruction, InstructionType};
+
+// What one retired instruction changed, as Spike's --log-commits reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    pub privilege: Privilege,
+    pub pc: u32,
+    pub raw: u32,
+    pub rd: Option<(u8, u32)>, // Register written and its new value; never x0
+    pub csr: Option<(u16, u32)>, // CSR written and its new value
+    pub load: Option<u32>,     // Address loaded from
+    pub store: Option<(u32, u32, u32)>, // Address, value and size stored
+}
+
+impl Commit {
+    // Note the privilege and memory access before `inst` executes.
+    pub fn new(cpu: &CPU, pc: u32, raw: u32, inst: &Instruction) -> Self {
+        let mut commit = Commit {
+            privilege: cpu.privilege,
+            pc,
+            raw,
+            rd: None,
+            csr: None,
+            load: None,
+            store: None,
+        };
+
+        match (cpu.memory_access(inst), inst.inst_type) {
+            (Some((addr, size, true)), InstructionType::S(s)) => {
+                let mask = u32::MAX >> (32 - size * 8);
+                commit.store = Some((addr, cpu.regs[s.rs2 as usize] & mask, size));
+            }
+            (Some((addr, _, false)), _) => commit.load = Some(addr),
+            _ => {}
+        }
+
+        commit
+    }
+
+    // Fill in the registers `inst` wrote, once it has executed.
+    pub fn finish(&mut self, cpu: &CPU, inst: &Instruction) {
+        let rd = match inst.inst_type {
+            InstructionType::R(r) => r.rd,
+            InstructionType::I(i) => i.rd,
+            InstructionType::U(u) => u.rd,
+            InstructionType::J(j) => j.rd,
+            InstructionType::S(_) | InstructionType::B(_) | InstructionType::FENCE(_) => 0,
+        };
+        if rd != 0 {
+            self.rd = Some((rd, cpu.regs[rd as usize]));
+        }
+
+        // Zicsr writes, and the mstatus update MRET makes.
+        if let Some(csr) = cpu.csrs.written {
+            self.csr = cpu
+                .csrs
+                .read(csr, Privilege::Machine)
+                .ok()
+                .map(|v| (csr, v));
+        }
+    }
+}
+
+// Spike's commit line: `core   0: 3 0x80000000 (0x00000297) x5  0x80000000`, followed by
+// any CSR write and ` mem addr` for loads or ` mem addr value` for stores.
+pub fn format(commit: &Commit) -> String {
+    let mut line = format!(
+        "core   0: {} 0x{:08x} (0x{:08x})",
+        commit.privilege as u8, commit.pc, commit.raw
+    );
+
+    if let Some((rd, value)) = commit.rd {
+        line += &format!(" x{:<2} 0x{:08x}", rd, value);
+    }
+    if let Some((csr, value)) = commit.csr {
+        let name = csr::name(csr).unwrap_or("unknown");
+        line += &format!(" c{}_{} 0x{:08x}", csr, name, value);
+    }
+    if let Some(addr) = commit.load {
+        line += &format!(" mem 0x{:08x}", addr);
+    }
+    if let Some((addr, value, size)) = commit.store {
+        line += &format!(
+            " mem 0x{:08x} 0x{:0width$x}",
+            addr,
+            value,
+            width = size as usize * 2
+        );
+    }
+
+    line
+}
+
+// Spike's `-l` line: `core   0: 0x80000000 (0x00000297) auipc   t0, 0x0`.
+pub fn format_disasm(pc: u32, raw: u32) -> String {
+    let text = match Instruction::try_from(raw) {
+        Ok(inst) => {
+            let syntax = Syntax {
+                pseudo: true,
+                abi_names: true,
+            };
+            let text = disasm::format(&inst, Some(pc), syntax);
+            match text.split_once(' ') {
+                Some((name, args)) => format!("{:<7} {}", name, args),
+                None => text,
+            }
+        }
+        Err(_) => "unknown".to_string(),
+    };
+
+    format!("core   0: 0x{:08x} (0x{:08x}) {}", pc, raw, text)
+}
+
+// Which retired instructions get logged: those with pc in [start, end), among the
+// `count` instructions retired after the first `skip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Filter {
+    pub start: u32,
+    pub end: Option<u32>,
+    pub skip: u64,
+    pub count: Option<u64>,
+}
+
+impl Filter {
+    pub fn matches(&self, pc: u32, index: u64) -> bool {
+        let in_range = pc >= self.start && self.end.is_none_or(|end| pc < end);
+        let in_window =
+            index >= self.skip && self.count.is_none_or(|count| index - self.skip < count);
+        in_range && in_window
+    }
+}
+
+// Writes a commit line for every retired instruction that passes the filter.
+pub struct CommitLog {
+    out: Option<Box<dyn Write>>,
+    pub filter: Filter,
+    pub disasm: bool, // Precede each commit with its disassembly, as with Spike's -l
+    pub last: Option<Commit>,
+    retired: u64,
+}
+
+impl CommitLog {
+    pub fn new(out: Box<dyn Write>, filter: Filter) -> Self {
+        CommitLog {
+            out: Some(out),
+            filter,
+            disasm: false,
+            last: None,
+            retired: 0,
+        }
+    }
+
+    // Keep only the `last` commit, without writing a log.
+    pub fn capture() -> Self {
+        CommitLog {
+            out: None,
+            filter: Filter::default(),
+            disasm: false,
+            last: None,
+            retired: 0,
+        }
+    }
+
+    // Log `commit` if the filter selects it. A log with holes would mislead whoever diffs
+    // it, so the first write error ends the log, with a note on stderr.
+    pub fn record(&mut self, commit: &Commit) {
+        let index = self.retired;
+        self.retired += 1;
+        self.last = Some(*commit);
+
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        if !self.filter.matches(commit.pc, index) {
+            return;
+        }
+
+        let mut written = Ok(());
+        if self.disasm {
+            written = writeln!(out, "{}", format_disasm(commit.pc, commit.raw));
+        }
+        if let Err(e) = written.and_then(|_| writeln!(out, "{}", format(commit))) {
+            eprintln!("Commit log ended after {} instructions: {}", index, e);
+            self.out = None;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::elf::Elf;
+
+// Instructions a test may run before it counts as hung. The longest rv32ui tests
+// finish in a few thousand.
+pub const MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+// How a test ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(u32),         // TESTNUM of the failing case
+    Timeout,           // Still running after the instruction limit
+    Exception(String), // An exception with no handler
+    Error(String),     // The test could not run
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(test) => write!(f, "FAIL   test {}", test),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+            Outcome::Exception(e) => write!(f, "FAIL   {}", e),
+            Outcome::Error(e) => write!(f, "ERROR  {}", e),
+        }
+    }
+}
+
+// The result of running one test binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub outcome: Outcome,
+    pub instructions: u64,
+    pub signature: Option<Vec<u32>>, // Words from begin_signature to end_signature
+}
+
+// RAM covering every segment of `elf` from `base`, in a power of two of at least `min`.
+pub fn memory_size(elf: &Elf, base: u32, min: u32) -> u32 {
+    let end = elf
+        .segments
+        .iter()
+        .map(|s| s.addr.wrapping_add(s.mem_size).wrapping_sub(base))
+        .max()
+        .unwrap_or(0);
+    end.max(min).checked_next_power_of_two().unwrap_or(end)
+}
+
+// What the test wrote to `tohost`, following riscv-tests' HTIF convention: 1 is a
+// pass, any other odd value fails test `value >> 1`, and even values are HTIF system
+// calls, which bare-metal tests never make.
+fn decode_tohost(value: u32) -> Option<Outcome> {
+    match value {
+        0 => None,
+        1 => Some(Outcome::Pass),
+        value if value & 1 == 1 => Some(Outcome::Fail(value >> 1)),
+        value => Some(Outcome::Error(format!(
+            "unsupported HTIF call 0x{:08x}",
+            value
+        ))),
+    }
+}
+
+// Read the words between the begin_signature and end_signature symbols of `elf`.
+pub fn signature(cpu: &mut CPU, elf: &Elf) -> Option<Vec<u32>> {
+    let begin = elf.symbols.get("begin_signature")?.addr;
+    let end = elf.symbols.get("end_signature")?.addr;
+
+    (begin..end)
+        .step_by(4)
+        .map(|addr| cpu.bus.read(addr, 4).ok())
+        .collect()
+}
+
+// A signature as riscv-arch-test and RISCOF compare it: one word per line, in lowercase
+// hex, lowest address first.
+pub fn format_signature(words: &[u32]) -> String {
+    words.iter().map(|word| format!("{:08x}\n", word)).collect()
+}
+
+// Run the test loaded into `cpu` until it writes `tohost`, then collect its signature.
+pub fn run(cpu: &mut CPU, elf: &Elf, max_instructions: u64) -> Report {
+    let mut report = Report {
+        outcome: Outcome::Timeout,
+        instructions: 0,
+        signature: None,
+    };
+
+    let Some(tohost) = elf.symbols.get("tohost").map(|symbol| symbol.addr) else {
+        report.outcome = Outcome::Error("no tohost symbol".to_string());
+        return report;
+    };
+
+    while report.instructions < max_instructions {
+        let pc = cpu.pc as u32;
+        report.instructions += 1;
+        if let Err(e) = cpu.step() {
+            report.outcome = Outcome::Exception(format!("{} at 0x{:08x}", e, pc));
+            break;
+        }
+
+        let value = cpu.bus.read(tohost, 4).unwrap_or(0);
+        if let Some(outcome) = decode_tohost(value) {
+            report.outcome = outcome;
+            break;
+        }
+    }
+
+    report.signature = signature(cpu, elf);
+    report
+}
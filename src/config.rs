@@ -0,0 +1,42 @@
+// Memory map and reset state for a CPU. Built with chained setters:
+//
+//     let config = Config::new().ram(0x8000_0000, 128 << 20).reset_pc(0x8000_0000);
+//     let cpu = CPU::with_config(config)?;
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub ram_base: u32,
+    pub ram_size: u32,
+    pub reset_pc: u32,
+    pub roms: Vec<(u32, Vec<u8>)>,
+}
+
+impl Config {
+    // 64 KiB of RAM at address 0, starting execution at 0.
+    pub fn new() -> Self {
+        Config {
+            ram_base: 0,
+            ram_size: 0x10000,
+            reset_pc: 0,
+            roms: Vec::new(),
+        }
+    }
+
+    // Map `size` bytes of zeroed RAM at `base`. The backing store is allocated zeroed on
+    // the heap, so the host only commits the pages the guest touches.
+    pub fn ram(mut self, base: u32, size: u32) -> Self {
+        self.ram_base = base;
+        self.ram_size = size;
+        self
+    }
+
+    pub fn reset_pc(mut self, pc: u32) -> Self {
+        self.reset_pc = pc;
+        self
+    }
+
+    // Map a read-only image at `base`, sized to fit `data`.
+    pub fn rom(mut self, base: u32, data: Vec<u8>) -> Self {
+        self.roms.push((base, data));
+        self
+    }
+}
@@ -1,13 +1,35 @@
-use std::{fs, result};
-
-use crate::isa::{Instruction, InstructionType, RV32I};
+use crate::bus::{Bus, BusError, Lines};
+use crate::commit::{Commit, CommitLog};
+use crate::config::Config;
+use crate::csr::{
+    CsrError, CsrFile, Privilege, MIP_MEIP, MIP_MSIP, MIP_MTIP, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE,
+    MSTATUS_MPP,
+};
+use crate::devices::{ram::Ram, rom::Rom};
+use crate::elf::{Elf, ElfError, SymbolTable};
+use crate::isa::{DecodeError, Instruction, InstructionType, NOP, REGISTER_NAMES, RV32I};
+use crate::semihost::{self, Semihosting};
+use crate::syscall::Syscalls;
+use crate::trap::Exception;
 
 pub struct CPU {
     pub regs: [u32; 32],
     pub pc: usize,
-    memory: [u8; 0x10000],
+    pub bus: Bus,
     pub exit_on_nop: bool,
     pub last_inst: Option<Instruction>,
+    pub last_trap: Option<Exception>,
+    pub csrs: CsrFile,
+    pub privilege: Privilege,
+    pub symbols: SymbolTable,
+    pub syscalls: Option<Syscalls>,
+    pub semihosting: Option<Semihosting>,
+    pub exit_code: Option<u8>,
+    pub commit_log: Option<CommitLog>,
+    // Interrupts and mtime driven by devices; hand clones to the devices that drive them.
+    pub lines: Lines,
+    // EBREAK halts for an attached debugger instead of trapping, like dcsr.ebreakm.
+    pub ebreak_halts: bool,
 }
 
 trait RV32ISA {
@@ -18,52 +40,52 @@ trait RV32ISA {
     fn auipc(&mut self, rd: u8, imm: u32);
 
     // Jump And Link: Performs a jump and saves the return address in rd.
-    fn jal(&mut self, rd: u8, imm: i16);
+    fn jal(&mut self, rd: u8, imm: i32) -> Result<(), Exception>;
 
     // Jump And Link Register: Jumps to address in rs1 + immediate and saves return address in rd.
-    fn jalr(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn jalr(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Equal: Branches if rs1 is equal to rs2.
-    fn beq(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn beq(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Not Equal: Branches if rs1 is not equal to rs2.
-    fn bne(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn bne(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Less Than: Branches if rs1 is less than rs2.
-    fn blt(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn blt(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Greater or Equal: Branches if rs1 is greater or equal to rs2.
-    fn bge(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn bge(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Less Than (Unsigned): Branches if rs1 is less than rs2, unsigned comparison.
-    fn bltu(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn bltu(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Branch if Greater or Equal (Unsigned): Branches if rs1 is greater or equal to rs2, unsigned comparison.
-    fn bgeu(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn bgeu(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Load Byte: Loads a byte from memory into rd.
-    fn lb(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn lb(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Load Half-word: Loads a half-word from memory into rd.
-    fn lh(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn lh(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Load Word: Loads a word from memory into rd.
-    fn lw(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn lw(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Load Byte Unsigned: Loads a byte from memory into rd, zero-extended.
-    fn lbu(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn lbu(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Load Half-word Unsigned: Loads a half-word from memory into rd, zero-extended.
-    fn lhu(&mut self, rd: u8, rs1: u8, imm: i16);
+    fn lhu(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception>;
 
     // Store Byte: Stores a byte to memory.
-    fn sb(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn sb(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Store Half-word: Stores a half-word to memory.
-    fn sh(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn sh(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Store Word: Stores a word to memory.
-    fn sw(&mut self, rs1: u8, rs2: u8, imm: i16);
+    fn sw(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception>;
 
     // Add Immediate: Adds an immediate value to rs1 and stores the result in rd.
     fn addi(&mut self, rd: u8, rs1: u8, imm: i16);
@@ -126,75 +148,335 @@ trait RV32ISA {
     fn fence(&mut self, rd: u8, rs1: u8, imm: u32);
 
     // Environment Call: Makes a call to the environment.
-    fn ecall(&mut self, rd: u8, rs1: u8, imm: u32);
+    fn ecall(&mut self, rd: u8, rs1: u8, imm: u32) -> Result<(), Exception>;
 
     // Environment Break: Breaks to the debugger.
-    fn ebreak(&mut self, rd: u8, rs1: u8, imm: u32);
+    fn ebreak(&mut self, rd: u8, rs1: u8, imm: u32) -> Result<(), Exception>;
 }
 
-pub trait Interface {
-    fn load(&mut self, instructions: &[u8]);
+trait RV32MISA {
+    // Multiply: Multiplies rs1 by rs2, stores the lower 32 bits in rd.
+    fn mul(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-    fn run(&mut self) -> u8;
+    // Multiply High: Multiplies signed rs1 by signed rs2, stores the upper 32 bits in rd.
+    fn mulh(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-    fn boot(&mut self, path: &str, radix: u8) -> u8 {
-        let instructions_str = fs::read_to_string(path).expect("Unable to read file");
-        let mut instructions_bytes = Vec::new();
+    // Multiply High Signed-Unsigned: Multiplies signed rs1 by unsigned rs2, stores the upper 32 bits in rd.
+    fn mulhsu(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-        for line in instructions_str.lines() {
-            let instruction = u32::from_str_radix(line, radix as u32).expect("Invalid number");
-            let bytes = instruction.to_le_bytes();
-            instructions_bytes.extend_from_slice(&bytes);
-        }
+    // Multiply High Unsigned: Multiplies unsigned rs1 by unsigned rs2, stores the upper 32 bits in rd.
+    fn mulhu(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-        self.load(&instructions_bytes);
-        self.run()
-    }
+    // Divide: Divides signed rs1 by signed rs2, rounding towards zero, stores in rd.
+    fn div(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-    fn from_inst(&mut self, instruction: Vec<u32>) {
-        let bytes = instruction
-            .iter()
-            .flat_map(|inst| inst.to_le_bytes().to_vec())
-            .collect::<Vec<u8>>();
+    // Divide Unsigned: Divides unsigned rs1 by unsigned rs2, stores in rd.
+    fn divu(&mut self, rd: u8, rs1: u8, rs2: u8);
 
-        self.load(&bytes);
-    }
+    // Remainder: Stores the remainder of signed rs1 / rs2 in rd.
+    fn rem(&mut self, rd: u8, rs1: u8, rs2: u8);
+
+    // Remainder Unsigned: Stores the remainder of unsigned rs1 / rs2 in rd.
+    fn remu(&mut self, rd: u8, rs1: u8, rs2: u8);
+}
+
+trait ZicsrISA {
+    // Atomic Read/Write CSR: Swaps the CSR with rs1, old value goes to rd (not read if rd is x0).
+    fn csrrw(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError>;
+
+    // Atomic Read and Set Bits: Sets the CSR bits that are set in rs1, old value goes to rd.
+    fn csrrs(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError>;
+
+    // Atomic Read and Clear Bits: Clears the CSR bits that are set in rs1, old value goes to rd.
+    fn csrrc(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError>;
+
+    // Atomic Read/Write CSR Immediate: Like CSRRW with a 5-bit zero-extended immediate.
+    fn csrrwi(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError>;
+
+    // Atomic Read and Set Bits Immediate: Like CSRRS with a 5-bit zero-extended immediate.
+    fn csrrsi(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError>;
+
+    // Atomic Read and Clear Bits Immediate: Like CSRRC with a 5-bit zero-extended immediate.
+    fn csrrci(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError>;
+}
+
+trait PrivilegedISA {
+    // Machine Trap Return: Returns from a machine-mode trap handler to mepc.
+    fn mret(&mut self);
 }
 
 impl CPU {
-    pub fn new() -> Self {
-        CPU {
+    // Build a CPU with the given memory map. Fails if two regions overlap or run past the
+    // end of the address space.
+    pub fn with_config(config: Config) -> Result<Self, BusError> {
+        let mut bus = Bus::new();
+        bus.attach(
+            config.ram_base,
+            config.ram_size,
+            Box::new(Ram::new(config.ram_size as usize)),
+        )?;
+
+        for (base, data) in config.roms {
+            bus.attach(base, data.len() as u32, Box::new(Rom::new(data)))?;
+        }
+
+        Ok(CPU {
             regs: [0; 32],
-            pc: 0,
-            memory: [0; 0x10000],
+            pc: config.reset_pc as usize,
+            bus,
             exit_on_nop: false,
             last_inst: None,
+            last_trap: None,
+            csrs: CsrFile::new(),
+            privilege: Privilege::Machine,
+            symbols: SymbolTable::default(),
+            syscalls: None,
+            semihosting: None,
+            exit_code: None,
+            commit_log: None,
+            lines: Lines::default(),
+            ebreak_halts: false,
+        })
+    }
+
+    // Copy every PT_LOAD segment of `elf` into memory, zero its .bss, point pc at the
+    // entry point and keep the symbol table for symbolization.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), ElfError> {
+        for segment in &elf.segments {
+            let mut image = segment.data.clone();
+            image.resize(segment.mem_size as usize, 0);
+            self.bus
+                .load(segment.addr, &image)
+                .map_err(|e| ElfError::Load(segment.addr, e))?;
+        }
+
+        self.pc = elf.entry as usize;
+        self.symbols = elf.symbols.clone();
+        Ok(())
+    }
+
+    fn fetch(&mut self) -> Result<u32, Exception> {
+        let addr = self.pc as u32;
+        if !addr.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(addr));
+        }
+
+        let inst = self
+            .bus
+            .read(addr, 4)
+            .map_err(|_| Exception::InstructionAccessFault(addr))?;
+        self.pc = self.pc.wrapping_add_32bit(4);
+        Ok(inst)
+    }
+
+    // Address of the instruction being executed; `fetch` has already advanced `pc`.
+    fn inst_pc(&self) -> usize {
+        self.pc.wrapping_sub(4) & 0xFFFFFFFF
+    }
+
+    // The memory a load or store is about to access, as (address, size, store), from the
+    // registers before it executes.
+    pub fn memory_access(&self, inst: &Instruction) -> Option<(u32, u32, bool)> {
+        let (rs1, imm, size, store) = match (inst.inst, inst.inst_type) {
+            (RV32I::LB | RV32I::LBU, InstructionType::I(i)) => (i.rs1, i.imm, 1, false),
+            (RV32I::LH | RV32I::LHU, InstructionType::I(i)) => (i.rs1, i.imm, 2, false),
+            (RV32I::LW, InstructionType::I(i)) => (i.rs1, i.imm, 4, false),
+            (RV32I::SB, InstructionType::S(s)) => (s.rs1, s.imm, 1, true),
+            (RV32I::SH, InstructionType::S(s)) => (s.rs1, s.imm, 2, true),
+            (RV32I::SW, InstructionType::S(s)) => (s.rs1, s.imm, 4, true),
+            _ => return None,
+        };
+
+        let addr = self.regs[rs1 as usize].wrapping_add(imm as i32 as u32);
+        Some((addr, size, store))
+    }
+
+    // Whether the EBREAK at `pc` sits in the semihosting entry sequence.
+    fn is_semihosting_call(&mut self, pc: u32) -> bool {
+        let before = self.bus.read(pc.wrapping_sub(4), 4);
+        let after = self.bus.read(pc.wrapping_add(4), 4);
+        before == Ok(semihost::ENTRY) && after == Ok(semihost::EXIT)
+    }
+
+    fn pc_relative(&self, offset: i32) -> usize {
+        self.inst_pc().wrapping_add_32bit(offset as usize)
+    }
+
+    // Without the C extension every jump target must be word aligned.
+    fn jump(&mut self, target: usize) -> Result<(), Exception> {
+        if !target.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(target as u32));
+        }
+
+        self.pc = target;
+        Ok(())
+    }
+
+    fn effective_addr(&self, rs1: u8, imm: i16) -> u32 {
+        self.regs[rs1 as usize].wrapping_add(imm as i32 as u32)
+    }
+
+    // Load a zero-extended little-endian value of `size` bytes (1, 2 or 4).
+    fn load_mem(&mut self, addr: u32, size: usize) -> Result<u32, Exception> {
+        if !(addr as usize).is_multiple_of(size) {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+
+        self.bus
+            .read(addr, size)
+            .map_err(|_| Exception::LoadAccessFault(addr))
+    }
+
+    // Store the low `size` bytes (1, 2 or 4) of `value` in little-endian order.
+    fn store_mem(&mut self, addr: u32, size: usize, value: u32) -> Result<(), Exception> {
+        if !(addr as usize).is_multiple_of(size) {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+
+        self.bus
+            .write(addr, size, value)
+            .map_err(|_| Exception::StoreAccessFault(addr))
+    }
+
+    fn decode(&self, inst: u32) -> Result<Instruction, DecodeError> {
+        Instruction::try_from(inst)
+    }
+
+    // Record the trap in mepc, mcause and mtval, stack the interrupt enable and previous
+    // privilege, then enter M-mode. The caller points pc at the handler.
+    fn enter_trap(&mut self, epc: u32, cause: u32, tval: u32) {
+        self.csrs.mepc = epc;
+        self.csrs.mcause = cause;
+        self.csrs.mtval = tval;
+
+        let mstatus = self.csrs.mstatus;
+        let mpie = if mstatus & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        let mpp = (self.privilege as u32) << 11;
+        self.csrs.mstatus = (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+        self.privilege = Privilege::Machine;
+    }
+
+    // Enter the machine-mode trap handler for an exception raised by the instruction at
+    // `epc`. Returns false, leaving pc on the faulting instruction, if no handler is
+    // installed (mtvec is zero).
+    fn take_trap(&mut self, exception: Exception, epc: u32) -> bool {
+        self.last_trap = Some(exception);
+
+        let base = self.csrs.mtvec & !0b11;
+        if base == 0 {
+            self.pc = epc as usize;
+            return false;
+        }
+
+        self.enter_trap(epc, exception.cause(), exception.tval());
+
+        // Synchronous exceptions always go to BASE, even in vectored mode.
+        self.pc = base as usize;
+        true
+    }
+
+    // Enter the trap handler for the highest-priority interrupt that is pending and
+    // enabled, if any, before the instruction at pc. Returns false if none was taken.
+    fn take_interrupt(&mut self) -> bool {
+        let pending = self.csrs.mip & self.csrs.mie;
+        let base = self.csrs.mtvec & !0b11;
+        let enabled = self.csrs.mstatus & MSTATUS_MIE != 0 || self.privilege < Privilege::Machine;
+        if pending == 0 || !enabled || base == 0 {
+            return false;
         }
+
+        // External, then software, then timer, as the privileged spec orders them.
+        let Some(code) = [11, 3, 7]
+            .into_iter()
+            .find(|code| pending & (1 << code) != 0)
+        else {
+            return false;
+        };
+
+        self.enter_trap(self.pc as u32, 0x8000_0000 | code, 0);
+
+        // Vectored mode sends interrupts to BASE + 4 * cause.
+        let vectored = self.csrs.mtvec & 1 != 0;
+        self.pc = (base + if vectored { 4 * code } else { 0 }) as usize;
+        true
     }
 
-    fn fetch(&mut self) -> u32 {
-        let inst = u32::from_le_bytes([
-            self.memory[self.pc],
-            self.memory[self.pc + 1],
-            self.memory[self.pc + 2],
-            self.memory[self.pc + 3],
-        ]);
-        self.pc += 4;
-        inst
+    // Let the devices run for a step, then latch the interrupts and time they drive.
+    fn tick(&mut self) {
+        self.bus.tick();
+        let driven = MIP_MSIP | MIP_MTIP | MIP_MEIP;
+        self.csrs.mip = (self.csrs.mip & !driven) | (self.lines.mip() & driven);
+        if let Some(mtime) = self.lines.mtime() {
+            self.csrs.time = mtime;
+        }
     }
 
-    fn decode(&self, inst: u32) -> Instruction {
-        Instruction::from(inst)
+    // Take a pending interrupt, or fetch, decode and execute a single instruction, taking
+    // a trap if it raises an exception. Returns the exception if there was no handler to
+    // take it.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let epc = self.pc as u32;
+        let result = if self.take_interrupt() {
+            Ok(())
+        } else {
+            match self.try_step() {
+                Ok(()) => Ok(()),
+                Err(Exception::Breakpoint(addr)) if self.ebreak_halts => {
+                    self.pc = epc as usize;
+                    Err(Exception::Breakpoint(addr))
+                }
+                Err(exception) if self.take_trap(exception, epc) => Ok(()),
+                Err(exception) => Err(exception),
+            }
+        };
+
+        self.tick();
+        result
+    }
+
+    fn try_step(&mut self) -> Result<(), Exception> {
+        let raw = self.fetch()?;
+        // Programs run with `exit_on_nop` end when they run off into zeroed memory.
+        let word = if raw == 0 && self.exit_on_nop {
+            NOP
+        } else {
+            raw
+        };
+        let inst = self
+            .decode(word)
+            .map_err(|_| Exception::IllegalInstruction(raw))?;
+        let commit = self
+            .commit_log
+            .is_some()
+            .then(|| Commit::new(self, self.inst_pc() as u32, raw, &inst));
+
+        self.csrs.written = None;
+        self.execute(inst)?;
+        self.csrs.retire();
+        self.lines.retire();
+        self.last_inst = Some(inst);
+
+        if let Some(mut commit) = commit {
+            commit.finish(self, &inst);
+            if let Some(log) = &mut self.commit_log {
+                log.record(&commit);
+            }
+        }
+        Ok(())
     }
 
     pub fn print_state(&self) {
         println!("PC:  0x{:08X}", self.pc);
         for (i, reg) in self.regs.iter().enumerate() {
-            println!("x{:02}: 0x{:08}", i, reg);
+            println!("x{:02} {:>4}: 0x{:08X}", i, REGISTER_NAMES[i], reg);
         }
     }
 
-    fn execute(&mut self, inst: Instruction) -> u8 {
+    fn execute(&mut self, inst: Instruction) -> Result<(), Exception> {
         match inst.inst {
             RV32I::LUI => {
                 let args = if let InstructionType::U(inst) = inst.inst_type {
@@ -223,7 +505,7 @@ impl CPU {
                     panic!("Invalid instruction type for JAL")
                 };
 
-                self.jal(args.rd, args.imm as i16);
+                self.jal(args.rd, args.imm)?;
             }
 
             RV32I::JALR => {
@@ -233,7 +515,7 @@ impl CPU {
                     panic!("Invalid instruction type for JALR")
                 };
 
-                self.jalr(args.rd, args.rs1, args.imm);
+                self.jalr(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::BEQ => {
@@ -243,7 +525,7 @@ impl CPU {
                     panic!("Invalid instruction type for BEQ")
                 };
 
-                self.beq(args.rs1, args.rs2, args.imm);
+                self.beq(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::BNE => {
@@ -253,7 +535,7 @@ impl CPU {
                     panic!("Invalid instruction type for BNE")
                 };
 
-                self.bne(args.rs1, args.rs2, args.imm);
+                self.bne(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::BLT => {
@@ -263,7 +545,7 @@ impl CPU {
                     panic!("Invalid instruction type for BLT")
                 };
 
-                self.blt(args.rs1, args.rs2, args.imm);
+                self.blt(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::BGE => {
@@ -273,7 +555,7 @@ impl CPU {
                     panic!("Invalid instruction type for BGE")
                 };
 
-                self.bge(args.rs1, args.rs2, args.imm);
+                self.bge(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::BLTU => {
@@ -283,7 +565,7 @@ impl CPU {
                     panic!("Invalid instruction type for BLTU")
                 };
 
-                self.bltu(args.rs1, args.rs2, args.imm);
+                self.bltu(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::BGEU => {
@@ -293,7 +575,7 @@ impl CPU {
                     panic!("Invalid instruction type for BGEU")
                 };
 
-                self.bgeu(args.rs1, args.rs2, args.imm);
+                self.bgeu(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::LB => {
@@ -303,7 +585,7 @@ impl CPU {
                     panic!("Invalid instruction type for LB")
                 };
 
-                self.lb(args.rd, args.rs1, args.imm);
+                self.lb(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::LH => {
@@ -313,7 +595,7 @@ impl CPU {
                     panic!("Invalid instruction type for LH")
                 };
 
-                self.lh(args.rd, args.rs1, args.imm);
+                self.lh(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::LW => {
@@ -323,7 +605,7 @@ impl CPU {
                     panic!("Invalid instruction type for LW")
                 };
 
-                self.lw(args.rd, args.rs1, args.imm);
+                self.lw(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::LBU => {
@@ -333,7 +615,7 @@ impl CPU {
                     panic!("Invalid instruction type for LBU")
                 };
 
-                self.lbu(args.rd, args.rs1, args.imm);
+                self.lbu(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::LHU => {
@@ -343,7 +625,7 @@ impl CPU {
                     panic!("Invalid instruction type for LHU")
                 };
 
-                self.lhu(args.rd, args.rs1, args.imm);
+                self.lhu(args.rd, args.rs1, args.imm)?;
             }
 
             RV32I::SB => {
@@ -353,7 +635,7 @@ impl CPU {
                     panic!("Invalid instruction type for SB")
                 };
 
-                self.sb(args.rs1, args.rs2, args.imm);
+                self.sb(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::SH => {
@@ -363,7 +645,7 @@ impl CPU {
                     panic!("Invalid instruction type for SH")
                 };
 
-                self.sh(args.rs1, args.rs2, args.imm);
+                self.sh(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::SW => {
@@ -373,7 +655,7 @@ impl CPU {
                     panic!("Invalid instruction type for SW")
                 };
 
-                self.sw(args.rs1, args.rs2, args.imm);
+                self.sw(args.rs1, args.rs2, args.imm)?;
             }
 
             RV32I::ADDI => {
@@ -466,163 +748,431 @@ impl CPU {
                 self.srai(args.rd, args.rs1, args.imm);
             }
 
-            _ => {
-                println!("Unimplemented instruction: {:?}", inst);
-                return 1;
+            RV32I::ADD => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for ADD")
+                };
+
+                self.add(args.rd, args.rs1, args.rs2);
             }
-        }
 
-        0
-    }
-}
+            RV32I::SUB => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SUB")
+                };
 
-impl Interface for CPU {
-    fn load(&mut self, instructions: &[u8]) {
-        for (i, inst) in instructions.iter().enumerate() {
-            self.memory[i + self.pc] = *inst;
-        }
-    }
+                self.sub(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::SLL => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SLL")
+                };
+
+                self.sll(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::SLT => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SLT")
+                };
+
+                self.slt(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::SLTU => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SLTU")
+                };
+
+                self.sltu(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::XOR => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for XOR")
+                };
+
+                self.xor(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::SRL => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SRL")
+                };
+
+                self.srl(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::SRA => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for SRA")
+                };
+
+                self.sra(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::OR => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for OR")
+                };
+
+                self.or(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::AND => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for AND")
+                };
+
+                self.and(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::FENCE => {
+                let args = if let InstructionType::FENCE(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for FENCE")
+                };
+
+                let imm = ((args.fm as u32) << 8) | ((args.pred as u32) << 4) | args.succ as u32;
+                self.fence(args.rd, args.rs1, imm);
+            }
+
+            // There is no instruction cache: every fetch reads the bus, so stores are
+            // already visible to it. The other fields are reserved and ignored.
+            RV32I::FENCEI => {}
+
+            RV32I::ECALL => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for ECALL")
+                };
+
+                self.ecall(args.rd, args.rs1, args.imm as u32)?;
+            }
+            RV32I::EBREAK => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for EBREAK")
+                };
+
+                self.ebreak(args.rd, args.rs1, args.imm as u32)?;
+            }
+
+            RV32I::MUL => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for MUL")
+                };
+
+                self.mul(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::MULH => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for MULH")
+                };
+
+                self.mulh(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::MULHSU => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for MULHSU")
+                };
+
+                self.mulhsu(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::MULHU => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for MULHU")
+                };
+
+                self.mulhu(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::DIV => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for DIV")
+                };
+
+                self.div(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::DIVU => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for DIVU")
+                };
+
+                self.divu(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::REM => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for REM")
+                };
+
+                self.rem(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::REMU => {
+                let args = if let InstructionType::R(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for REMU")
+                };
+
+                self.remu(args.rd, args.rs1, args.rs2);
+            }
+
+            RV32I::CSRRW => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRW")
+                };
+
+                self.csrrw(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
+            }
+
+            RV32I::CSRRS => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRS")
+                };
 
-    fn run(&mut self) -> u8 {
-        loop {
-            let inst = self.fetch();
-            let inst = self.decode(inst);
-            self.execute(inst);
-            self.last_inst = Some(inst);
-            if self.exit_on_nop && inst.is_nop() {
-                return 0;
+                self.csrrs(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
             }
+
+            RV32I::CSRRC => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRC")
+                };
+
+                self.csrrc(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
+            }
+
+            RV32I::CSRRWI => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRWI")
+                };
+
+                self.csrrwi(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
+            }
+
+            RV32I::CSRRSI => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRSI")
+                };
+
+                self.csrrsi(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
+            }
+
+            RV32I::CSRRCI => {
+                let args = if let InstructionType::I(inst) = inst.inst_type {
+                    inst
+                } else {
+                    panic!("Invalid instruction type for CSRRCI")
+                };
+
+                self.csrrci(args.rd, args.rs1, args.csr())
+                    .map_err(|_| Exception::IllegalInstruction(inst.raw))?;
+            }
+
+            RV32I::MRET => {
+                if self.privilege != Privilege::Machine {
+                    return Err(Exception::IllegalInstruction(inst.raw));
+                }
+
+                self.mret();
+            }
+
+            // A legal WFI may return at once. Idle loops spin around it, and a pending
+            // interrupt is taken at the next step.
+            RV32I::WFI => {}
         }
+
+        // x0 is hardwired to zero; discard anything written to it.
+        self.regs[0] = 0;
+
+        Ok(())
     }
 }
 
 impl RV32ISA for CPU {
     fn lui(&mut self, rd: u8, imm: u32) {
-        self.regs[rd as usize] = imm;
+        self.regs[rd as usize] = imm << 12;
     }
 
     fn auipc(&mut self, rd: u8, imm: u32) {
-        self.regs[rd as usize] = (self.pc as u32) + imm;
+        self.regs[rd as usize] = (self.inst_pc() as u32).wrapping_add(imm << 12);
     }
 
-    fn jal(&mut self, rd: u8, imm: i16) {
-        let tmp_pc = self.pc;
-        let sign_extended_imm = ((imm as i32) << 1 >> 1) as u32; // Sign extend the immediate value.
-        self.pc = self.pc.wrapping_add_32bit(sign_extended_imm as usize); // Jump to the new address.
-        self.regs[rd as usize] = (tmp_pc as u32).wrapping_add(4); // Return PC + 4 to rd.
+    fn jal(&mut self, rd: u8, imm: i32) -> Result<(), Exception> {
+        let return_addr = self.pc as u32;
+        self.jump(self.pc_relative(imm))?; // Jump to the new address.
+        self.regs[rd as usize] = return_addr; // Return PC + 4 to rd.
+        Ok(())
     }
 
-    fn jalr(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let tmp_pc = self.pc;
-        let sign_extended_imm = ((imm as i32) << 1 >> 1) as u32; // Sign extend the immediate value.
-        self.pc = self.regs[rs1 as usize].wrapping_add_12bit(sign_extended_imm) as usize; // Jump to the new address.
-        self.regs[rd as usize] = (tmp_pc as u32).wrapping_add_12bit(4); // Return PC + 4 to rd.
+    fn jalr(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let return_addr = self.pc as u32;
+        let target = self.regs[rs1 as usize].wrapping_add(imm as i32 as u32) & !1;
+        self.jump(target as usize)?; // Jump to the new address.
+        self.regs[rd as usize] = return_addr; // Return PC + 4 to rd.
+        Ok(())
     }
 
-    fn beq(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn beq(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize];
         let rs2_val = self.regs[rs2 as usize];
         if rs1_val == rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn bne(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn bne(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize];
         let rs2_val = self.regs[rs2 as usize];
         if rs1_val != rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn blt(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn blt(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize] as i32;
         let rs2_val = self.regs[rs2 as usize] as i32;
         if rs1_val < rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn bge(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn bge(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize] as i32;
         let rs2_val = self.regs[rs2 as usize] as i32;
         if rs1_val >= rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn bltu(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn bltu(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize];
         let rs2_val = self.regs[rs2 as usize];
         if rs1_val < rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn bgeu(&mut self, rs1: u8, rs2: u8, imm: i16) {
+    fn bgeu(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
         let rs1_val = self.regs[rs1 as usize];
         let rs2_val = self.regs[rs2 as usize];
         if rs1_val >= rs2_val {
-            self.pc = ((self.pc as i32) + ((imm as i32) << 1)) as usize;
+            self.jump(self.pc_relative(imm as i32))?;
         }
+        Ok(())
     }
 
-    fn lb(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add(imm) as usize;
-        self.regs[rd as usize] = self.memory[addr] as i8 as i32 as u32;
+    fn lb(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.regs[rd as usize] = self.load_mem(addr, 1)? as i8 as i32 as u32;
+        Ok(())
     }
 
-    fn lh(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        self.regs[rd as usize] =
-            u16::from_le_bytes([self.memory[addr], self.memory[addr + 1]]) as i16 as i32 as u32;
+    fn lh(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.regs[rd as usize] = self.load_mem(addr, 2)? as i16 as i32 as u32;
+        Ok(())
     }
 
-    fn lw(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        self.regs[rd as usize] = u32::from_le_bytes([
-            self.memory[addr],
-            self.memory[addr + 1],
-            self.memory[addr + 2],
-            self.memory[addr + 3],
-        ]);
+    fn lw(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.regs[rd as usize] = self.load_mem(addr, 4)?;
+        Ok(())
     }
 
-    fn lbu(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        self.regs[rd as usize] = self.memory[addr] as u32;
+    fn lbu(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.regs[rd as usize] = self.load_mem(addr, 1)?;
+        Ok(())
     }
 
-    fn lhu(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        self.regs[rd as usize] =
-            u16::from_le_bytes([self.memory[addr], self.memory[addr + 1]]) as u32;
+    fn lhu(&mut self, rd: u8, rs1: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.regs[rd as usize] = self.load_mem(addr, 2)?;
+        Ok(())
     }
 
-    fn sb(&mut self, rs1: u8, rs2: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        self.memory[addr] = self.regs[rs2 as usize] as u8;
+    fn sb(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.store_mem(addr, 1, self.regs[rs2 as usize])
     }
 
-    fn sh(&mut self, rs1: u8, rs2: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        let bytes = self.regs[rs2 as usize].to_le_bytes();
-        self.memory[addr] = bytes[0];
-        self.memory[addr + 1] = bytes[1];
+    fn sh(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.store_mem(addr, 2, self.regs[rs2 as usize])
     }
 
-    fn sw(&mut self, rs1: u8, rs2: u8, imm: i16) {
-        let addr = self.regs[rs1 as usize].wrapping_add_12bit(imm) as usize;
-        let bytes = self.regs[rs2 as usize].to_le_bytes();
-        self.memory[addr] = bytes[0];
-        self.memory[addr + 1] = bytes[1];
-        self.memory[addr + 2] = bytes[2];
-        self.memory[addr + 3] = bytes[3];
+    fn sw(&mut self, rs1: u8, rs2: u8, imm: i16) -> Result<(), Exception> {
+        let addr = self.effective_addr(rs1, imm);
+        self.store_mem(addr, 4, self.regs[rs2 as usize])
     }
 
     fn addi(&mut self, rd: u8, rs1: u8, imm: i16) {
-        let imm = ((imm as i32) << 20) >> 20; // Sign extend the 12-bit immediate value
-        self.regs[rd as usize] = self.regs[rs1 as usize].wrapping_add_12bit(imm as u32);
+        self.regs[rd as usize] = self.regs[rs1 as usize].wrapping_add(imm as i32 as u32);
     }
 
     fn slti(&mut self, rd: u8, rs1: u8, imm: i16) {
@@ -636,7 +1186,7 @@ impl RV32ISA for CPU {
 
     fn sltiu(&mut self, rd: u8, rs1: u8, imm: i16) {
         let rs1_val = self.regs[rs1 as usize];
-        if rs1_val < imm {
+        if rs1_val < (imm as i32 as u32) {
             self.regs[rd as usize] = 1;
         } else {
             self.regs[rd as usize] = 0;
@@ -644,28 +1194,28 @@ impl RV32ISA for CPU {
     }
 
     fn xori(&mut self, rd: u8, rs1: u8, imm: i16) {
-        self.regs[rd as usize] = self.regs[rs1 as usize] ^ imm;
+        self.regs[rd as usize] = self.regs[rs1 as usize] ^ (imm as i32 as u32);
     }
 
     fn ori(&mut self, rd: u8, rs1: u8, imm: i16) {
-        self.regs[rd as usize] = self.regs[rs1 as usize] | imm;
+        self.regs[rd as usize] = self.regs[rs1 as usize] | (imm as i32 as u32);
     }
 
     fn andi(&mut self, rd: u8, rs1: u8, imm: i16) {
-        self.regs[rd as usize] = self.regs[rs1 as usize] & imm;
+        self.regs[rd as usize] = self.regs[rs1 as usize] & (imm as i32 as u32);
     }
 
     fn slli(&mut self, rd: u8, rs1: u8, imm: i16) {
-        self.regs[rd as usize] = self.regs[rs1 as usize] << imm;
+        self.regs[rd as usize] = self.regs[rs1 as usize] << (imm & 0x1F);
     }
 
     fn srli(&mut self, rd: u8, rs1: u8, imm: i16) {
-        self.regs[rd as usize] = self.regs[rs1 as usize] >> imm;
+        self.regs[rd as usize] = self.regs[rs1 as usize] >> (imm & 0x1F);
     }
 
     fn srai(&mut self, rd: u8, rs1: u8, imm: i16) {
         let rs1_val = self.regs[rs1 as usize] as i32;
-        self.regs[rd as usize] = (rs1_val >> imm) as u32;
+        self.regs[rd as usize] = (rs1_val >> (imm & 0x1F)) as u32;
     }
 
     fn add(&mut self, rd: u8, rs1: u8, rs2: u8) {
@@ -721,44 +1271,169 @@ impl RV32ISA for CPU {
         self.regs[rd as usize] = self.regs[rs1 as usize] & self.regs[rs2 as usize];
     }
 
-    fn fence(&mut self, rd: u8, rs1: u8, imm: u32) {}
+    // A single hart with in-order memory has nothing to order.
+    fn fence(&mut self, _rd: u8, _rs1: u8, _imm: u32) {}
+
+    fn ecall(&mut self, _rd: u8, _rs1: u8, _imm: u32) -> Result<(), Exception> {
+        // With syscall emulation on, ECALL is a Linux system call rather than a trap.
+        if let Some(syscalls) = &mut self.syscalls {
+            if let Some(code) = syscalls.handle(&mut self.regs, &mut self.bus) {
+                self.exit_code = Some(code);
+            }
+            return Ok(());
+        }
+
+        Err(Exception::EnvironmentCall(self.privilege))
+    }
 
-    fn ecall(&mut self, rd: u8, rs1: u8, imm: u32) {}
+    fn ebreak(&mut self, _rd: u8, _rs1: u8, _imm: u32) -> Result<(), Exception> {
+        // With semihosting on, an EBREAK between `slli x0, x0, 0x1f` and `srai x0, x0, 7`
+        // is a call to the host rather than a breakpoint.
+        let pc = self.inst_pc() as u32;
+        if self.semihosting.is_some() && self.is_semihosting_call(pc) {
+            let semihosting = self.semihosting.as_mut().unwrap();
+            if let Some(code) = semihosting.handle(&mut self.regs, &mut self.bus) {
+                self.exit_code = Some(code);
+            }
+            return Ok(());
+        }
 
-    fn ebreak(&mut self, rd: u8, rs1: u8, imm: u32) {}
+        Err(Exception::Breakpoint(self.inst_pc() as u32))
+    }
 }
 
-trait TwelveBitWrappingOps {
-    fn wrapping_add_12bit(self, rhs: Self) -> Self;
+// Division never traps: dividing by zero and signed overflow produce the values the M
+// extension specifies instead.
+impl RV32MISA for CPU {
+    fn mul(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        self.regs[rd as usize] = self.regs[rs1 as usize].wrapping_mul(self.regs[rs2 as usize]);
+    }
+
+    fn mulh(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize] as i32 as i64;
+        let rs2_val = self.regs[rs2 as usize] as i32 as i64;
+        self.regs[rd as usize] = ((rs1_val * rs2_val) >> 32) as u32;
+    }
+
+    fn mulhsu(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize] as i32 as i64;
+        let rs2_val = self.regs[rs2 as usize] as i64;
+        self.regs[rd as usize] = ((rs1_val * rs2_val) >> 32) as u32;
+    }
 
-    fn wrapping_sub_12bit(self, rhs: Self) -> Self;
+    fn mulhu(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize] as u64;
+        let rs2_val = self.regs[rs2 as usize] as u64;
+        self.regs[rd as usize] = ((rs1_val * rs2_val) >> 32) as u32;
+    }
+
+    fn div(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize] as i32;
+        let rs2_val = self.regs[rs2 as usize] as i32;
+        self.regs[rd as usize] = if rs2_val == 0 {
+            u32::MAX
+        } else {
+            rs1_val.wrapping_div(rs2_val) as u32 // i32::MIN / -1 wraps to i32::MIN
+        };
+    }
+
+    fn divu(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize];
+        let rs2_val = self.regs[rs2 as usize];
+        self.regs[rd as usize] = rs1_val.checked_div(rs2_val).unwrap_or(u32::MAX);
+    }
+
+    fn rem(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize] as i32;
+        let rs2_val = self.regs[rs2 as usize] as i32;
+        self.regs[rd as usize] = if rs2_val == 0 {
+            rs1_val as u32
+        } else {
+            rs1_val.wrapping_rem(rs2_val) as u32 // i32::MIN % -1 is 0
+        };
+    }
+
+    fn remu(&mut self, rd: u8, rs1: u8, rs2: u8) {
+        let rs1_val = self.regs[rs1 as usize];
+        let rs2_val = self.regs[rs2 as usize];
+        self.regs[rd as usize] = rs1_val.checked_rem(rs2_val).unwrap_or(rs1_val);
+    }
 }
 
-impl TwelveBitWrappingOps for i16 {
-    fn wrapping_add_12bit(self, rhs: Self) -> i16 {
-        let result = self.wrapping_add(rhs);
-        if result >= 2047 {
-            return 2047;
-        }
+impl PrivilegedISA for CPU {
+    fn mret(&mut self) {
+        let mstatus = self.csrs.mstatus;
+        let mie = if mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        self.privilege = Privilege::from_bits(mstatus >> 11);
+
+        // Only M-mode is implemented, so MPP is left pointing at it.
+        self.csrs.mstatus = (mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE | MSTATUS_MPP;
+        self.csrs.written = Some(MSTATUS);
+        self.pc = self.csrs.mepc as usize;
+    }
+}
 
-        if result <= -2047 {
-            return -2047;
-        }
+// A CSR is only read when rd is not x0 (CSRRW) and only written when the source is not x0
+// or a zero immediate (CSRRS/CSRRC), so those forms never fault on read- or write-only access.
+impl ZicsrISA for CPU {
+    fn csrrw(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError> {
+        let value = self.regs[rs1 as usize];
+        self.csrrw_value(rd, value, csr)
+    }
 
-        result
+    fn csrrs(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError> {
+        let mask = self.regs[rs1 as usize];
+        self.csr_set_clear(rd, mask, rs1 != 0, csr, |old, mask| old | mask)
     }
 
-    fn wrapping_sub_12bit(self, rhs: Self) -> i16 {
-        let result = self.wrapping_add(rhs);
-        if result >= 2047 {
-            return 2047;
-        }
+    fn csrrc(&mut self, rd: u8, rs1: u8, csr: u16) -> Result<(), CsrError> {
+        let mask = self.regs[rs1 as usize];
+        self.csr_set_clear(rd, mask, rs1 != 0, csr, |old, mask| old & !mask)
+    }
 
-        if result <= -2047 {
-            return -2047;
-        }
+    fn csrrwi(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError> {
+        self.csrrw_value(rd, uimm as u32, csr)
+    }
 
-        result
+    fn csrrsi(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError> {
+        self.csr_set_clear(rd, uimm as u32, uimm != 0, csr, |old, mask| old | mask)
+    }
+
+    fn csrrci(&mut self, rd: u8, uimm: u8, csr: u16) -> Result<(), CsrError> {
+        self.csr_set_clear(rd, uimm as u32, uimm != 0, csr, |old, mask| old & !mask)
+    }
+}
+
+impl CPU {
+    fn csrrw_value(&mut self, rd: u8, value: u32, csr: u16) -> Result<(), CsrError> {
+        let old = if rd != 0 {
+            self.csrs.read(csr, self.privilege)?
+        } else {
+            0
+        };
+        self.csrs.write(csr, value, self.privilege)?;
+        self.regs[rd as usize] = old;
+        Ok(())
+    }
+
+    fn csr_set_clear(
+        &mut self,
+        rd: u8,
+        mask: u32,
+        write: bool,
+        csr: u16,
+        op: fn(u32, u32) -> u32,
+    ) -> Result<(), CsrError> {
+        let old = self.csrs.read(csr, self.privilege)?;
+        if write {
+            self.csrs.write(csr, op(old, mask), self.privilege)?;
+        }
+        self.regs[rd as usize] = old;
+        Ok(())
     }
 }
 
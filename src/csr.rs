@@ -0,0 +1,244 @@
+use std::fmt;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// Machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+
+// Machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Machine counters
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+// Unprivileged counters (read-only shadows)
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+// mstatus fields
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+
+// mie / mip fields
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+// Assembler names of the implemented CSRs.
+pub const NAMES: [(u16, &str); 23] = [
+    (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"),
+    (MIMPID, "mimpid"),
+    (MHARTID, "mhartid"),
+    (MSTATUS, "mstatus"),
+    (MISA, "misa"),
+    (MIE, "mie"),
+    (MTVEC, "mtvec"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+    (MCYCLE, "mcycle"),
+    (MINSTRET, "minstret"),
+    (MCYCLEH, "mcycleh"),
+    (MINSTRETH, "minstreth"),
+    (CYCLE, "cycle"),
+    (TIME, "time"),
+    (INSTRET, "instret"),
+    (CYCLEH, "cycleh"),
+    (TIMEH, "timeh"),
+    (INSTRETH, "instreth"),
+];
+
+// RV32 (MXL = 1) with the I and M extensions.
+const MISA_VALUE: u32 =
+    (1 << 30) | (1 << ('I' as u32 - 'A' as u32)) | (1 << ('M' as u32 - 'A' as u32));
+
+pub fn name(csr: u16) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|(addr, _)| *addr == csr)
+        .map(|(_, name)| *name)
+}
+
+pub fn by_name(name: &str) -> Option<u16> {
+    NAMES
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(addr, _)| *addr)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    // Decode a two-bit privilege field such as mstatus.MPP; the reserved value 2 reads as M.
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrError {
+    Unimplemented(u16),
+    ReadOnly(u16),
+    Privileged(u16),
+}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrError::Unimplemented(csr) => write!(f, "Unimplemented CSR: {:#05x}", csr),
+            CsrError::ReadOnly(csr) => write!(f, "Write to read-only CSR: {:#05x}", csr),
+            CsrError::Privileged(csr) => write!(f, "Insufficient privilege for CSR: {:#05x}", csr),
+        }
+    }
+}
+
+impl std::error::Error for CsrError {}
+
+pub struct CsrFile {
+    pub mstatus: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub mcycle: u64,
+    pub minstret: u64,
+    pub time: u64,
+    pub mhartid: u32,
+    pub written: Option<u16>, // The CSR the current instruction wrote, for the commit log
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        CsrFile {
+            // Only machine mode exists, so MPP is hardwired to M.
+            mstatus: MSTATUS_MPP,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mcycle: 0,
+            minstret: 0,
+            time: 0,
+            mhartid: 0,
+            written: None,
+        }
+    }
+
+    // Bits [9:8] of a CSR address hold the lowest privilege level allowed to access it.
+    fn check_privilege(csr: u16, privilege: Privilege) -> Result<(), CsrError> {
+        if ((csr >> 8) & 0b11) > privilege as u16 {
+            return Err(CsrError::Privileged(csr));
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self, csr: u16, privilege: Privilege) -> Result<u32, CsrError> {
+        Self::check_privilege(csr, privilege)?;
+
+        let value = match csr {
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.mhartid,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            MCYCLE | CYCLE => self.mcycle as u32,
+            MCYCLEH | CYCLEH => (self.mcycle >> 32) as u32,
+            MINSTRET | INSTRET => self.minstret as u32,
+            MINSTRETH | INSTRETH => (self.minstret >> 32) as u32,
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            _ => return Err(CsrError::Unimplemented(csr)),
+        };
+
+        Ok(value)
+    }
+
+    pub fn write(&mut self, csr: u16, value: u32, privilege: Privilege) -> Result<(), CsrError> {
+        Self::check_privilege(csr, privilege)?;
+
+        // Bits [11:10] == 0b11 mark the read-only CSR space.
+        if (csr >> 10) & 0b11 == 0b11 {
+            return match self.read(csr, privilege) {
+                Ok(_) => Err(CsrError::ReadOnly(csr)),
+                Err(e) => Err(e),
+            };
+        }
+
+        match csr {
+            MSTATUS => {
+                let writable = MSTATUS_MIE | MSTATUS_MPIE;
+                self.mstatus = (self.mstatus & !writable) | (value & writable);
+            }
+            // misa is WARL; the extension set is fixed so writes are ignored.
+            MISA => {}
+            MIE => self.mie = value & (MIP_MSIP | MIP_MTIP | MIP_MEIP),
+            // Pending bits are driven by the interrupt sources, not by software.
+            MIP => {}
+            // Only direct (0) and vectored (1) modes are legal.
+            MTVEC => self.mtvec = value & !0b10,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MCYCLE => self.mcycle = (self.mcycle & !0xFFFF_FFFF) | value as u64,
+            MCYCLEH => self.mcycle = (self.mcycle & 0xFFFF_FFFF) | ((value as u64) << 32),
+            MINSTRET => self.minstret = (self.minstret & !0xFFFF_FFFF) | value as u64,
+            MINSTRETH => self.minstret = (self.minstret & 0xFFFF_FFFF) | ((value as u64) << 32),
+            _ => return Err(CsrError::Unimplemented(csr)),
+        }
+
+        self.written = Some(csr);
+        Ok(())
+    }
+
+    // Advance the counters for one retired instruction; every instruction takes one cycle.
+    pub fn retire(&mut self) {
+        self.mcycle = self.mcycle.wrapping_add(1);
+        self.minstret = self.minstret.wrapping_add(1);
+        self.time = self.time.wrapping_add(1);
+    }
+}
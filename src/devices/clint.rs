@@ -0,0 +1,126 @@
+use std::time::Instant;
+
+use crate::bus::{BusError, Device, Lines};
+use crate::csr::{MIP_MSIP, MIP_MTIP};
+
+// Where SiFive parts and QEMU's virt machine put the CLINT.
+pub const BASE: u32 = 0x0200_0000;
+pub const SIZE: u32 = 0x1_0000;
+
+// mtime frequency when it follows the host clock, as on QEMU's virt machine.
+pub const WALL_CLOCK_HZ: u64 = 10_000_000;
+
+const MSIP: u32 = 0x0;
+const MTIMECMP: u32 = 0x4000;
+const MTIMECMPH: u32 = 0x4004;
+const MTIME: u32 = 0xBFF8;
+const MTIMEH: u32 = 0xBFFC;
+
+// What advances mtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timebase {
+    Instructions, // One tick per retired instruction, so runs are reproducible
+    WallClock,    // WALL_CLOCK_HZ ticks per second of host time
+}
+
+// A core-local interruptor for one hart: the machine timer and the software interrupt.
+pub struct Clint {
+    lines: Lines,
+    mtime: u64,             // mtime when it was last set
+    since: Option<Instant>, // Host time `mtime` was set at, in wall-clock mode
+    retired: u64,           // Instructions retired when `mtime` was set
+    mtimecmp: u64,
+    msip: bool,
+}
+
+impl Clint {
+    // A CLINT driving MSIP and MTIP on `lines`. mtimecmp resets to its maximum so the
+    // timer stays quiet until software sets it.
+    pub fn new(lines: Lines, timebase: Timebase) -> Self {
+        let retired = lines.retired();
+        let mut clint = Clint {
+            lines,
+            mtime: 0,
+            since: (timebase == Timebase::WallClock).then(Instant::now),
+            retired,
+            mtimecmp: u64::MAX,
+            msip: false,
+        };
+        clint.update();
+        clint
+    }
+
+    pub fn mtime(&self) -> u64 {
+        let ticks = match self.since {
+            Some(since) => {
+                (since.elapsed().as_nanos() * WALL_CLOCK_HZ as u128 / 1_000_000_000) as u64
+            }
+            None => self.lines.retired().wrapping_sub(self.retired),
+        };
+        self.mtime.wrapping_add(ticks)
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.retired = self.lines.retired();
+        if self.since.is_some() {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    // Drive the interrupt lines and the `time` CSR from the current state.
+    fn update(&mut self) {
+        let mtime = self.mtime();
+        self.lines.set_mtime(mtime);
+        self.lines.set(MIP_MTIP, mtime >= self.mtimecmp);
+        self.lines.set(MIP_MSIP, self.msip);
+    }
+}
+
+// Replace the low or high half of a 64-bit register.
+fn set_half(value: u64, half: u32, high: bool) -> u64 {
+    if high {
+        (value & 0xFFFF_FFFF) | (half as u64) << 32
+    } else {
+        (value & !0xFFFF_FFFF) | half as u64
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            MSIP => Ok(self.msip as u32),
+            MTIMECMP => Ok(self.mtimecmp as u32),
+            MTIMECMPH => Ok((self.mtimecmp >> 32) as u32),
+            MTIME => Ok(self.mtime() as u32),
+            MTIMEH => Ok((self.mtime() >> 32) as u32),
+            _ => Err(BusError::BadAccess),
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError> {
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            MSIP => self.msip = value & 1 != 0,
+            MTIMECMP => self.mtimecmp = set_half(self.mtimecmp, value, false),
+            MTIMECMPH => self.mtimecmp = set_half(self.mtimecmp, value, true),
+            MTIME => self.set_mtime(set_half(self.mtime(), value, false)),
+            MTIMEH => self.set_mtime(set_half(self.mtime(), value, true)),
+            _ => return Err(BusError::BadAccess),
+        }
+
+        self.update();
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.update();
+    }
+}
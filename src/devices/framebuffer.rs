@@ -0,0 +1,327 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::bus::{BusError, Device};
+
+// Registers at BASE, pixels at BASE + VRAM; 16 MiB covers 1920x1080 at four bytes a pixel.
+pub const BASE: u32 = 0x3000_0000;
+pub const SIZE: u32 = 0x0100_0000;
+pub const VRAM: u32 = 0x10_0000;
+
+// Steps between vertical blanks: 60 Hz for a guest running at 6 MIPS.
+pub const TICKS_PER_FRAME: u64 = 100_000;
+
+const WIDTH: u32 = 0x00;
+const HEIGHT: u32 = 0x04;
+const FORMAT: u32 = 0x08;
+const STRIDE: u32 = 0x0C;
+const CONTROL: u32 = 0x10;
+const STATUS: u32 = 0x14;
+const FRAME: u32 = 0x18;
+const PALETTE: u32 = 0x400;
+const PALETTE_END: u32 = 0x800;
+
+const CONTROL_PRESENT: u32 = 1 << 0;
+const STATUS_VBLANK: u32 = 1 << 0;
+
+// How the guest lays out pixels in VRAM, as written to FORMAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb565 = 0,   // 16 bits: red in 15:11, green in 10:5, blue in 4:0
+    Rgba8888 = 1, // Bytes R, G, B, A; alpha is ignored
+    Indexed8 = 2, // One byte indexing PALETTE's 0x00RRGGBB entries
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Indexed8 => 1,
+        }
+    }
+}
+
+// The file format of frame dumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Png,
+    Ppm,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// An 8-bit RGB PNG of `rgb`, three bytes a pixel. The zlib stream uses stored blocks,
+// which every decoder reads and which keep the bytes stable for golden tests.
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks(width as usize * 3) {
+        raw.push(0); // Filter type None
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib);
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// A binary PPM (P6) of `rgb`.
+pub fn encode_ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.extend_from_slice(rgb);
+    ppm
+}
+
+// A linear framebuffer. The guest draws into VRAM and writes CONTROL.PRESENT to show the
+// frame, which is then dumped to a file, drawn on a terminal, or both. STATUS.VBLANK is
+// set every TICKS_PER_FRAME steps and cleared when read.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    vram: Vec<u8>,
+    palette: [u32; 256],
+    frame: u32,
+    ticks: u64,
+    vblank: bool,
+    dump_failed: bool,                       // A dump has failed and been reported
+    pub dump: Option<(PathBuf, DumpFormat)>, // Directory presented frames are written to
+    pub view: Option<Box<dyn Write>>,        // Terminal presented frames are drawn on
+}
+
+impl Framebuffer {
+    // A `width` by `height` RGB565 framebuffer. The caller checks the size fits VRAM.
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            format: PixelFormat::Rgb565,
+            vram: vec![0; (width * height * 4) as usize],
+            palette: [0; 256],
+            frame: 0,
+            ticks: 0,
+            vblank: false,
+            dump_failed: false,
+            dump: None,
+            view: None,
+        }
+    }
+
+    // Whether a framebuffer of this size fits the VRAM window at four bytes a pixel.
+    pub fn fits(width: u32, height: u32) -> bool {
+        width > 0 && height > 0 && (width as u64 * height as u64 * 4) <= (SIZE - VRAM) as u64
+    }
+
+    fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    // The visible image as RGB bytes, three a pixel, converted from the current format.
+    pub fn rgb(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel() as usize;
+        let pixels = (self.width * self.height) as usize;
+        let mut rgb = Vec::with_capacity(pixels * 3);
+
+        for pixel in self.vram[..pixels * bpp].chunks(bpp) {
+            let color = match self.format {
+                PixelFormat::Rgb565 => {
+                    let value = u16::from_le_bytes([pixel[0], pixel[1]]) as u32;
+                    // Replicate the high bits into the low ones so white stays white.
+                    let r = (value >> 11) & 0x1F;
+                    let g = (value >> 5) & 0x3F;
+                    let b = value & 0x1F;
+                    [(r << 3 | r >> 2), (g << 2 | g >> 4), (b << 3 | b >> 2)].map(|c| c as u8)
+                }
+                PixelFormat::Rgba8888 => [pixel[0], pixel[1], pixel[2]],
+                PixelFormat::Indexed8 => {
+                    let entry = self.palette[pixel[0] as usize];
+                    [(entry >> 16) as u8, (entry >> 8) as u8, entry as u8]
+                }
+            };
+            rgb.extend_from_slice(&color);
+        }
+
+        rgb
+    }
+
+    // Draw `rgb` with half-block characters, two pixel rows a line, sampling every
+    // `step` pixels so the image fits 160 columns.
+    fn draw(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let Some(view) = &mut self.view else {
+            return Ok(());
+        };
+
+        let step = self.width.div_ceil(160).max(1) as usize;
+        let (width, height) = (self.width as usize, self.height as usize);
+        let pixel = |x: usize, y: usize| &rgb[(y * width + x) * 3..][..3];
+
+        let mut text = String::from("\x1b[H");
+        for y in (0..height).step_by(step * 2) {
+            for x in (0..width).step_by(step) {
+                let top = pixel(x, y);
+                let bottom = pixel(x, (y + step).min(height - 1));
+                text += &format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+                );
+            }
+            text += "\x1b[0m\r\n";
+        }
+
+        view.write_all(text.as_bytes())?;
+        view.flush()
+    }
+
+    fn present(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+        if self.dump.is_none() && self.view.is_none() {
+            return;
+        }
+
+        let rgb = self.rgb();
+        if let Some((dir, format)) = &self.dump {
+            let (data, extension) = match format {
+                DumpFormat::Png => (encode_png(self.width, self.height, &rgb), "png"),
+                DumpFormat::Ppm => (encode_ppm(self.width, self.height, &rgb), "ppm"),
+            };
+            // Tests that compare dumps would otherwise just find frames missing. Say so
+            // once rather than for every frame of a full disk.
+            let path = dir.join(format!("frame-{:05}.{}", self.frame, extension));
+            if let Err(e) = fs::write(&path, data) {
+                if !self.dump_failed {
+                    eprintln!("Unable to dump frame to {}: {}", path.display(), e);
+                    self.dump_failed = true;
+                }
+            }
+        }
+
+        // A terminal that has gone away stays gone; stop drawing on it.
+        if self.draw(&rgb).is_err() {
+            self.view = None;
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        if offset >= VRAM {
+            let offset = (offset - VRAM) as usize;
+            let data = self
+                .vram
+                .get(offset..offset + size)
+                .ok_or(BusError::BadAccess)?;
+            let mut bytes = [0; 4];
+            bytes[..size].copy_from_slice(data);
+            return Ok(u32::from_le_bytes(bytes));
+        }
+
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            WIDTH => Ok(self.width),
+            HEIGHT => Ok(self.height),
+            FORMAT => Ok(self.format as u32),
+            STRIDE => Ok(self.stride()),
+            CONTROL => Ok(0),
+            STATUS => Ok(std::mem::take(&mut self.vblank) as u32 * STATUS_VBLANK),
+            FRAME => Ok(self.frame),
+            PALETTE..PALETTE_END => Ok(self.palette[((offset - PALETTE) / 4) as usize]),
+            _ => Err(BusError::BadAccess),
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError> {
+        if offset >= VRAM {
+            let offset = (offset - VRAM) as usize;
+            self.vram
+                .get_mut(offset..offset + size)
+                .ok_or(BusError::BadAccess)?
+                .copy_from_slice(&value.to_le_bytes()[..size]);
+            return Ok(());
+        }
+
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            WIDTH | HEIGHT | STRIDE | STATUS | FRAME => {}
+            FORMAT => {
+                self.format = match value {
+                    0 => PixelFormat::Rgb565,
+                    1 => PixelFormat::Rgba8888,
+                    2 => PixelFormat::Indexed8,
+                    _ => return Err(BusError::BadAccess),
+                }
+            }
+            CONTROL => {
+                if value & CONTROL_PRESENT != 0 {
+                    self.present();
+                }
+            }
+            PALETTE..PALETTE_END => {
+                self.palette[((offset - PALETTE) / 4) as usize] = value & 0xFF_FFFF
+            }
+            _ => return Err(BusError::BadAccess),
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(TICKS_PER_FRAME) {
+            self.vblank = true;
+        }
+    }
+}
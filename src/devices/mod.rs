@@ -0,0 +1,7 @@
+pub mod clint;
+pub mod framebuffer;
+pub mod plic;
+pub mod ram;
+pub mod rom;
+pub mod sound;
+pub mod uart;
@@ -0,0 +1,210 @@
+use crate::bus::{BusError, Device, Irq, Lines};
+use crate::csr::MIP_MEIP;
+
+// The SiFive PLIC window, as on the FU540 and QEMU's virt machine.
+pub const BASE: u32 = 0x0C00_0000;
+pub const SIZE: u32 = 0x0400_0000;
+
+// Source 0 is reserved and never interrupts, leaving 1023.
+pub const MAX_SOURCES: u32 = 1023;
+
+const PRIORITY: u32 = 0x0;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+// Priorities and thresholds are three bits wide, as on SiFive parts.
+const PRIORITY_MASK: u32 = 0b111;
+
+// A hart context: hart 0 in M mode, then hart 0 in S mode, as SiFive numbers them.
+struct Context {
+    enable: Vec<u32>, // One bit per source
+    threshold: u32,
+    output: Option<u32>, // mip bit this context drives; S mode is not wired up yet
+}
+
+// A platform-level interrupt controller. Sources are level-triggered: a raised line
+// becomes pending, stays pending until claimed, and can only become pending again once
+// the claim is completed.
+pub struct Plic {
+    lines: Lines,
+    sources: Vec<Irq>, // Index 0 is the reserved source
+    priority: Vec<u32>,
+    pending: Vec<u32>,
+    claimed: Vec<u32>, // Claimed and not yet completed
+    contexts: Vec<Context>,
+}
+
+fn bit(bits: &[u32], source: u32) -> bool {
+    bits[(source / 32) as usize] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32], source: u32, value: bool) {
+    let word = &mut bits[(source / 32) as usize];
+    if value {
+        *word |= 1 << (source % 32);
+    } else {
+        *word &= !(1 << (source % 32));
+    }
+}
+
+impl Plic {
+    // A PLIC with `sources` interrupt sources, numbered from 1, driving MEIP on `lines`.
+    pub fn new(lines: Lines, sources: u32) -> Self {
+        let count = sources.min(MAX_SOURCES) as usize + 1;
+        let words = count.div_ceil(32);
+        let context = |output| Context {
+            enable: vec![0; words],
+            threshold: 0,
+            output,
+        };
+
+        Plic {
+            lines,
+            sources: (0..count).map(|_| Irq::default()).collect(),
+            priority: vec![0; count],
+            pending: vec![0; words],
+            claimed: vec![0; words],
+            contexts: vec![context(Some(MIP_MEIP)), context(None)],
+        }
+    }
+
+    // The line a device drives to raise `source`, or None if there is no such source.
+    pub fn source(&self, source: u32) -> Option<Irq> {
+        match source {
+            0 => None,
+            source => self.sources.get(source as usize).cloned(),
+        }
+    }
+
+    // The pending source `context` would claim: the highest priority one it enables
+    // above its threshold, lowest number first among equals.
+    fn best(&self, context: usize) -> Option<u32> {
+        let context = &self.contexts[context];
+        (1..self.sources.len() as u32)
+            .filter(|&source| bit(&self.pending, source) && bit(&context.enable, source))
+            .filter(|&source| self.priority[source as usize] > context.threshold)
+            .min_by_key(|&source| (u32::MAX - self.priority[source as usize], source))
+    }
+
+    // Latch raised lines into pending and drive each context's interrupt.
+    fn update(&mut self) {
+        for source in 1..self.sources.len() as u32 {
+            if self.sources[source as usize].level() && !bit(&self.claimed, source) {
+                set_bit(&mut self.pending, source, true);
+            }
+        }
+
+        for context in 0..self.contexts.len() {
+            if let Some(output) = self.contexts[context].output {
+                self.lines.set(output, self.best(context).is_some());
+            }
+        }
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+        set_bit(&mut self.pending, source, false);
+        set_bit(&mut self.claimed, source, true);
+        source
+    }
+
+    // Decode a context register offset into (context, register within it).
+    fn context(&self, offset: u32) -> Option<(usize, u32)> {
+        let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+        (context < self.contexts.len()).then_some((context, offset % CONTEXT_STRIDE))
+    }
+
+    // Decode an enable word offset into (context, word).
+    fn enable(&self, offset: u32) -> Option<(usize, usize)> {
+        let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+        let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+        (context < self.contexts.len() && word < self.pending.len()).then_some((context, word))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        let value = match offset {
+            PRIORITY..PENDING => {
+                let source = (offset / 4) as usize;
+                self.priority.get(source).copied().unwrap_or(0)
+            }
+            PENDING..ENABLE => {
+                let word = ((offset - PENDING) / 4) as usize;
+                self.pending.get(word).copied().unwrap_or(0)
+            }
+            ENABLE..CONTEXT => match self.enable(offset) {
+                Some((context, word)) => self.contexts[context].enable[word],
+                None => 0,
+            },
+            _ => match self.context(offset) {
+                Some((context, 0)) => self.contexts[context].threshold,
+                Some((context, 4)) => {
+                    let source = self.claim(context);
+                    self.update();
+                    source
+                }
+                _ => 0,
+            },
+        };
+
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError> {
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            PRIORITY..PENDING => {
+                let source = (offset / 4) as usize;
+                if source != 0 && source < self.priority.len() {
+                    self.priority[source] = value & PRIORITY_MASK;
+                }
+            }
+            // Pending bits are set by the sources and cleared by claims.
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                if let Some((context, word)) = self.enable(offset) {
+                    // Source 0 and sources past the last one cannot be enabled.
+                    let first = if word == 0 { !1 } else { !0 };
+                    let valid = match self.sources.len() - word * 32 {
+                        32.. => u32::MAX,
+                        count => (1 << count) - 1,
+                    };
+                    self.contexts[context].enable[word] = value & first & valid;
+                }
+            }
+            _ => match self.context(offset) {
+                Some((context, 0)) => self.contexts[context].threshold = value & PRIORITY_MASK,
+                // Completing a source the context does not enable is ignored.
+                Some((context, 4)) => {
+                    let source = value;
+                    if (source as usize) < self.sources.len()
+                        && bit(&self.contexts[context].enable, source)
+                    {
+                        set_bit(&mut self.claimed, source, false);
+                    }
+                }
+                _ => {}
+            },
+        }
+
+        self.update();
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.update();
+    }
+}
@@ -0,0 +1,45 @@
+use crate::bus::{BusError, Device};
+
+// Zero-initialised read/write memory.
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Ram {
+            data: vec![0; size],
+        }
+    }
+}
+
+impl Device for Ram {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        let offset = offset as usize;
+        let data = self
+            .data
+            .get(offset..offset + size)
+            .ok_or(BusError::BadAccess)?;
+        let mut bytes = [0; 4];
+        bytes[..size].copy_from_slice(data);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError> {
+        let offset = offset as usize;
+        self.data
+            .get_mut(offset..offset + size)
+            .ok_or(BusError::BadAccess)?
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        let offset = offset as usize;
+        self.data
+            .get_mut(offset..offset + data.len())
+            .ok_or(BusError::BadAccess)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
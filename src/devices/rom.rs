@@ -0,0 +1,38 @@
+use crate::bus::{BusError, Device};
+
+// Read-only memory. The guest can only read it; the host fills it with `Device::load`.
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(data: Vec<u8>) -> Self {
+        Rom { data }
+    }
+}
+
+impl Device for Rom {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        let offset = offset as usize;
+        let data = self
+            .data
+            .get(offset..offset + size)
+            .ok_or(BusError::BadAccess)?;
+        let mut bytes = [0; 4];
+        bytes[..size].copy_from_slice(data);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, _offset: u32, _size: usize, _value: u32) -> Result<(), BusError> {
+        Err(BusError::ReadOnly)
+    }
+
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        let offset = offset as usize;
+        self.data
+            .get_mut(offset..offset + data.len())
+            .ok_or(BusError::BadAccess)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
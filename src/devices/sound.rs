@@ -0,0 +1,344 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::bus::{BusError, Device};
+
+// Registers at BASE, the PCM ring at BASE + RING.
+pub const BASE: u32 = 0x3100_0000;
+pub const SIZE: u32 = 0x4000;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Steps between output samples: 44.1 kHz for a guest running at 6 MIPS, like the
+// framebuffer's vertical blank.
+pub const TICKS_PER_SAMPLE: u64 = 136;
+
+// Tone channels: square, triangle and noise, each with FREQ, VOLUME, ENVELOPE and CONTROL.
+pub const CHANNELS: usize = 3;
+const CHANNEL_STRIDE: u32 = 0x10;
+const FREQ: u32 = 0x0; // Hz
+const VOLUME: u32 = 0x4; // 0 to 255
+const ENVELOPE: u32 = 0x8; // Milliseconds to fade from VOLUME to silence; 0 holds it
+const CONTROL: u32 = 0xC;
+
+const CONTROL_ENABLE: u32 = 1 << 0; // Writing it restarts the envelope
+const CONTROL_DUTY_SHIFT: u32 = 8; // Square duty: 12.5%, 25%, 50% or 75%
+
+const PCM_READ: u32 = 0x40; // Index of the next sample to play
+const PCM_WRITE: u32 = 0x44; // Index one past the last sample queued
+const PCM_VOLUME: u32 = 0x48;
+const SAMPLES: u32 = 0x4C; // Samples generated since reset
+
+// A ring of signed 16-bit samples; it is empty when PCM_READ equals PCM_WRITE.
+const RING: u32 = 0x1000;
+pub const RING_SAMPLES: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wave {
+    Square,
+    Triangle,
+    Noise,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    wave: Wave,
+    freq: u32,
+    volume: u32,
+    envelope: u32,
+    control: u32,
+    phase: u32,  // Position in the current period, as a fraction of 2^32
+    age: u32,    // Samples since the channel was enabled
+    lfsr: u16,   // Noise shift register
+    noise: bool, // Current noise output
+}
+
+impl Channel {
+    fn new(wave: Wave) -> Self {
+        Channel {
+            wave,
+            freq: 0,
+            volume: 0,
+            envelope: 0,
+            control: 0,
+            phase: 0,
+            age: 0,
+            lfsr: 1,
+            noise: false,
+        }
+    }
+
+    // The next sample, from -1 to 1 at full volume.
+    fn sample(&mut self) -> f32 {
+        if self.control & CONTROL_ENABLE == 0 || self.freq == 0 {
+            return 0.0;
+        }
+
+        let value = match self.wave {
+            Wave::Square => {
+                let duty = [0x2000_0000, 0x4000_0000, 0x8000_0000, 0xC000_0000u32]
+                    [(self.control >> CONTROL_DUTY_SHIFT) as usize & 0b11];
+                if self.phase < duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Wave::Triangle => {
+                let ramp = self.phase as f32 / 2f32.powi(31);
+                if ramp < 1.0 {
+                    2.0 * ramp - 1.0
+                } else {
+                    3.0 - 2.0 * ramp
+                }
+            }
+            Wave::Noise => {
+                if self.noise {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        };
+
+        let step = ((self.freq as u64) << 32) / SAMPLE_RATE as u64;
+        let (phase, wrapped) = self.phase.overflowing_add(step as u32);
+        self.phase = phase;
+        // The noise register shifts once a period, with taps at bits 0 and 1 as on the NES.
+        if wrapped {
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            self.noise = self.lfsr & 1 != 0;
+        }
+
+        let fade = match self.envelope {
+            0 => 1.0,
+            ms => {
+                let length = ms as f32 * SAMPLE_RATE as f32 / 1000.0;
+                (1.0 - self.age as f32 / length).max(0.0)
+            }
+        };
+        self.age = self.age.saturating_add(1);
+
+        value * fade * self.volume as f32 / 255.0
+    }
+}
+
+// Where mixed samples go: a WAV file or anything else that can be rewound to fill in
+// the header once the length is known.
+pub trait Sink: Write + Seek {}
+
+impl<T: Write + Seek> Sink for T {}
+
+// Writes mono 16-bit PCM as a WAV file, patching the sizes in the header on `finish`.
+pub struct WavWriter {
+    out: Box<dyn Sink>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn new(mut out: Box<dyn Sink>) -> io::Result<Self> {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // Bytes a second
+        header.extend_from_slice(&2u16.to_le_bytes()); // Bytes a frame
+        header.extend_from_slice(&16u16.to_le_bytes()); // Bits a sample
+        header.extend_from_slice(b"data\0\0\0\0");
+        out.write_all(&header)?;
+
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write(&mut self, sample: i16) -> io::Result<()> {
+        self.samples += 1;
+        self.out.write_all(&sample.to_le_bytes())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        let data = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+// A tone generator with square, triangle and noise channels and a PCM ring buffer,
+// mixed into one 44.1 kHz stream. With a WAV writer attached the stream is recorded;
+// without one it plays to nobody, but the ring still drains at the same rate.
+pub struct Sound {
+    channels: [Channel; CHANNELS],
+    ring: Vec<i16>,
+    read: u32,
+    write: u32,
+    pcm_volume: u32,
+    samples: u32,
+    ticks: u64,
+    wav: Option<WavWriter>,
+    wav_error: Option<String>, // Why the recording stopped early
+}
+
+impl Sound {
+    pub fn new(wav: Option<WavWriter>) -> Self {
+        Sound {
+            channels: [
+                Channel::new(Wave::Square),
+                Channel::new(Wave::Triangle),
+                Channel::new(Wave::Noise),
+            ],
+            ring: vec![0; RING_SAMPLES as usize],
+            read: 0,
+            write: 0,
+            pcm_volume: 255,
+            samples: 0,
+            ticks: 0,
+            wav,
+            wav_error: None,
+        }
+    }
+
+    // Patch the WAV header and close the recording, keeping the first error.
+    fn stop_recording(&mut self) {
+        if let Some(mut wav) = self.wav.take() {
+            if let Err(e) = wav.finish() {
+                self.wav_error.get_or_insert(e.to_string());
+            }
+        }
+    }
+
+    // Mix one sample from every channel and the next queued PCM sample.
+    fn mix(&mut self) -> i16 {
+        let mut mix: f32 = self.channels.iter_mut().map(Channel::sample).sum();
+
+        if self.read != self.write {
+            let pcm = self.ring[self.read as usize] as f32 / 32768.0;
+            mix += pcm * self.pcm_volume as f32 / 255.0;
+            self.read = (self.read + 1) % RING_SAMPLES;
+        }
+
+        // Four full-scale sources fit without clipping.
+        (mix / 4.0 * 32767.0).clamp(-32768.0, 32767.0) as i16
+    }
+}
+
+impl Device for Sound {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, BusError> {
+        if offset >= RING {
+            if size != 2 || !offset.is_multiple_of(2) {
+                return Err(BusError::BadAccess);
+            }
+            let sample = self.ring.get(((offset - RING) / 2) as usize);
+            return sample
+                .map(|sample| *sample as u16 as u32)
+                .ok_or(BusError::BadAccess);
+        }
+
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            0..PCM_READ => {
+                let channel = self
+                    .channels
+                    .get((offset / CHANNEL_STRIDE) as usize)
+                    .ok_or(BusError::BadAccess)?;
+                match offset % CHANNEL_STRIDE {
+                    FREQ => Ok(channel.freq),
+                    VOLUME => Ok(channel.volume),
+                    ENVELOPE => Ok(channel.envelope),
+                    CONTROL => Ok(channel.control),
+                    _ => Err(BusError::BadAccess),
+                }
+            }
+            PCM_READ => Ok(self.read),
+            PCM_WRITE => Ok(self.write),
+            PCM_VOLUME => Ok(self.pcm_volume),
+            SAMPLES => Ok(self.samples),
+            _ => Err(BusError::BadAccess),
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), BusError> {
+        if offset >= RING {
+            if size != 2 || !offset.is_multiple_of(2) {
+                return Err(BusError::BadAccess);
+            }
+            let sample = self
+                .ring
+                .get_mut(((offset - RING) / 2) as usize)
+                .ok_or(BusError::BadAccess)?;
+            *sample = value as u16 as i16;
+            return Ok(());
+        }
+
+        if size != 4 {
+            return Err(BusError::BadAccess);
+        }
+
+        match offset {
+            0..PCM_READ => {
+                let channel = self
+                    .channels
+                    .get_mut((offset / CHANNEL_STRIDE) as usize)
+                    .ok_or(BusError::BadAccess)?;
+                match offset % CHANNEL_STRIDE {
+                    FREQ => channel.freq = value.min(SAMPLE_RATE / 2),
+                    VOLUME => channel.volume = value.min(255),
+                    ENVELOPE => channel.envelope = value & 0xFFFF,
+                    CONTROL => {
+                        if value & CONTROL_ENABLE != 0 {
+                            channel.age = 0;
+                        }
+                        channel.control = value & 0x301;
+                    }
+                    _ => return Err(BusError::BadAccess),
+                }
+            }
+            PCM_READ | SAMPLES => {}
+            PCM_WRITE => self.write = value % RING_SAMPLES,
+            PCM_VOLUME => self.pcm_volume = value.min(255),
+            _ => return Err(BusError::BadAccess),
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if !self.ticks.is_multiple_of(TICKS_PER_SAMPLE) {
+            return;
+        }
+
+        let sample = self.mix();
+        self.samples = self.samples.wrapping_add(1);
+        if let Some(wav) = &mut self.wav {
+            // Nothing more can be recorded, so make what was recorded playable now and
+            // leave the error for `finish`.
+            if let Err(e) = wav.write(sample) {
+                self.wav_error = Some(e.to_string());
+                self.stop_recording();
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.stop_recording();
+        match self.wav_error.take() {
+            Some(e) => Err(format!("Unable to record the sound output: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+// A recording nobody finished, as when a test drops the device, still gets its header.
+impl Drop for Sound {
+    fn drop(&mut self) {
+        self.stop_recording();
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::bus::{BusError, Device, Irq};
+
+// Where QEMU's virt machine puts its NS16550A, and the size of the window it decodes.
+pub const BASE: u32 = 0x1000_0000;
+pub const SIZE: u32 = 0x100;
+// Its PLIC source on the virt machine.
+pub const IRQ: u32 = 10;
+
+// Register offsets. With LCR.DLAB set, 0 and 1 are the divisor latch instead.
+const RBR_THR: u32 = 0;
+const IER: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RDI: u8 = 1 << 0; // Received data available
+const IER_THRI: u8 = 1 << 1; // Transmit holding register empty
+const IER_RLSI: u8 = 1 << 2; // Receiver line status
+const IER_MSI: u8 = 1 << 3; // Modem status
+
+// IIR interrupt identification, highest priority first.
+const IIR_NONE: u8 = 0x01;
+const IIR_RLS: u8 = 0x06;
+const IIR_RDI: u8 = 0x04;
+const IIR_THRI: u8 = 0x02;
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0; // Data ready
+const LSR_OE: u8 = 1 << 1; // Overrun error
+const LSR_THRE: u8 = 1 << 5; // Transmit holding register empty
+const LSR_TEMT: u8 = 1 << 6; // Transmitter empty
+
+const FIFO_SIZE: usize = 16;
+
+// Feed the bytes of `input` to a UART from a background thread, so the guest never blocks
+// on the host. The channel ends when `input` does.
+pub fn spawn_reader(mut input: impl Read + Send + 'static) -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 256];
+        while let Ok(n @ 1..) = input.read(&mut buf) {
+            if buf[..n].iter().any(|byte| tx.send(*byte).is_err()) {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// An NS16550A. Transmitted bytes go straight to `output`, so the transmitter is always
+// empty; received bytes come from `input` as the guest polls for them.
+pub struct Uart {
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8, // Only the sticky error bits; the status bits are computed
+    scr: u8,
+    divisor: u16,
+    thre_pending: bool, // THRE interrupt raised and not yet acknowledged
+    irq: Option<Irq>,
+}
+
+impl Uart {
+    pub fn new(output: Box<dyn Write>, input: Option<Receiver<u8>>) -> Self {
+        Uart {
+            output,
+            input,
+            rx: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: 0,
+            scr: 0,
+            divisor: 0,
+            thre_pending: false,
+            irq: None,
+        }
+    }
+
+    // Drive `irq` with the interrupt output.
+    pub fn connect(&mut self, irq: Irq) {
+        self.irq = Some(irq);
+        self.tick();
+    }
+
+    fn fifo_size(&self) -> usize {
+        if self.fcr & FCR_ENABLE != 0 {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    // Move waiting host input into the receive FIFO. Input is held back rather than
+    // overrunning, since the host has no line speed to keep up with.
+    fn poll(&mut self) {
+        let Some(input) = &self.input else {
+            return;
+        };
+        while self.rx.len() < self.fifo_size() {
+            match input.try_recv() {
+                Ok(byte) => self.rx.push_back(byte),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.fifo_size() {
+            self.rx.push_back(byte);
+        } else {
+            self.lsr |= LSR_OE;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);
+        } else {
+            // Once the console is gone, carry on like a UART with its cable pulled:
+            // transmitting into nothing rather than failing every write.
+            let sent = self
+                .output
+                .write_all(&[byte])
+                .and_then(|_| self.output.flush());
+            if sent.is_err() {
+                self.output = Box::new(io::sink());
+            }
+        }
+        self.thre_pending = true;
+    }
+
+    fn line_status(&mut self) -> u8 {
+        self.poll();
+        let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+        self.lsr | dr | LSR_THRE | LSR_TEMT
+    }
+
+    // The highest-priority pending interrupt, as IIR reports it.
+    fn identify(&mut self) -> u8 {
+        let lsr = self.line_status();
+        if self.ier & IER_RLSI != 0 && lsr & LSR_OE != 0 {
+            IIR_RLS
+        } else if self.ier & IER_RDI != 0 && lsr & LSR_DR != 0 {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thre_pending {
+            IIR_THRI
+        } else {
+            IIR_NONE
+        }
+    }
+
+    // Level of the interrupt output.
+    pub fn interrupt(&mut self) -> bool {
+        self.identify() != IIR_NONE
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOP != 0 {
+            // DTR, RTS, OUT1 and OUT2 loop back to DSR, CTS, RI and DCD.
+            let mcr = self.mcr;
+            ((mcr & 0b01) << 5)
+                | ((mcr & 0b10) << 3)
+                | ((mcr & 0b0100) << 4)
+                | ((mcr & 0b1000) << 4)
+        } else {
+            // DCD, DSR and CTS: a cable with the other end ready.
+            0xB0
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: usize) -> Result<u32, BusError> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => {
+                self.poll();
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let iir = self.identify();
+                // Reading IIR acknowledges a THRE interrupt it reports.
+                if iir == IIR_THRI {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fcr & FCR_ENABLE != 0 {
+                    IIR_FIFO
+                } else {
+                    0
+                };
+                iir | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let lsr = self.line_status();
+                self.lsr = 0;
+                lsr
+            }
+            MSR => self.modem_status(),
+            SCR => self.scr,
+            _ => return Err(BusError::BadAccess),
+        };
+
+        self.tick();
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, offset: u32, _size: usize, value: u32) -> Result<(), BusError> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = value as u8;
+        match offset {
+            RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            IER => {
+                // Enabling the THRE interrupt while the transmitter is empty raises it.
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & (IER_RDI | IER_THRI | IER_RLSI | IER_MSI);
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & !0b110;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return Err(BusError::BadAccess),
+        }
+
+        self.tick();
+        Ok(())
+    }
+
+    // Poll for input even when the guest is not reading, so received data can interrupt.
+    fn tick(&mut self) {
+        if self.irq.is_some() {
+            let level = self.interrupt();
+            if let Some(irq) = &self.irq {
+                irq.set(level);
+            }
+        }
+    }
+}
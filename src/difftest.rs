@@ -0,0 +1,310 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::commit::{self, Commit, CommitLog};
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::disasm;
+use crate::isa::{Instruction, REGISTER_NAMES};
+
+// Matching commits listed before a divergence.
+const CONTEXT: usize = 8;
+
+// Steps in a row that may retire nothing, taking traps, before the run counts as stuck.
+const MAX_TRAPS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: invalid commit: {}", self.line, self.text)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn hex(text: &str) -> Option<u64> {
+    let text = text.trim_matches(|c| c == '(' || c == ')');
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+// Reference values are 64 bits wide when the model ran RV64 but traced an RV32 program.
+fn hex32(text: &str) -> Option<u32> {
+    hex(text).map(|value| value as u32)
+}
+
+// `core   0: 3 0x80000000 (0x00000297) x5  0x80000000 mem 0x80001000 ...`. Spike's `-l`
+// lines have no privilege digit and are skipped.
+fn parse_spike(line: &str) -> Option<Option<Commit>> {
+    let rest = line.strip_prefix("core")?;
+    let (_, rest) = rest.split_once(':')?;
+    let mut words = rest.split_whitespace();
+
+    let privilege = match words.next()? {
+        "0" => Privilege::User,
+        "1" => Privilege::Supervisor,
+        "3" => Privilege::Machine,
+        _ => return Some(None),
+    };
+
+    let mut commit = Commit {
+        privilege,
+        pc: hex32(words.next()?)?,
+        raw: hex32(words.next()?)?,
+        rd: None,
+        csr: None,
+        load: None,
+        store: None,
+    };
+
+    let words: Vec<&str> = words.collect();
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let value = words.get(i + 1).and_then(|v| hex32(v));
+
+        if word == "mem" {
+            let addr = value?;
+            // A store has a value after the address; its width gives the size.
+            match words.get(i + 2).filter(|v| v.starts_with("0x")) {
+                Some(data) => {
+                    let size = (data.len() as u32 - 2) / 2;
+                    commit.store = Some((addr, hex32(data)?, size));
+                    i += 3;
+                }
+                None => {
+                    commit.load = Some(addr);
+                    i += 2;
+                }
+            }
+            continue;
+        }
+
+        if let Some(reg) = word.strip_prefix('x') {
+            commit.rd = Some((reg.parse().ok()?, value?));
+        } else if let Some(csr) = word.strip_prefix('c') {
+            let number = csr.split('_').next()?.parse().ok()?;
+            commit.csr = Some((number, value?));
+        } else {
+            return None;
+        }
+        i += 2;
+    }
+
+    Some(Some(commit))
+}
+
+// Sail's `[12] [M]: 0x80000000 (0x00000297) auipc t0, 0x0` starts an instruction; its
+// effects follow on lines such as `x5 <- 0x80000000` and `mem[0x80001000] <- 0x0`.
+fn parse_sail_header(line: &str) -> Option<Commit> {
+    let rest = line.strip_prefix('[')?;
+    let (_, rest) = rest.split_once("] [")?;
+    let (mode, rest) = rest.split_once("]:")?;
+    let mut words = rest.split_whitespace();
+
+    Some(Commit {
+        privilege: match mode {
+            "U" => Privilege::User,
+            "S" => Privilege::Supervisor,
+            "M" => Privilege::Machine,
+            _ => return None,
+        },
+        pc: hex32(words.next()?)?,
+        raw: hex32(words.next()?)?,
+        rd: None,
+        csr: None,
+        load: None,
+        store: None,
+    })
+}
+
+fn parse_sail_effect(line: &str, commit: &mut Commit) -> Option<()> {
+    if let Some((target, value)) = line.split_once(" <- ") {
+        let value = hex32(value.split_whitespace().next()?)?;
+        if let Some(reg) = target.strip_prefix('x') {
+            let reg = reg.parse().ok()?;
+            if reg != 0 {
+                commit.rd = Some((reg, value));
+            }
+        } else if let Some(name) = target.strip_prefix("CSR ") {
+            if let Some(csr) = csr::by_name(name.trim()) {
+                commit.csr = Some((csr, value));
+            }
+        } else if let Some(addr) = target.strip_prefix("mem[") {
+            let addr = hex32(addr.trim_end_matches(']').rsplit(',').next()?)?;
+            let digits = line.rsplit("0x").next()?.trim().len() as u32;
+            commit.store = Some((addr, value, digits.div_ceil(2).clamp(1, 4)));
+        }
+    } else if let Some((target, _)) = line.split_once(" -> ") {
+        if let Some(addr) = target.strip_prefix("mem[") {
+            commit.load = Some(hex32(addr.trim_end_matches(']').rsplit(',').next()?)?);
+        }
+    }
+
+    Some(())
+}
+
+// The commits in a Spike `--log-commits` or Sail trace. Lines that are neither are skipped.
+pub fn parse_trace(text: &str) -> Result<Vec<Commit>, ParseError> {
+    let mut commits = Vec::new();
+    let mut sail: Option<Commit> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let error = || ParseError {
+            line: number + 1,
+            text: line.to_string(),
+        };
+
+        if line.starts_with("core") {
+            match parse_spike(line).ok_or_else(error)? {
+                Some(commit) => commits.push(commit),
+                None => continue,
+            }
+        } else if line.starts_with('[') {
+            commits.extend(sail.take());
+            sail = Some(parse_sail_header(line).ok_or_else(error)?);
+        } else if let Some(commit) = &mut sail {
+            parse_sail_effect(line, commit).ok_or_else(error)?;
+        }
+    }
+
+    commits.extend(sail);
+    Ok(commits)
+}
+
+// The first point where the emulator and the reference disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub index: usize, // Number of commits that matched before this one
+    pub expected: Commit,
+    pub actual: Result<Commit, String>, // What RV-801 retired, or why it retired nothing
+    pub context: Vec<Commit>,           // The matching commits just before
+    pub regs: [u32; 32],                // RV-801's registers after the divergence
+}
+
+fn describe(commit: &Commit) -> String {
+    match Instruction::try_from(commit.raw) {
+        Ok(inst) => disasm::format(&inst, Some(commit.pc), Default::default()),
+        Err(_) => format!(".word {:#010x}", commit.raw),
+    }
+}
+
+fn reg(reg: Option<(u8, u32)>) -> String {
+    match reg {
+        Some((reg, value)) => format!(
+            "x{} ({}) = 0x{:08x}",
+            reg, REGISTER_NAMES[reg as usize], value
+        ),
+        None => "no register write".to_string(),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expected = &self.expected;
+        writeln!(
+            f,
+            "Divergence after {} matching instructions at pc 0x{:08x}: {}",
+            self.index,
+            expected.pc,
+            describe(expected)
+        )?;
+
+        writeln!(f, "  expected: {}", commit::format(expected))?;
+        match &self.actual {
+            Ok(actual) => {
+                writeln!(f, "  actual:   {}", commit::format(actual))?;
+                if actual.rd != expected.rd {
+                    writeln!(
+                        f,
+                        "  expected {}, actual {}",
+                        reg(expected.rd),
+                        reg(actual.rd)
+                    )?;
+                }
+            }
+            Err(reason) => writeln!(f, "  actual:   {}", reason)?,
+        }
+
+        if !self.context.is_empty() {
+            writeln!(f, "Preceding instructions:")?;
+            for commit in &self.context {
+                writeln!(f, "  {}  # {}", commit::format(commit), describe(commit))?;
+            }
+        }
+
+        writeln!(f, "Registers:")?;
+        for row in 0..8 {
+            let line: Vec<String> = (0..4)
+                .map(|col| {
+                    let reg = row + col * 8;
+                    format!("{:<4} 0x{:08x}", REGISTER_NAMES[reg], self.regs[reg])
+                })
+                .collect();
+            writeln!(f, "  {}", line.join("  "))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Step `cpu` through `reference`, comparing what each instruction retires. Returns the
+// number of commits compared, or the first one that differs. Instructions that trap
+// retire nothing, as in Spike, so steps that take a trap are not compared.
+pub fn compare(cpu: &mut CPU, reference: &[Commit]) -> Result<usize, Box<Divergence>> {
+    cpu.commit_log = Some(CommitLog::capture());
+    let mut context = VecDeque::with_capacity(CONTEXT);
+
+    for (index, expected) in reference.iter().enumerate() {
+        let actual = retire(cpu);
+        let matches = actual.as_ref().is_ok_and(|actual| actual == expected);
+
+        if !matches {
+            return Err(Box::new(Divergence {
+                index,
+                expected: *expected,
+                actual,
+                context: context.into_iter().collect(),
+                regs: cpu.regs,
+            }));
+        }
+
+        if context.len() == CONTEXT {
+            context.pop_front();
+        }
+        context.push_back(*expected);
+    }
+
+    Ok(reference.len())
+}
+
+// Step until an instruction retires.
+fn retire(cpu: &mut CPU) -> Result<Commit, String> {
+    for _ in 0..MAX_TRAPS {
+        if cpu.exit_code.is_some() {
+            return Err("the program has exited".to_string());
+        }
+
+        if let Some(log) = &mut cpu.commit_log {
+            log.last = None;
+        }
+        let pc = cpu.pc as u32;
+        if let Err(e) = cpu.step() {
+            return Err(format!("unhandled exception at 0x{:08x}: {}", pc, e));
+        }
+
+        if let Some(commit) = cpu.commit_log.as_ref().and_then(|log| log.last) {
+            return Ok(commit);
+        }
+    }
+
+    Err(format!(
+        "{} traps in a row without retiring an instruction",
+        MAX_TRAPS
+    ))
+}
@@ -0,0 +1,181 @@
+use crate::bus::{Bus, BusError};
+use crate::csr;
+use crate::isa::{Instruction, InstructionType, REGISTER_NAMES, RV32I};
+
+// How instructions are printed. The default is canonical syntax with ABI register names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Syntax {
+    pub pseudo: bool,    // Print li, mv, j, ret, nop... where an instruction matches one
+    pub abi_names: bool, // Print a0, sp... rather than x10, x2...
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            pseudo: false,
+            abi_names: true,
+        }
+    }
+}
+
+impl Syntax {
+    fn reg(&self, reg: u8) -> String {
+        if self.abi_names {
+            REGISTER_NAMES[reg as usize].to_string()
+        } else {
+            format!("x{}", reg)
+        }
+    }
+}
+
+fn csr_name(csr: u16) -> String {
+    match csr::name(csr) {
+        Some(name) => name.to_string(),
+        None => format!("{:#05x}", csr),
+    }
+}
+
+fn fence_set(set: u8) -> String {
+    let set: String = [(0b1000, 'i'), (0b0100, 'o'), (0b0010, 'r'), (0b0001, 'w')]
+        .iter()
+        .filter(|(bit, _)| set & bit != 0)
+        .map(|(_, c)| *c)
+        .collect();
+
+    if set.is_empty() {
+        "0".to_string()
+    } else {
+        set
+    }
+}
+
+// Branch and jump targets print as an absolute address when the instruction's own
+// address is known, and as the raw offset otherwise.
+fn target(pc: Option<u32>, offset: i32) -> String {
+    match pc {
+        Some(pc) => format!("{:#x}", pc.wrapping_add(offset as u32)),
+        None => offset.to_string(),
+    }
+}
+
+fn pseudo(inst: &Instruction, pc: Option<u32>, syntax: Syntax) -> Option<String> {
+    let reg = |r| syntax.reg(r);
+
+    let text = match (inst.inst, inst.inst_type) {
+        (RV32I::ADDI, InstructionType::I(i)) if i.rd == 0 && i.rs1 == 0 && i.imm == 0 => {
+            "nop".to_string()
+        }
+        (RV32I::ADDI, InstructionType::I(i)) if i.rs1 == 0 => {
+            format!("li {}, {}", reg(i.rd), i.imm)
+        }
+        (RV32I::ADDI, InstructionType::I(i)) if i.imm == 0 => {
+            format!("mv {}, {}", reg(i.rd), reg(i.rs1))
+        }
+        (RV32I::JAL, InstructionType::J(j)) if j.rd == 0 => format!("j {}", target(pc, j.imm)),
+        (RV32I::JAL, InstructionType::J(j)) if j.rd == 1 => {
+            format!("jal {}", target(pc, j.imm))
+        }
+        (RV32I::JALR, InstructionType::I(i)) if i.rd == 0 && i.rs1 == 1 && i.imm == 0 => {
+            "ret".to_string()
+        }
+        (RV32I::JALR, InstructionType::I(i)) if i.rd == 0 && i.imm == 0 => {
+            format!("jr {}", reg(i.rs1))
+        }
+        (RV32I::BEQ, InstructionType::B(b)) if b.rs2 == 0 => {
+            format!("beqz {}, {}", reg(b.rs1), target(pc, b.imm as i32))
+        }
+        (RV32I::BNE, InstructionType::B(b)) if b.rs2 == 0 => {
+            format!("bnez {}, {}", reg(b.rs1), target(pc, b.imm as i32))
+        }
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+// Render one instruction. `pc` is the address it was fetched from, if known.
+pub fn format(inst: &Instruction, pc: Option<u32>, syntax: Syntax) -> String {
+    if syntax.pseudo {
+        if let Some(text) = pseudo(inst, pc, syntax) {
+            return text;
+        }
+    }
+
+    let reg = |r| syntax.reg(r);
+    let name = format!("{:?}", inst.inst).to_lowercase();
+
+    match (inst.inst, inst.inst_type) {
+        (_, InstructionType::R(r)) => {
+            format!("{} {}, {}, {}", name, reg(r.rd), reg(r.rs1), reg(r.rs2))
+        }
+        (RV32I::ECALL | RV32I::EBREAK | RV32I::MRET | RV32I::WFI, _) => name,
+        (RV32I::FENCEI, _) => "fence.i".to_string(),
+        (RV32I::CSRRW | RV32I::CSRRS | RV32I::CSRRC, InstructionType::I(i)) => {
+            format!(
+                "{} {}, {}, {}",
+                name,
+                reg(i.rd),
+                csr_name(i.csr()),
+                reg(i.rs1)
+            )
+        }
+        (RV32I::CSRRWI | RV32I::CSRRSI | RV32I::CSRRCI, InstructionType::I(i)) => {
+            format!("{} {}, {}, {}", name, reg(i.rd), csr_name(i.csr()), i.rs1)
+        }
+        (RV32I::SLLI | RV32I::SRLI | RV32I::SRAI, InstructionType::I(i)) => {
+            format!("{} {}, {}, {}", name, reg(i.rd), reg(i.rs1), i.imm & 0x1F)
+        }
+        (
+            RV32I::LB | RV32I::LH | RV32I::LW | RV32I::LBU | RV32I::LHU | RV32I::JALR,
+            InstructionType::I(i),
+        ) => format!("{} {}, {}({})", name, reg(i.rd), i.imm, reg(i.rs1)),
+        (_, InstructionType::I(i)) => {
+            format!("{} {}, {}, {}", name, reg(i.rd), reg(i.rs1), i.imm)
+        }
+        (_, InstructionType::S(s)) => format!("{} {}, {}({})", name, reg(s.rs2), s.imm, reg(s.rs1)),
+        (_, InstructionType::B(b)) => format!(
+            "{} {}, {}, {}",
+            name,
+            reg(b.rs1),
+            reg(b.rs2),
+            target(pc, b.imm as i32)
+        ),
+        (_, InstructionType::U(u)) => format!("{} {}, {:#x}", name, reg(u.rd), u.imm),
+        (_, InstructionType::J(j)) => format!("{} {}, {}", name, reg(j.rd), target(pc, j.imm)),
+        (_, InstructionType::FENCE(f)) => {
+            format!("{} {}, {}", name, fence_set(f.pred), fence_set(f.succ))
+        }
+    }
+}
+
+// One `address:  raw  instruction` listing line. Words that do not decode are listed
+// as `.word`.
+pub fn line(addr: u32, raw: u32, syntax: Syntax) -> String {
+    let text = match Instruction::try_from(raw) {
+        Ok(inst) => format(&inst, Some(addr), syntax),
+        Err(_) => format!(".word {:#010x}", raw),
+    };
+
+    format!("{:08x}:  {:08x}  {}", addr, raw, text)
+}
+
+// List the words in [start, end) as `line`s. Stops with an error at the first unreadable address.
+pub fn disassemble_range(
+    bus: &mut Bus,
+    start: u32,
+    end: u32,
+    syntax: Syntax,
+) -> Result<Vec<String>, BusError> {
+    let mut lines = Vec::new();
+    let mut addr = start;
+
+    while addr < end {
+        lines.push(line(addr, bus.read(addr, 4)?, syntax));
+        addr = match addr.checked_add(4) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Ok(lines)
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+
+use crate::bus::BusError;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const EF_RISCV_RVC: u32 = 0x1;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    WrongClass(u8),      // EI_CLASS, expected ELFCLASS32
+    WrongEndianness(u8), // EI_DATA, expected little-endian
+    WrongMachine(u16),   // e_machine, expected EM_RISCV
+    NotExecutable(u16),  // e_type, expected ET_EXEC
+    Compressed,          // Built for the C extension, which is not implemented
+    Load(u32, BusError), // A segment could not be written at the given address
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::BadMagic => write!(f, "Not an ELF file"),
+            ElfError::WrongClass(class) => {
+                write!(f, "Wrong ELF class {} (expected 32-bit)", class)
+            }
+            ElfError::WrongEndianness(data) => {
+                write!(
+                    f,
+                    "Wrong ELF data encoding {} (expected little-endian)",
+                    data
+                )
+            }
+            ElfError::WrongMachine(machine) => {
+                write!(f, "Wrong ELF machine {} (expected RISC-V)", machine)
+            }
+            ElfError::NotExecutable(kind) => {
+                write!(f, "ELF type {} is not a static executable", kind)
+            }
+            ElfError::Compressed => write!(f, "ELF uses compressed instructions (RVC)"),
+            ElfError::Load(addr, e) => write!(f, "Unable to load segment at 0x{:08X}: {}", addr, e),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+// A PT_LOAD segment. Bytes past `data` up to `mem_size` are zero (.bss).
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+// Function and object symbols sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        SymbolTable { symbols }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // The closest symbol at or below `addr`, with the offset of `addr` into it. Sized
+    // symbols only match inside their extent.
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let index = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = self.symbols[..index].last()?;
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        Some((symbol, offset))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn slice(data: &[u8], offset: u32, size: u32) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    let end = start
+        .checked_add(size as usize)
+        .ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+fn read_str(strtab: &[u8], offset: u32) -> Result<String, ElfError> {
+    let bytes = strtab.get(offset as usize..).ok_or(ElfError::Truncated)?;
+    let len = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(ElfError::Truncated)?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(b"\x7fELF")
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if data.len() < 52 {
+            return Err(if Elf::is_elf(data) {
+                ElfError::Truncated
+            } else {
+                ElfError::BadMagic
+            });
+        }
+
+        if !Elf::is_elf(data) {
+            return Err(ElfError::BadMagic);
+        }
+
+        if data[4] != ELFCLASS32 {
+            return Err(ElfError::WrongClass(data[4]));
+        }
+
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::WrongEndianness(data[5]));
+        }
+
+        let e_type = read_u16(data, 16)?;
+        let e_machine = read_u16(data, 18)?;
+        if e_machine != EM_RISCV {
+            return Err(ElfError::WrongMachine(e_machine));
+        }
+
+        if e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable(e_type));
+        }
+
+        if read_u32(data, 36)? & EF_RISCV_RVC != 0 {
+            return Err(ElfError::Compressed);
+        }
+
+        let entry = read_u32(data, 24)?;
+        let phoff = read_u32(data, 28)? as usize;
+        let shoff = read_u32(data, 32)? as usize;
+        let phentsize = read_u16(data, 42)? as usize;
+        let phnum = read_u16(data, 44)? as usize;
+        let shentsize = read_u16(data, 46)? as usize;
+        let shnum = read_u16(data, 48)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if read_u32(data, ph)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(data, ph + 4)?;
+            let paddr = read_u32(data, ph + 12)?;
+            let file_size = read_u32(data, ph + 16)?;
+            let mem_size = read_u32(data, ph + 20)?;
+            let flags = read_u32(data, ph + 24)?;
+
+            segments.push(Segment {
+                addr: paddr,
+                data: slice(data, offset, file_size)?.to_vec(),
+                mem_size: mem_size.max(file_size),
+                flags,
+            });
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if read_u32(data, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let symtab = slice(data, read_u32(data, sh + 16)?, read_u32(data, sh + 20)?)?;
+            let link = read_u32(data, sh + 24)? as usize;
+            let strtab_sh = shoff + link * shentsize;
+            let strtab = slice(
+                data,
+                read_u32(data, strtab_sh + 16)?,
+                read_u32(data, strtab_sh + 20)?,
+            )?;
+
+            for sym in symtab.chunks_exact(16) {
+                let name = read_u32(sym, 0)?;
+                let info = sym[12];
+                let shndx = read_u16(sym, 14)?;
+                let kind = info & 0xF;
+
+                // Skip undefined, section and file symbols.
+                if name == 0 || shndx == 0 || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&kind) {
+                    continue;
+                }
+
+                // "$x" / "$d" mapping symbols only mark code and data ranges.
+                let name = read_str(strtab, name)?;
+                if name.starts_with('$') {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name,
+                    addr: read_u32(sym, 4)?,
+                    size: read_u32(sym, 8)?,
+                });
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols: SymbolTable::new(symbols),
+        })
+    }
+}
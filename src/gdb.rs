@@ -0,0 +1,616 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::isa::{Instruction, REGISTER_NAMES};
+use crate::trap::Exception;
+
+const EBREAK: u32 = 0x00100073;
+
+// GDB numbers the CSRs after x0-x31, pc and the 32 floating point registers.
+const PC_REGNUM: usize = 32;
+const CSR_REGNUM: usize = 65;
+
+// Steps between checks for a Ctrl-C from GDB while the guest is running.
+const POLL_INTERVAL: u64 = 4096;
+
+// Signal numbers GDB uses in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+// How a debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Session {
+    Exited(u8), // The guest exited with this status
+    Detached,   // GDB detached and left the guest to run freely
+    Killed,     // GDB killed the guest or went away
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,  // Z2
+    Read,   // Z3
+    Access, // Z4
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u32,
+    len: u32,
+}
+
+// Why the guest stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Signal(u8),
+    SoftwareBreak,
+    HardwareBreak,
+    Watch(Watchpoint),
+    Exited(u8),
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::SoftwareBreak => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::HardwareBreak => format!("T{:02x}hwbreak:;", SIGTRAP),
+            Stop::Watch(watch) => {
+                let name = match watch.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, watch.addr)
+            }
+            Stop::Exited(code) => format!("W{:02x}", code),
+        }
+    }
+}
+
+fn signal(exception: Exception) -> u8 {
+    match exception {
+        Exception::IllegalInstruction(_) => SIGILL,
+        Exception::InstructionAccessFault(_)
+        | Exception::LoadAccessFault(_)
+        | Exception::StoreAccessFault(_) => SIGSEGV,
+        Exception::InstructionAddressMisaligned(_)
+        | Exception::LoadAddressMisaligned(_)
+        | Exception::StoreAddressMisaligned(_) => SIGBUS,
+        Exception::Breakpoint(_) | Exception::EnvironmentCall(_) => SIGTRAP,
+    }
+}
+
+// The XML target description GDB asks for with qXfer:features:read.
+pub fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <architecture>riscv:rv32</architecture>\n",
+        "  <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    ));
+
+    for (regnum, name) in REGISTER_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "s0" => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "    <reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, kind, regnum
+        );
+    }
+    xml += &format!(
+        "    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n",
+        PC_REGNUM
+    );
+    xml += "  </feature>\n  <feature name=\"org.gnu.gdb.riscv.csr\">\n";
+
+    for (csr, name) in csr::NAMES {
+        xml += &format!(
+            "    <reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"csr\"/>\n",
+            name,
+            CSR_REGNUM + csr as usize
+        );
+    }
+
+    xml + "  </feature>\n</target>\n"
+}
+
+fn hex_u32(value: u32) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// A register value as GDB sends it: eight hex digits, least significant byte first.
+fn parse_reg(text: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(text)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+// "addr,len" as used by m, M and the Z packets.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+// Packet framing over the TCP connection: `$data#checksum`, acknowledged with `+`.
+struct Connection {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Connection {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // The next packet's data, or None once GDB closes the connection. A bare Ctrl-C
+    // outside of a packet comes back as "\x03".
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some("\x03".to_string())),
+                Some(b'$') => {}
+                Some(_) => continue, // Acks and noise between packets
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if self.ack {
+                let reply = if expected == Some(sum) { b"+" } else { b"-" };
+                self.stream.write_all(reply)?;
+            }
+            if expected == Some(sum) || !self.ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        // Escape the characters that would otherwise end or confuse the packet.
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+                _ => escaped.push(byte),
+            }
+        }
+
+        let sum = escaped.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.ack {
+                return Ok(());
+            }
+
+            match self.byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Whether GDB has sent a Ctrl-C, without waiting for one.
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let mut byte = [0];
+        let interrupted = matches!(self.stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+        let _ = self.stream.set_nonblocking(false);
+        interrupted
+    }
+}
+
+// A GDB remote serial protocol server for one CPU. Software breakpoints write EBREAK
+// into memory; hardware breakpoints and watchpoints are checked around every step.
+pub struct GdbStub<'a> {
+    cpu: &'a mut CPU,
+    breakpoints: HashMap<u32, u32>, // Address to the instruction word the EBREAK replaced
+    hw_breakpoints: HashSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(cpu: &'a mut CPU) -> Self {
+        GdbStub {
+            cpu,
+            breakpoints: HashMap::new(),
+            hw_breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    // Wait for GDB to connect to `addr` and serve it until the session ends.
+    pub fn listen<A: ToSocketAddrs>(self, addr: A) -> io::Result<Session> {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        self.serve(stream)
+    }
+
+    // Serve GDB over an accepted connection. The guest does not run until GDB says so.
+    pub fn serve(mut self, stream: TcpStream) -> io::Result<Session> {
+        stream.set_nodelay(true)?;
+        let mut conn = Connection { stream, ack: true };
+
+        self.cpu.ebreak_halts = true;
+        let session = self.session(&mut conn);
+        self.cpu.ebreak_halts = false;
+
+        // Leave memory as the program wrote it.
+        for (addr, word) in self.breakpoints.drain() {
+            let _ = self.cpu.bus.write(addr, 4, word);
+        }
+
+        session
+    }
+
+    fn session(&mut self, conn: &mut Connection) -> io::Result<Session> {
+        while let Some(packet) = conn.receive()? {
+            let reply = match packet.as_str() {
+                "\x03" => Stop::Signal(SIGINT).reply(),
+                "?" => Stop::Signal(SIGTRAP).reply(),
+                "D" | "D;1" => {
+                    conn.send("OK")?;
+                    return Ok(Session::Detached);
+                }
+                "k" | "vKill;1" => {
+                    if packet != "k" {
+                        conn.send("OK")?;
+                    }
+                    return Ok(Session::Killed);
+                }
+                "QStartNoAckMode" => {
+                    conn.send("OK")?;
+                    conn.ack = false;
+                    continue;
+                }
+                // c/s [addr] and C/S sig[;addr]: the guest has no signals to deliver.
+                _ if packet.starts_with(['c', 's', 'C', 'S']) => {
+                    let addr = match packet.split_once(';') {
+                        Some((_, addr)) => addr,
+                        None if packet.starts_with(['c', 's']) => &packet[1..],
+                        None => "",
+                    };
+                    if let Some(addr) = parse_hex(addr) {
+                        self.cpu.pc = addr as usize;
+                    }
+
+                    let stop = self.resume(conn, packet.starts_with(['s', 'S']));
+                    conn.send(&stop.reply())?;
+                    if let Stop::Exited(code) = stop {
+                        return Ok(Session::Exited(code));
+                    }
+                    continue;
+                }
+                _ => self.command(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+
+            conn.send(&reply)?;
+        }
+
+        Ok(Session::Killed)
+    }
+
+    // Reply to a packet that does not run the guest. None is an error reply; unknown
+    // packets get the empty reply that tells GDB they are unsupported.
+    fn command(&mut self, packet: &str) -> Option<String> {
+        let (kind, args) = packet.split_at(1);
+
+        match kind {
+            "g" => {
+                let mut regs: String = self.cpu.regs.iter().map(|r| hex_u32(*r)).collect();
+                regs += &hex_u32(self.cpu.pc as u32);
+                Some(regs)
+            }
+            "G" => {
+                for (regnum, chunk) in args.as_bytes().chunks(8).enumerate().take(33) {
+                    let value = parse_reg(std::str::from_utf8(chunk).ok()?)?;
+                    self.write_reg(regnum, value)?;
+                }
+                Some("OK".to_string())
+            }
+            "p" => Some(hex_u32(self.read_reg(parse_hex(args)? as usize)?)),
+            "P" => {
+                let (regnum, value) = args.split_once('=')?;
+                self.write_reg(parse_hex(regnum)? as usize, parse_reg(value)?)?;
+                Some("OK".to_string())
+            }
+            "m" => {
+                let (addr, len) = parse_range(args)?;
+                let bytes = self.read_memory(addr, len);
+                if bytes.is_empty() && len > 0 {
+                    return None;
+                }
+                Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (addr, len) = parse_range(range)?;
+                let data = parse_hex_bytes(data)?;
+                if data.len() != len as usize {
+                    return None;
+                }
+                self.write_memory(addr, &data)?;
+                Some("OK".to_string())
+            }
+            "Z" | "z" => {
+                let (kind_num, range) = args.split_once(',')?;
+                let range = range.split(';').next()?;
+                let (addr, len) = parse_range(range)?;
+                self.set_breakpoint(kind_num, addr, len, kind == "Z")?;
+                Some("OK".to_string())
+            }
+            "H" | "T" => Some("OK".to_string()),
+            "q" => self.query(packet),
+            _ => Some(String::new()),
+        }
+    }
+
+    fn query(&self, packet: &str) -> Option<String> {
+        if let Some(features) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = parse_range(features)?;
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let more = if end < xml.len() { "m" } else { "l" };
+            return Some(format!("{}{}", more, &xml[start..end]));
+        }
+
+        let reply = match packet.split(':').next()? {
+            "qSupported" => {
+                "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+"
+            }
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        };
+
+        Some(reply.to_string())
+    }
+
+    fn read_reg(&self, regnum: usize) -> Option<u32> {
+        match regnum {
+            0..=31 => Some(self.cpu.regs[regnum]),
+            PC_REGNUM => Some(self.cpu.pc as u32),
+            _ => {
+                let csr = u16::try_from(regnum.checked_sub(CSR_REGNUM)?).ok()?;
+                self.cpu.csrs.read(csr, Privilege::Machine).ok()
+            }
+        }
+    }
+
+    fn write_reg(&mut self, regnum: usize, value: u32) -> Option<()> {
+        match regnum {
+            0 => {} // x0 stays zero
+            1..=31 => self.cpu.regs[regnum] = value,
+            PC_REGNUM => self.cpu.pc = value as usize,
+            _ => {
+                let csr = u16::try_from(regnum.checked_sub(CSR_REGNUM)?).ok()?;
+                self.cpu.csrs.write(csr, value, Privilege::Machine).ok()?;
+            }
+        }
+
+        Some(())
+    }
+
+    // The byte at `addr` as the program sees it, from under any breakpoint.
+    fn breakpoint_byte(&self, addr: u32) -> Option<u8> {
+        self.breakpoints.iter().find_map(|(bp, word)| {
+            let offset = addr.wrapping_sub(*bp);
+            (offset < 4).then(|| word.to_le_bytes()[offset as usize])
+        })
+    }
+
+    // Up to `len` bytes from `addr`, stopping at the first unreadable one.
+    fn read_memory(&mut self, addr: u32, len: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for i in 0..len {
+            let addr = addr.wrapping_add(i);
+            let byte = match self.breakpoint_byte(addr) {
+                Some(byte) => byte,
+                None => match self.cpu.bus.read(addr, 1) {
+                    Ok(byte) => byte as u8,
+                    Err(_) => break,
+                },
+            };
+            bytes.push(byte);
+        }
+
+        bytes
+    }
+
+    // Bytes under a breakpoint go into the saved instruction, so the EBREAK stays in place.
+    fn write_memory(&mut self, addr: u32, data: &[u8]) -> Option<()> {
+        for (i, byte) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            let saved = self
+                .breakpoints
+                .iter_mut()
+                .find(|(bp, _)| addr.wrapping_sub(**bp) < 4);
+            match saved {
+                Some((bp, word)) => {
+                    let mut bytes = word.to_le_bytes();
+                    bytes[addr.wrapping_sub(*bp) as usize] = *byte;
+                    *word = u32::from_le_bytes(bytes);
+                }
+                None => self.cpu.bus.write(addr, 1, *byte as u32).ok()?,
+            }
+        }
+
+        Some(())
+    }
+
+    fn set_breakpoint(&mut self, kind: &str, addr: u32, len: u32, insert: bool) -> Option<()> {
+        let watch = |kind| Watchpoint { kind, addr, len };
+
+        match (kind, insert) {
+            ("0", true) => {
+                if !self.breakpoints.contains_key(&addr) {
+                    let word = self.cpu.bus.read(addr, 4).ok()?;
+                    self.cpu.bus.write(addr, 4, EBREAK).ok()?;
+                    self.breakpoints.insert(addr, word);
+                }
+            }
+            ("0", false) => {
+                if let Some(word) = self.breakpoints.remove(&addr) {
+                    self.cpu.bus.write(addr, 4, word).ok()?;
+                }
+            }
+            ("1", true) => {
+                self.hw_breakpoints.insert(addr);
+            }
+            ("1", false) => {
+                self.hw_breakpoints.remove(&addr);
+            }
+            ("2" | "3" | "4", _) => {
+                let watchpoint = watch(match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                });
+                self.watchpoints.retain(|w| *w != watchpoint);
+                if insert {
+                    self.watchpoints.push(watchpoint);
+                }
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    // The watchpoint the next instruction will trigger, if any.
+    fn watch_hit(&mut self) -> Option<Watchpoint> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let raw = self.cpu.bus.read(self.cpu.pc as u32, 4).ok()?;
+        let inst = Instruction::try_from(raw).ok()?;
+        let (addr, size, store) = self.cpu.memory_access(&inst)?;
+        self.watchpoints.iter().copied().find(|w| {
+            let overlaps = addr < w.addr.wrapping_add(w.len) && w.addr < addr.wrapping_add(size);
+            let matches = match w.kind {
+                WatchKind::Write => store,
+                WatchKind::Read => !store,
+                WatchKind::Access => true,
+            };
+            overlaps && matches
+        })
+    }
+
+    // Execute one instruction. At a software breakpoint this runs the instruction the
+    // EBREAK replaced, so resuming from a breakpoint moves past it.
+    fn step(&mut self) -> Result<(), Exception> {
+        let pc = self.cpu.pc as u32;
+        let Some(word) = self.breakpoints.get(&pc).copied() else {
+            return self.cpu.step();
+        };
+
+        let _ = self.cpu.bus.write(pc, 4, word);
+        let result = self.cpu.step();
+        let _ = self.cpu.bus.write(pc, 4, EBREAK);
+        result
+    }
+
+    // Run one instruction, or until something stops the guest.
+    fn resume(&mut self, conn: &mut Connection, single: bool) -> Stop {
+        let mut executed = 0u64;
+
+        loop {
+            let pc = self.cpu.pc as u32;
+            if executed > 0 {
+                if self.hw_breakpoints.contains(&pc) {
+                    return Stop::HardwareBreak;
+                }
+                if executed.is_multiple_of(POLL_INTERVAL) && conn.interrupted() {
+                    return Stop::Signal(SIGINT);
+                }
+            }
+
+            let watch = self.watch_hit();
+            let result = if executed == 0 {
+                self.step()
+            } else {
+                self.cpu.step()
+            };
+            executed += 1;
+
+            match result {
+                Err(Exception::Breakpoint(addr)) if self.breakpoints.contains_key(&addr) => {
+                    return Stop::SoftwareBreak;
+                }
+                Err(exception) => return Stop::Signal(signal(exception)),
+                Ok(()) => {}
+            }
+
+            if let Some(code) = self.cpu.exit_code {
+                return Stop::Exited(code);
+            }
+            if self.cpu.exit_on_nop && self.cpu.last_inst.is_some_and(|inst| inst.is_nop()) {
+                return Stop::Exited(0);
+            }
+            if let Some(watch) = watch {
+                return Stop::Watch(watch);
+            }
+            if single {
+                return Stop::Signal(SIGTRAP);
+            }
+        }
+    }
+}
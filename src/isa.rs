@@ -1,4 +1,18 @@
-#[derive(Debug, Clone, Copy)]
+use std::fmt;
+
+use crate::disasm::{self, Syntax};
+
+// ABI names of x0-x31.
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// The canonical NOP, addi x0, x0, 0. The all-zero word is illegal.
+pub const NOP: u32 = 0x0000_0013;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RV32I {
     LUI,    // Load Upper Immediate
     AUIPC,  // Add Upper Immediate to PC
@@ -40,6 +54,31 @@ pub enum RV32I {
     FENCE,  // Fence
     ECALL,  // Environment Call
     EBREAK, // Environment Break
+
+    // Zifencei
+    FENCEI, // Instruction Fence
+
+    // RV32M
+    MUL,    // Multiply
+    MULH,   // Multiply High (signed x signed)
+    MULHSU, // Multiply High (signed x unsigned)
+    MULHU,  // Multiply High (unsigned x unsigned)
+    DIV,    // Divide
+    DIVU,   // Divide Unsigned
+    REM,    // Remainder
+    REMU,   // Remainder Unsigned
+
+    // Zicsr
+    CSRRW,  // Atomic Read/Write CSR
+    CSRRS,  // Atomic Read and Set Bits in CSR
+    CSRRC,  // Atomic Read and Clear Bits in CSR
+    CSRRWI, // Atomic Read/Write CSR Immediate
+    CSRRSI, // Atomic Read and Set Bits in CSR Immediate
+    CSRRCI, // Atomic Read and Clear Bits in CSR Immediate
+
+    // Privileged
+    MRET, // Machine-mode Trap Return
+    WFI,  // Wait for Interrupt
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -61,6 +100,13 @@ pub struct I {
     pub opcode: u8,
 }
 
+impl I {
+    // CSR instructions keep an unsigned 12-bit CSR address in the immediate field.
+    pub fn csr(&self) -> u16 {
+        (self.imm as u16) & 0xFFF
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct S {
     pub imm: i16,
@@ -123,23 +169,174 @@ pub struct Instruction {
     pub raw: u32,
 }
 
-impl Instruction {
-    pub fn from(inst: u32) -> Self {
-        let inst_type = parse_inst(inst).expect("Invalid instruction");
-        let decoded_inst = get_inst(inst_type).expect("Invalid instruction");
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode(u8),
+    InvalidFunct3(u8),
+    InvalidFunct7(u8),
+    ReservedEncoding,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "Invalid opcode: {:#b}", opcode),
+            DecodeError::InvalidFunct3(funct3) => write!(f, "Invalid funct3: {:#b}", funct3),
+            DecodeError::InvalidFunct7(funct7) => write!(f, "Invalid funct7: {:#b}", funct7),
+            DecodeError::ReservedEncoding => write!(f, "Reserved encoding"),
+        }
+    }
+}
 
-        Instruction {
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    InvalidRegister(u8),
+    InvalidField(u8), // opcode, funct3, funct7 or FENCE field wider than its slot
+    ImmediateOutOfRange(i64), // Immediate that does not fit its field
+    MisalignedImmediate(i64), // Branch or jump offset that is not a multiple of 2
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::InvalidRegister(reg) => write!(f, "Invalid register: x{}", reg),
+            EncodeError::InvalidField(field) => write!(f, "Invalid field: {:#b}", field),
+            EncodeError::ImmediateOutOfRange(imm) => write!(f, "Immediate out of range: {}", imm),
+            EncodeError::MisalignedImmediate(imm) => write!(f, "Misaligned immediate: {}", imm),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+fn reg_field(reg: u8) -> Result<u32, EncodeError> {
+    if reg >= 32 {
+        return Err(EncodeError::InvalidRegister(reg));
+    }
+
+    Ok(reg as u32)
+}
+
+fn field(value: u8, bits: u32) -> Result<u32, EncodeError> {
+    if value as u32 >= 1 << bits {
+        return Err(EncodeError::InvalidField(value));
+    }
+
+    Ok(value as u32)
+}
+
+// Check that `imm` fits a signed field of `bits` bits and, for branches and jumps, is
+// even. Returns the two's complement bits.
+fn imm_field(imm: i64, bits: u32, even: bool) -> Result<u32, EncodeError> {
+    let limit = 1i64 << (bits - 1);
+    if imm < -limit || imm >= limit {
+        return Err(EncodeError::ImmediateOutOfRange(imm));
+    }
+
+    if even && imm % 2 != 0 {
+        return Err(EncodeError::MisalignedImmediate(imm));
+    }
+
+    Ok(imm as u32)
+}
+
+impl InstructionType {
+    // Pack the fields into a machine word; the inverse of decoding.
+    pub fn encode(&self) -> Result<u32, EncodeError> {
+        let word = match *self {
+            InstructionType::R(i) => {
+                (field(i.funct7, 7)? << 25)
+                    | (reg_field(i.rs2)? << 20)
+                    | (reg_field(i.rs1)? << 15)
+                    | (field(i.funct3, 3)? << 12)
+                    | (reg_field(i.rd)? << 7)
+                    | field(i.opcode, 7)?
+            }
+            InstructionType::I(i) => {
+                let imm = imm_field(i.imm as i64, 12, false)?;
+                ((imm & 0xFFF) << 20)
+                    | (reg_field(i.rs1)? << 15)
+                    | (field(i.funct3, 3)? << 12)
+                    | (reg_field(i.rd)? << 7)
+                    | field(i.opcode, 7)?
+            }
+            InstructionType::S(i) => {
+                let imm = imm_field(i.imm as i64, 12, false)?;
+                (((imm >> 5) & 0x7F) << 25)
+                    | (reg_field(i.rs2)? << 20)
+                    | (reg_field(i.rs1)? << 15)
+                    | (field(i.funct3, 3)? << 12)
+                    | ((imm & 0x1F) << 7)
+                    | field(i.opcode, 7)?
+            }
+            InstructionType::B(i) => {
+                let imm = imm_field(i.imm as i64, 13, true)?;
+                (((imm >> 12) & 0x1) << 31)
+                    | (((imm >> 5) & 0x3F) << 25)
+                    | (reg_field(i.rs2)? << 20)
+                    | (reg_field(i.rs1)? << 15)
+                    | (field(i.funct3, 3)? << 12)
+                    | (((imm >> 1) & 0xF) << 8)
+                    | (((imm >> 11) & 0x1) << 7)
+                    | field(i.opcode, 7)?
+            }
+            InstructionType::U(i) => {
+                if i.imm > 0xFFFFF {
+                    return Err(EncodeError::ImmediateOutOfRange(i.imm as i64));
+                }
+
+                (i.imm << 12) | (reg_field(i.rd)? << 7) | field(i.opcode, 7)?
+            }
+            InstructionType::J(i) => {
+                let imm = imm_field(i.imm as i64, 21, true)?;
+                (((imm >> 20) & 0x1) << 31)
+                    | (((imm >> 1) & 0x3FF) << 21)
+                    | (((imm >> 11) & 0x1) << 20)
+                    | (((imm >> 12) & 0xFF) << 12)
+                    | (reg_field(i.rd)? << 7)
+                    | field(i.opcode, 7)?
+            }
+            InstructionType::FENCE(i) => {
+                (field(i.fm, 4)? << 28)
+                    | (field(i.pred, 4)? << 24)
+                    | (field(i.succ, 4)? << 20)
+                    | (reg_field(i.rs1)? << 15)
+                    | (field(i.funct3, 3)? << 12)
+                    | (reg_field(i.rd)? << 7)
+                    | field(i.opcode, 7)?
+            }
+        };
+
+        Ok(word)
+    }
+}
+
+impl TryFrom<u32> for Instruction {
+    type Error = DecodeError;
+
+    fn try_from(inst: u32) -> Result<Self, Self::Error> {
+        let inst_type = parse_inst(inst)?;
+        let decoded_inst = get_inst(inst_type)?;
+
+        Ok(Instruction {
             inst_type,
             inst: decoded_inst,
             raw: inst,
-        }
+        })
     }
+}
 
-    pub fn is_nop(&self) -> bool {
-        if self.raw == 0 {
-            return true;
-        }
+// Canonical assembly syntax with ABI register names; see `disasm` for other styles.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", disasm::format(self, None, Syntax::default()))
+    }
+}
 
+impl Instruction {
+    pub fn is_nop(&self) -> bool {
         match self.inst {
             RV32I::ADDI => {
                 let i = match self.inst_type {
@@ -158,13 +355,13 @@ fn get_opcode(inst: u32) -> u8 {
     (inst & 0x7F) as u8
 }
 
-fn parse_inst(inst: u32) -> Result<InstructionType, String> {
+fn parse_inst(inst: u32) -> Result<InstructionType, DecodeError> {
     let opcode = get_opcode(inst);
 
     match opcode {
         // U-Type
         0b0110111 | 0b0010111 => {
-            let imm = ((inst >> 12) & 0xFFFFF) as u32;
+            let imm = (inst >> 12) & 0xFFFFF;
             let rd = ((inst >> 7) & 0x1F) as u8;
 
             Ok(InstructionType::U(U { imm, rd, opcode }))
@@ -172,7 +369,11 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
 
         // J-Type
         0b1101111 => {
-            let imm = ((((inst >> 12) & 0xFFFFF) as i32) << 11) >> 11;
+            let imm = (((inst >> 31) & 0x1) << 20)
+                | (((inst >> 12) & 0xFF) << 12)
+                | (((inst >> 20) & 0x1) << 11)
+                | (((inst >> 21) & 0x3FF) << 1);
+            let imm = ((imm as i32) << 11) >> 11;
             let rd = ((inst >> 7) & 0x1F) as u8;
 
             Ok(InstructionType::J(J { imm, rd, opcode }))
@@ -200,7 +401,7 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
                 | (((inst >> 7) & 0x1) << 11)
                 | (((inst >> 25) & 0x3F) << 5)
                 | (((inst >> 8) & 0xF) << 1)) as u16;
-            let imm = ((((imm) as i16) << 4) >> 4) as i16;
+            let imm = ((imm as i16) << 3) >> 3;
             let rs2 = ((inst >> 20) & 0x1F) as u8;
             let rs1 = ((inst >> 15) & 0x1F) as u8;
             let funct3 = ((inst >> 12) & 0x7) as u8;
@@ -216,8 +417,8 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
 
         // S-Type
         0b0100011 => {
-            let imm = ((((inst >> 25) & 0x7F) << 5) | (((inst >> 7) & 0x1F) << 0)) as u16;
-            let imm = ((((imm) as i16) << 4) >> 4) as i16;
+            let imm = ((((inst >> 25) & 0x7F) << 5) | ((inst >> 7) & 0x1F)) as u16;
+            let imm = ((imm as i16) << 4) >> 4;
             let rs2 = ((inst >> 20) & 0x1F) as u8;
             let rs1 = ((inst >> 15) & 0x1F) as u8;
             let funct3 = ((inst >> 12) & 0x7) as u8;
@@ -249,17 +450,16 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
             }))
         }
 
-        // ECALL, EBREAK
+        // ECALL, EBREAK, CSR*
         0b1110011 => {
             let imm = ((((inst >> 20) & 0xFFF) as i16) << 4) >> 4;
             let rs1 = ((inst >> 15) & 0x1F) as u8;
             let funct3 = ((inst >> 12) & 0x7) as u8;
             let rd = ((inst >> 7) & 0x1F) as u8;
 
-            assert_eq!(imm, 0);
-            assert_eq!(rs1, 0);
-            assert_eq!(funct3, 0);
-            assert_eq!(rd, 0);
+            if funct3 == 0 && (rs1 != 0 || rd != 0) {
+                return Err(DecodeError::ReservedEncoding);
+            }
 
             Ok(InstructionType::I(I {
                 imm,
@@ -279,7 +479,10 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
             let funct3 = ((inst >> 12) & 0x7) as u8;
             let rd = ((inst >> 7) & 0x1F) as u8;
 
-            assert_eq!(funct3, 0);
+            // FENCE or FENCE.I
+            if funct3 > 0b001 {
+                return Err(DecodeError::InvalidFunct3(funct3));
+            }
 
             Ok(InstructionType::FENCE(FENCE {
                 fm,
@@ -292,28 +495,11 @@ fn parse_inst(inst: u32) -> Result<InstructionType, String> {
             }))
         }
 
-        0b0000000 => {
-            // ADDI x0, x0, 0
-
-            let imm = 0;
-            let rs1 = 0;
-            let funct3 = 0;
-            let rd = 0;
-
-            Ok(InstructionType::I(I {
-                imm,
-                rs1,
-                funct3,
-                rd,
-                opcode,
-            }))
-        }
-
-        _ => Err(format!("Invalid opcode: {:#b}", opcode)),
+        _ => Err(DecodeError::UnknownOpcode(opcode)),
     }
 }
 
-fn get_inst(inst: InstructionType) -> Result<RV32I, String> {
+fn get_inst(inst: InstructionType) -> Result<RV32I, DecodeError> {
     match inst {
         InstructionType::R(i) => match i.funct7 {
             0b0000000 => match i.funct3 {
@@ -325,14 +511,25 @@ fn get_inst(inst: InstructionType) -> Result<RV32I, String> {
                 0b101 => Ok(RV32I::SRL),
                 0b110 => Ok(RV32I::OR),
                 0b111 => Ok(RV32I::AND),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
             0b0100000 => match i.funct3 {
                 0b000 => Ok(RV32I::SUB),
                 0b101 => Ok(RV32I::SRA),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
+            },
+            0b0000001 => match i.funct3 {
+                0b000 => Ok(RV32I::MUL),
+                0b001 => Ok(RV32I::MULH),
+                0b010 => Ok(RV32I::MULHSU),
+                0b011 => Ok(RV32I::MULHU),
+                0b100 => Ok(RV32I::DIV),
+                0b101 => Ok(RV32I::DIVU),
+                0b110 => Ok(RV32I::REM),
+                0b111 => Ok(RV32I::REMU),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
-            _ => Err(format!("Invalid funct7: {:#b}", i.funct7)),
+            _ => Err(DecodeError::InvalidFunct7(i.funct7)),
         },
 
         InstructionType::B(i) => match i.funct3 {
@@ -342,13 +539,13 @@ fn get_inst(inst: InstructionType) -> Result<RV32I, String> {
             0b101 => Ok(RV32I::BGE),
             0b110 => Ok(RV32I::BLTU),
             0b111 => Ok(RV32I::BGEU),
-            _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+            _ => Err(DecodeError::InvalidFunct3(i.funct3)),
         },
 
         InstructionType::I(i) => match i.opcode {
             0b1100111 => match i.funct3 {
                 0b000 => Ok(RV32I::JALR),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
             0b0000011 => match i.funct3 {
                 0b000 => Ok(RV32I::LB),
@@ -356,58 +553,65 @@ fn get_inst(inst: InstructionType) -> Result<RV32I, String> {
                 0b010 => Ok(RV32I::LW),
                 0b100 => Ok(RV32I::LBU),
                 0b101 => Ok(RV32I::LHU),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
-            0b0010011 => match i.funct3 {
-                0b001 => Ok(RV32I::SLLI),
-                0b101 => match i.imm & 0x400 {
-                    0x400 => Ok(RV32I::SRAI),
-                    _ => Ok(RV32I::SRLI),
-                },
-                0b000 => Ok(RV32I::ADDI),
-                0b010 => Ok(RV32I::SLTI),
-                0b011 => Ok(RV32I::SLTIU),
-                0b100 => Ok(RV32I::XORI),
-                0b110 => Ok(RV32I::ORI),
-                0b111 => Ok(RV32I::ANDI),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+            // Shifts keep funct7 in imm[11:5]; on RV32 shamt[5] must be clear as well.
+            0b0010011 => match (i.funct3, (i.imm >> 5) & 0x7F) {
+                (0b001, 0b0000000) => Ok(RV32I::SLLI),
+                (0b101, 0b0000000) => Ok(RV32I::SRLI),
+                (0b101, 0b0100000) => Ok(RV32I::SRAI),
+                (0b001 | 0b101, funct7) => Err(DecodeError::InvalidFunct7(funct7 as u8)),
+                (0b000, _) => Ok(RV32I::ADDI),
+                (0b010, _) => Ok(RV32I::SLTI),
+                (0b011, _) => Ok(RV32I::SLTIU),
+                (0b100, _) => Ok(RV32I::XORI),
+                (0b110, _) => Ok(RV32I::ORI),
+                (0b111, _) => Ok(RV32I::ANDI),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
             0b1110011 => match i.funct3 {
-                0b000 => Ok(RV32I::ECALL),
-                0b001 => Ok(RV32I::EBREAK),
-                _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+                0b000 => match i.imm {
+                    0 => Ok(RV32I::ECALL),
+                    1 => Ok(RV32I::EBREAK),
+                    0x302 => Ok(RV32I::MRET),
+                    0x105 => Ok(RV32I::WFI),
+                    _ => Err(DecodeError::ReservedEncoding),
+                },
+                0b001 => Ok(RV32I::CSRRW),
+                0b010 => Ok(RV32I::CSRRS),
+                0b011 => Ok(RV32I::CSRRC),
+                0b101 => Ok(RV32I::CSRRWI),
+                0b110 => Ok(RV32I::CSRRSI),
+                0b111 => Ok(RV32I::CSRRCI),
+                _ => Err(DecodeError::InvalidFunct3(i.funct3)),
             },
 
-            0b0000000 => {
-                assert_eq!(i.funct3, 0);
-                assert_eq!(i.imm, 0);
-
-                Ok(RV32I::ADDI)
-            }
-            _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+            _ => Err(DecodeError::InvalidFunct3(i.funct3)),
         },
 
         InstructionType::S(i) => match i.funct3 {
             0b000 => Ok(RV32I::SB),
             0b001 => Ok(RV32I::SH),
             0b010 => Ok(RV32I::SW),
-            _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+            _ => Err(DecodeError::InvalidFunct3(i.funct3)),
         },
 
         InstructionType::U(i) => match i.opcode {
             0b0110111 => Ok(RV32I::LUI),
             0b0010111 => Ok(RV32I::AUIPC),
-            _ => Err(format!("Invalid funct3: {:#?}", i)),
+            _ => Err(DecodeError::UnknownOpcode(i.opcode)),
         },
 
         InstructionType::J(i) => match i.opcode {
             0b1101111 => Ok(RV32I::JAL),
-            _ => Err(format!("Invalid funct3: {:#?}", i)),
+            _ => Err(DecodeError::UnknownOpcode(i.opcode)),
         },
 
-        InstructionType::FENCE(i) => match i.opcode {
-            0b0001111 => Ok(RV32I::FENCE),
-            _ => Err(format!("Invalid funct3: {:#b}", i.funct3)),
+        InstructionType::FENCE(i) => match (i.opcode, i.funct3) {
+            (0b0001111, 0b000) => Ok(RV32I::FENCE),
+            (0b0001111, 0b001) => Ok(RV32I::FENCEI),
+            (0b0001111, _) => Err(DecodeError::InvalidFunct3(i.funct3)),
+            _ => Err(DecodeError::UnknownOpcode(i.opcode)),
         },
     }
 }
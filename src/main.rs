@@ -1,15 +1,30 @@
-use cpu::Interface;
+#![allow(clippy::upper_case_acronyms)]
 
+use std::{env, process};
+
+mod asm;
+mod bus;
+mod cli;
+mod commit;
+mod compliance;
+mod config;
 mod cpu;
+mod csr;
+mod devices;
+mod difftest;
+mod disasm;
+mod elf;
+mod gdb;
 mod isa;
+mod monitor;
+mod semihost;
+mod syscall;
+#[cfg(test)]
 mod tests;
+mod trap;
+mod tui;
 
 fn main() {
-    let mut cpu = cpu::CPU::new();
-    cpu.exit_on_nop = true;
-    // cpu.boot("tests/test.bin", 16);
-    cpu.from_inst(vec![0x3e800093, 0x06308113, 0x40208133]);
-    cpu.run();
-
-    cpu.print_state();
+    let args: Vec<String> = env::args().skip(1).collect();
+    process::exit(cli::main(&args) as i32);
 }
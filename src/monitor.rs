@@ -0,0 +1,495 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::cli::parse_number;
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::disasm::{self, Syntax};
+use crate::isa::REGISTER_NAMES;
+
+const HELP: &str = "\
+Commands:
+    step [n], s [n]            Execute n instructions (default 1), listing each one
+    continue, c                Run until a breakpoint, an exception or the program exits
+    break [loc], b [loc]       Set a breakpoint at an address or symbol, or list them
+    delete <loc>, d <loc>      Remove a breakpoint
+    regs, r                    Show pc and every register
+    reg <name> [value]         Show or set a register, pc or CSR
+    mem <loc> [bytes]          Dump memory (default 64 bytes)
+    poke <loc> <value> [size]  Write a 1, 2 or 4 byte (default) value to memory
+    disas [loc] [n]            Disassemble n instructions around loc (default pc)
+    last                       Show the last instruction executed and the last trap
+    history                    List the commands entered so far
+    !n, !!                     Repeat command n, or the last command
+    source <file>              Run the commands in a file
+    help, h                    Show this help
+    quit, q                    Leave the monitor
+Locations are numbers (0x for hex), symbols or symbol+offset. An empty line repeats
+the last command.
+";
+
+// Instructions listed on each side of the location by `disas`.
+const DISAS_CONTEXT: u32 = 4;
+
+// What the REPL does after a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+// An interactive monitor that steps a CPU and inspects its state, one command per line.
+pub struct Monitor<'a> {
+    cpu: &'a mut CPU,
+    pub breakpoints: BTreeSet<u32>,
+    pub history: Vec<String>,
+    pub syntax: Syntax,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(cpu: &'a mut CPU) -> Self {
+        Monitor {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            history: Vec::new(),
+            syntax: Syntax::default(),
+        }
+    }
+
+    // Read commands from `input` until it ends or a command quits. With `prompt`, each
+    // command is preceded by a prompt, as for a terminal.
+    pub fn repl(
+        &mut self,
+        input: &mut dyn BufRead,
+        out: &mut dyn Write,
+        prompt: bool,
+    ) -> io::Result<()> {
+        loop {
+            if prompt {
+                write!(out, "(rv801) ")?;
+                out.flush()?;
+            }
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            if self.enter(line.trim(), out)? == Flow::Quit {
+                return Ok(());
+            }
+        }
+    }
+
+    // Run a line typed by the user, resolving history references and recording it.
+    pub fn enter(&mut self, line: &str, out: &mut dyn Write) -> io::Result<Flow> {
+        let line = match line {
+            "" | "!!" => match self.history.last() {
+                Some(last) => last.clone(),
+                None => return Ok(Flow::Continue),
+            },
+            _ if line.starts_with('!') => {
+                let entry = line[1..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.history.get(n.checked_sub(1)?));
+                match entry {
+                    Some(entry) => entry.clone(),
+                    None => {
+                        writeln!(out, "No command {} in history", &line[1..])?;
+                        return Ok(Flow::Continue);
+                    }
+                }
+            }
+            _ => line.to_string(),
+        };
+
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        self.execute(&line, out)
+    }
+
+    // Run one command. Errors in the command are reported to `out`.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<Flow> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(Flow::Continue);
+        };
+        if command.starts_with('#') {
+            return Ok(Flow::Continue);
+        }
+
+        let result = match command {
+            "step" | "s" => match args.first() {
+                Some(count) => match parse_number(count) {
+                    Some(count) => self.step(count, out),
+                    None => Err(format!("Invalid count: {}", count)),
+                },
+                None => self.step(1, out),
+            },
+            "continue" | "c" => self.step(u64::MAX, out),
+            "break" | "b" => self.set_breakpoint(args.first().copied(), out),
+            "delete" | "d" => self.delete(args.first().copied(), out),
+            "regs" | "r" => self.regs(out).map_err(|e| e.to_string()),
+            "reg" => self.reg(args, out),
+            "mem" => self.mem(args, out),
+            "poke" => self.poke(args),
+            "disas" => self.disas(args, out),
+            "last" => self.last(out).map_err(|e| e.to_string()),
+            "history" => self
+                .history
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, line)| writeln!(out, "{:>4}  {}", i + 1, line))
+                .map_err(|e| e.to_string()),
+            "source" => match args.first() {
+                Some(path) => return self.source(path, out),
+                None => Err("Missing file for source".to_string()),
+            },
+            "help" | "h" => write!(out, "{}", HELP).map_err(|e| e.to_string()),
+            "quit" | "q" => return Ok(Flow::Quit),
+            _ => Err(format!("Unknown command: {} (try help)", command)),
+        };
+
+        if let Err(e) = result {
+            writeln!(out, "{}", e)?;
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    // Run each line of a script as a command. Scripts do not go into the history.
+    pub fn source(&mut self, path: &str, out: &mut dyn Write) -> io::Result<Flow> {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                writeln!(out, "Unable to read {}: {}", path, e)?;
+                return Ok(Flow::Continue);
+            }
+        };
+
+        for line in script.lines() {
+            if self.execute(line.trim(), out)? == Flow::Quit {
+                return Ok(Flow::Quit);
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    // An address from a number, a symbol or symbol+offset.
+    fn location(&self, text: &str) -> Result<u32, String> {
+        if let Some(addr) = parse_number(text).and_then(|value| u32::try_from(value).ok()) {
+            return Ok(addr);
+        }
+
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => {
+                let offset = parse_number(offset)
+                    .and_then(|value| u32::try_from(value).ok())
+                    .ok_or_else(|| format!("Invalid offset: {}", offset))?;
+                (name, offset)
+            }
+            None => (text, 0),
+        };
+
+        match self.cpu.symbols.get(name) {
+            Some(symbol) => Ok(symbol.addr.wrapping_add(offset)),
+            None => Err(format!("No symbol or address: {}", text)),
+        }
+    }
+
+    // "0x00000010 <main+4>", or just the address when no symbol covers it.
+    fn describe(&self, addr: u32) -> String {
+        match self.cpu.symbols.lookup(addr) {
+            Some((symbol, 0)) => format!("0x{:08x} <{}>", addr, symbol.name),
+            Some((symbol, offset)) => format!("0x{:08x} <{}+{}>", addr, symbol.name, offset),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    // Set a breakpoint, or list them all without a location.
+    fn set_breakpoint(&mut self, loc: Option<&str>, out: &mut dyn Write) -> Result<(), String> {
+        let lines = match loc {
+            Some(loc) => {
+                let addr = self.location(loc)?;
+                self.breakpoints.insert(addr);
+                vec![format!("Breakpoint at {}", self.describe(addr))]
+            }
+            None if self.breakpoints.is_empty() => vec!["No breakpoints".to_string()],
+            None => self
+                .breakpoints
+                .iter()
+                .map(|addr| self.describe(*addr))
+                .collect(),
+        };
+
+        lines
+            .iter()
+            .try_for_each(|line| writeln!(out, "{}", line))
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete(&mut self, loc: Option<&str>, out: &mut dyn Write) -> Result<(), String> {
+        let addr = self.location(loc.ok_or("Missing location for delete")?)?;
+        if !self.breakpoints.remove(&addr) {
+            return Err(format!("No breakpoint at {}", self.describe(addr)));
+        }
+
+        writeln!(out, "Deleted breakpoint at {}", self.describe(addr)).map_err(|e| e.to_string())
+    }
+
+    // Execute up to `count` instructions, listing each one, and stop early at a
+    // breakpoint, an unhandled exception or the end of the program. A breakpoint at the
+    // starting pc does not stop the first instruction, so `continue` moves past it.
+    fn step(&mut self, count: u64, out: &mut dyn Write) -> Result<(), String> {
+        if self.finished() {
+            return Err("The program has exited".to_string());
+        }
+
+        let listing = count != u64::MAX;
+        let mut executed = 0;
+
+        while executed < count {
+            let pc = self.cpu.pc as u32;
+            if executed > 0 && self.breakpoints.contains(&pc) {
+                writeln!(out, "Stopped at breakpoint {}", self.describe(pc))
+                    .map_err(|e| e.to_string())?;
+                break;
+            }
+
+            if listing {
+                self.list(pc, out).map_err(|e| e.to_string())?;
+            }
+
+            executed += 1;
+            if let Err(e) = self.cpu.step() {
+                return Err(format!("Unhandled exception at 0x{:08x}: {}", pc, e));
+            }
+
+            if self.finished() {
+                let status = self.cpu.exit_code.unwrap_or(0);
+                writeln!(out, "Program exited with status {}", status)
+                    .map_err(|e| e.to_string())?;
+                return Ok(());
+            }
+        }
+
+        if !listing {
+            self.list(self.cpu.pc as u32, out)
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn finished(&self) -> bool {
+        self.cpu.exit_code.is_some()
+            || (self.cpu.exit_on_nop && self.cpu.last_inst.is_some_and(|inst| inst.is_nop()))
+    }
+
+    // The listing line for the instruction at `addr`, labelled when a symbol starts there.
+    fn list(&mut self, addr: u32, out: &mut dyn Write) -> io::Result<()> {
+        if let Some((symbol, 0)) = self.cpu.symbols.lookup(addr) {
+            writeln!(out, "{}:", symbol.name)?;
+        }
+
+        match self.cpu.bus.read(addr, 4) {
+            Ok(raw) => writeln!(out, "{}", disasm::line(addr, raw, self.syntax)),
+            Err(e) => writeln!(out, "{:08x}:  <{}>", addr, e),
+        }
+    }
+
+    fn regs(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "pc   {}", self.describe(self.cpu.pc as u32))?;
+        for row in 0..8 {
+            let line: Vec<String> = (0..4)
+                .map(|col| {
+                    let reg = row + col * 8;
+                    format!("{:<4} 0x{:08x}", REGISTER_NAMES[reg], self.cpu.regs[reg])
+                })
+                .collect();
+            writeln!(out, "{}", line.join("  "))?;
+        }
+
+        Ok(())
+    }
+
+    fn reg(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let name = *args.first().ok_or("Missing register for reg")?;
+        let index = register(name);
+        let csr = csr::by_name(name);
+
+        if let Some(value) = args.get(1) {
+            let value = parse_value(value)?;
+            match (name, index, csr) {
+                ("pc", _, _) => self.cpu.pc = value as usize,
+                (_, Some(0), _) => return Err("zero cannot be written".to_string()),
+                (_, Some(index), _) => self.cpu.regs[index] = value,
+                (_, None, Some(csr)) => self
+                    .cpu
+                    .csrs
+                    .write(csr, value, Privilege::Machine)
+                    .map_err(|e| e.to_string())?,
+                _ => return Err(format!("Unknown register: {}", name)),
+            }
+        }
+
+        let value = match (name, index, csr) {
+            ("pc", _, _) => self.cpu.pc as u32,
+            (_, Some(index), _) => self.cpu.regs[index],
+            (_, None, Some(csr)) => self
+                .cpu
+                .csrs
+                .read(csr, Privilege::Machine)
+                .map_err(|e| e.to_string())?,
+            _ => return Err(format!("Unknown register: {}", name)),
+        };
+
+        writeln!(out, "{} = 0x{:08x} ({})", name, value, value as i32).map_err(|e| e.to_string())
+    }
+
+    // A hex dump with 16 bytes and their ASCII on each line.
+    fn mem(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let start = self.location(args.first().ok_or("Missing location for mem")?)?;
+        let len = match args.get(1) {
+            Some(len) => parse_value(len)?,
+            None => 64,
+        };
+
+        for line in (0..len).step_by(16) {
+            let addr = start.wrapping_add(line);
+            let mut bytes = Vec::new();
+            for i in 0..16.min(len - line) {
+                let byte =
+                    self.cpu.bus.read(addr.wrapping_add(i), 1).map_err(|e| {
+                        format!("Unable to read 0x{:08x}: {}", addr.wrapping_add(i), e)
+                    })?;
+                bytes.push(byte as u8);
+            }
+
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|b| match b {
+                    0x20..=0x7e => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "{:08x}:  {:<47}  {}", addr, hex.join(" "), ascii)
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn poke(&mut self, args: &[&str]) -> Result<(), String> {
+        let (Some(loc), Some(value)) = (args.first(), args.get(1)) else {
+            return Err("Usage: poke <loc> <value> [size]".to_string());
+        };
+        let addr = self.location(loc)?;
+        let value = parse_value(value)?;
+        let size = match args.get(2) {
+            Some(size) => parse_value(size)?,
+            None => 4,
+        };
+        if !matches!(size, 1 | 2 | 4) {
+            return Err(format!("Invalid size: {}", size));
+        }
+
+        self.cpu
+            .bus
+            .write(addr, size as usize, value)
+            .map_err(|e| format!("Unable to write 0x{:08x}: {}", addr, e))
+    }
+
+    // List instructions around `loc`, marking the one at pc.
+    fn disas(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let center = match args.first() {
+            Some(loc) => self.location(loc)?,
+            None => self.cpu.pc as u32,
+        } & !0b11;
+        let span = match args.get(1) {
+            Some(count) => parse_value(count)?
+                .checked_mul(4)
+                .ok_or_else(|| format!("Invalid count: {}", count))?,
+            None => DISAS_CONTEXT * 4,
+        };
+
+        let start = center.saturating_sub(span);
+        let end = center.saturating_add(span);
+        let mut addr = start;
+        while addr <= end {
+            let marker = if addr == self.cpu.pc as u32 {
+                "=> "
+            } else {
+                "   "
+            };
+            if let Some((symbol, 0)) = self.cpu.symbols.lookup(addr) {
+                writeln!(out, "{}:", symbol.name).map_err(|e| e.to_string())?;
+            }
+
+            let text = match self.cpu.bus.read(addr, 4) {
+                Ok(raw) => disasm::line(addr, raw, self.syntax),
+                Err(_) => format!("{:08x}:  <unmapped>", addr),
+            };
+            writeln!(out, "{}{}", marker, text).map_err(|e| e.to_string())?;
+
+            addr = match addr.checked_add(4) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        Ok(())
+    }
+
+    fn last(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.cpu.last_inst {
+            Some(inst) => writeln!(
+                out,
+                "Last instruction: {} ({:08x})",
+                disasm::format(&inst, None, self.syntax),
+                inst.raw
+            )?,
+            None => writeln!(out, "No instruction executed yet")?,
+        }
+
+        if let Some(trap) = self.cpu.last_trap {
+            writeln!(out, "Last trap: {}", trap)?;
+        }
+
+        Ok(())
+    }
+}
+
+// x0-x31 or an ABI name such as a0 or fp.
+fn register(name: &str) -> Option<usize> {
+    if let Some(index) = REGISTER_NAMES.iter().position(|reg| *reg == name) {
+        return Some(index);
+    }
+
+    match name {
+        "fp" => Some(8),
+        _ => name
+            .strip_prefix('x')
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n < 32),
+    }
+}
+
+// A 32-bit value; negative numbers are stored as two's complement.
+fn parse_value(text: &str) -> Result<u32, String> {
+    let value = match text.strip_prefix('-') {
+        Some(digits) => parse_number(digits).and_then(|value| {
+            let value = i64::try_from(value).ok()?;
+            (value <= 1 << 31).then(|| (-value) as u32)
+        }),
+        None => parse_number(text).and_then(|value| u32::try_from(value).ok()),
+    };
+
+    value.ok_or_else(|| format!("Invalid value: {}", text))
+}
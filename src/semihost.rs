@@ -0,0 +1,312 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::Bus;
+use crate::syscall::{
+    self, read_bytes, read_cstring, read_to_guest, write_bytes, write_from_guest, Handle,
+};
+
+// The instructions around the EBREAK of a semihosting call.
+pub const ENTRY: u32 = 0x01f01013; // slli x0, x0, 0x1f
+pub const EXIT: u32 = 0x40705013; // srai x0, x0, 7
+
+// ARM semihosting operation numbers
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISERROR: u32 = 0x08;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_REMOVE: u32 = 0x0E;
+const SYS_RENAME: u32 = 0x0F;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+const SYS_ELAPSED: u32 = 0x30;
+const SYS_TICKFREQ: u32 = 0x31;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+
+// Most operations return -1 on failure and leave the reason for SYS_ERRNO.
+const FAILED: u32 = u32::MAX;
+
+// Semihosting calls made with the `slli; ebreak; srai` sequence, served from the host.
+// Files are confined to a sandbox directory, as with `Syscalls`.
+pub struct Semihosting {
+    root: PathBuf,
+    cmdline: String,
+    files: Vec<Option<Handle>>,
+    errno: i32,
+    started: Instant,
+    pub stdin: Box<dyn Read>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+}
+
+impl Semihosting {
+    // `cmdline` is what SYS_GET_CMDLINE reports: the program name and its arguments.
+    pub fn new(root: &Path, cmdline: &str) -> io::Result<Self> {
+        Ok(Semihosting {
+            root: root.canonicalize()?,
+            cmdline: cmdline.to_string(),
+            files: vec![None],
+            errno: 0,
+            started: Instant::now(),
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        })
+    }
+
+    // Serve the operation in a0 with the parameter (usually a block address) in a1,
+    // leaving the result in a0. Returns the exit status if the guest called SYS_EXIT.
+    pub fn handle(&mut self, regs: &mut [u32; 32], bus: &mut Bus) -> Option<u8> {
+        let (op, param) = (regs[10], regs[11]);
+
+        match op {
+            // On RV32 the parameter of SYS_EXIT is the reason itself, with no status.
+            SYS_EXIT => return Some(self.exit(param, 0)),
+            SYS_EXIT_EXTENDED => {
+                let block = self.block(bus, param, 2).unwrap_or([0; 4]);
+                return Some(self.exit(block[0], block[1]));
+            }
+            _ => {}
+        }
+
+        regs[10] = match self.dispatch(op, param, bus) {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                FAILED
+            }
+        };
+
+        None
+    }
+
+    fn exit(&mut self, reason: u32, status: u32) -> u8 {
+        let _ = self.stdout.flush();
+        let _ = self.stderr.flush();
+
+        match reason {
+            ADP_STOPPED_APPLICATION_EXIT => status as u8,
+            _ => 1,
+        }
+    }
+
+    // The first `count` words of a parameter block.
+    fn block(&self, bus: &mut Bus, addr: u32, count: u32) -> Result<[u32; 4], i32> {
+        let mut words = [0; 4];
+        let bytes = read_bytes(bus, addr, count * 4)?;
+        for (word, bytes) in words.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        Ok(words)
+    }
+
+    fn handle_of(&mut self, handle: u32) -> Result<&mut Handle, i32> {
+        self.files
+            .get_mut(handle as usize)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+
+    // Store a handle in the lowest free slot. Slot 0 stays empty: a successful SYS_OPEN
+    // returns a nonzero handle.
+    fn insert(&mut self, handle: Handle) -> u32 {
+        match (1..self.files.len()).find(|&index| self.files[index].is_none()) {
+            Some(index) => {
+                self.files[index] = Some(handle);
+                index as u32
+            }
+            None => {
+                self.files.push(Some(handle));
+                (self.files.len() - 1) as u32
+            }
+        }
+    }
+
+    fn path(&self, bus: &mut Bus, addr: u32, len: u32) -> Result<PathBuf, i32> {
+        let name = read_bytes(bus, addr, len)?;
+        syscall::sandbox_path(&self.root, &String::from_utf8_lossy(&name))
+    }
+
+    fn dispatch(&mut self, op: u32, param: u32, bus: &mut Bus) -> Result<u32, i32> {
+        match op {
+            SYS_OPEN => {
+                let [name, mode, len, _] = self.block(bus, param, 3)?;
+                if mode > 11 {
+                    return Err(EINVAL);
+                }
+
+                // ":tt" is the console: stdin to read, stdout to write and stderr to append.
+                if read_bytes(bus, name, len)? == b":tt" {
+                    let handle = match mode {
+                        0..=3 => Handle::Stdin,
+                        4..=7 => Handle::Stdout,
+                        _ => Handle::Stderr,
+                    };
+                    return Ok(self.insert(handle));
+                }
+
+                // The modes are fopen's r, r+, w, w+, a and a+, each with and without b.
+                let mut options = OpenOptions::new();
+                let update = mode & 0b10 != 0;
+                match mode >> 2 {
+                    0 => options.read(true).write(update),
+                    1 => options.write(true).read(update).create(true).truncate(true),
+                    _ => options.append(true).read(update).create(true),
+                };
+
+                let path = self.path(bus, name, len)?;
+                let file = options.open(path).map_err(syscall::errno)?;
+                Ok(self.insert(Handle::File(file)))
+            }
+            SYS_CLOSE => {
+                let [handle, ..] = self.block(bus, param, 1)?;
+                let slot = self.files.get_mut(handle as usize).ok_or(EBADF)?;
+                slot.take().ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let data = read_bytes(bus, param, 1)?;
+                self.stdout.write_all(&data).map_err(syscall::errno)?;
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let text = read_cstring(bus, param)?;
+                self.stdout
+                    .write_all(text.as_bytes())
+                    .and_then(|_| self.stdout.flush())
+                    .map_err(syscall::errno)?;
+                Ok(0)
+            }
+            // Both return the number of bytes *not* transferred.
+            SYS_WRITE => {
+                let [handle, buf, len, _] = self.block(bus, param, 3)?;
+                let handle = self
+                    .files
+                    .get_mut(handle as usize)
+                    .and_then(Option::as_mut)
+                    .ok_or(EBADF)?;
+                match write_from_guest(bus, handle, &mut self.stdout, &mut self.stderr, buf, len) {
+                    Ok(count) => Ok(len - count),
+                    // Nothing was written; SYS_ERRNO says why.
+                    Err(errno) => {
+                        self.errno = errno;
+                        Ok(len)
+                    }
+                }
+            }
+            SYS_READ => {
+                let [handle, buf, len, _] = self.block(bus, param, 3)?;
+                let handle = self
+                    .files
+                    .get_mut(handle as usize)
+                    .and_then(Option::as_mut)
+                    .ok_or(EBADF)?;
+                let count = read_to_guest(bus, handle, &mut self.stdin, buf, len)?;
+                Ok(len - count)
+            }
+            SYS_READC => {
+                let mut byte = [0];
+                match self.stdin.read(&mut byte).map_err(syscall::errno)? {
+                    0 => Err(EINVAL),
+                    _ => Ok(byte[0] as u32),
+                }
+            }
+            SYS_ISERROR => {
+                let [status, ..] = self.block(bus, param, 1)?;
+                Ok(((status as i32) < 0) as u32)
+            }
+            SYS_ISTTY => {
+                let [handle, ..] = self.block(bus, param, 1)?;
+                match self.handle_of(handle)? {
+                    Handle::Stdin => Ok(io::stdin().is_terminal() as u32),
+                    Handle::Stdout => Ok(io::stdout().is_terminal() as u32),
+                    Handle::Stderr => Ok(io::stderr().is_terminal() as u32),
+                    Handle::File(_) => Ok(0),
+                }
+            }
+            SYS_SEEK => {
+                let [handle, pos, ..] = self.block(bus, param, 2)?;
+                match self.handle_of(handle)? {
+                    Handle::File(file) => {
+                        file.seek(SeekFrom::Start(pos as u64))
+                            .map_err(syscall::errno)?;
+                        Ok(0)
+                    }
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                let [handle, ..] = self.block(bus, param, 1)?;
+                match self.handle_of(handle)? {
+                    Handle::File(file) => Ok(file.metadata().map_err(syscall::errno)?.len() as u32),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_REMOVE => {
+                let [name, len, ..] = self.block(bus, param, 2)?;
+                let path = self.path(bus, name, len)?;
+                fs::remove_file(path).map_err(syscall::errno)?;
+                Ok(0)
+            }
+            SYS_RENAME => {
+                let [from, from_len, to, to_len] = self.block(bus, param, 4)?;
+                let from = self.path(bus, from, from_len)?;
+                let to = self.path(bus, to, to_len)?;
+                fs::rename(from, to).map_err(syscall::errno)?;
+                Ok(0)
+            }
+            SYS_CLOCK => Ok((self.started.elapsed().as_millis() / 10) as u32),
+            SYS_TIME => Ok(SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as u32),
+            SYS_ERRNO => Ok(self.errno as u32),
+            SYS_GET_CMDLINE => {
+                let [buf, size, ..] = self.block(bus, param, 2)?;
+                if self.cmdline.len() as u32 >= size {
+                    return Err(EINVAL);
+                }
+
+                let mut text = self.cmdline.clone().into_bytes();
+                text.push(0);
+                write_bytes(bus, buf, &text)?;
+                write_bytes(bus, param + 4, &(self.cmdline.len() as u32).to_le_bytes())?;
+                Ok(0)
+            }
+            // Zeroes ask the C library to use its linker-script defaults.
+            SYS_HEAPINFO => {
+                let [block, ..] = self.block(bus, param, 1)?;
+                write_bytes(bus, block, &[0; 16])?;
+                Ok(0)
+            }
+            // Ticks are microseconds.
+            SYS_ELAPSED => {
+                let ticks = self.started.elapsed().as_micros() as u64;
+                write_bytes(bus, param, &ticks.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(1_000_000),
+            _ => Err(ENOSYS),
+        }
+    }
+}
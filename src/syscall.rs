@@ -0,0 +1,598 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::{Bus, BusError};
+use crate::cpu::CPU;
+
+// RISC-V Linux system call numbers (asm-generic/unistd.h)
+const SYS_GETCWD: u32 = 17;
+const SYS_DUP: u32 = 23;
+const SYS_IOCTL: u32 = 29;
+const SYS_MKDIRAT: u32 = 34;
+const SYS_UNLINKAT: u32 = 35;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_NEWFSTATAT: u32 = 79;
+const SYS_FSTAT: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_CLOCK_GETTIME: u32 = 113;
+const SYS_UNAME: u32 = 160;
+const SYS_GETTIMEOFDAY: u32 = 169;
+const SYS_GETPID: u32 = 172;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+// Legacy calls still made by newlib's libgloss
+const SYS_OPEN: u32 = 1024;
+const SYS_UNLINK: u32 = 1026;
+const SYS_STAT: u32 = 1038;
+
+const AT_FDCWD: i32 = -100;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const O_ACCMODE: u32 = 0o3;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const CLOCK_REALTIME: u32 = 0;
+
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+// The most a single read or write copies through host memory at once.
+const READ_CHUNK: usize = 0x1_0000;
+
+// The most iovecs readv and writev take.
+const IOV_MAX: u32 = 1024;
+
+// The guest errno for a failed call; a0 gets its negation.
+type SysResult = Result<u32, i32>;
+
+pub fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+pub enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl Handle {
+    fn duplicate(&self) -> Result<Handle, i32> {
+        match self {
+            Handle::Stdin => Ok(Handle::Stdin),
+            Handle::Stdout => Ok(Handle::Stdout),
+            Handle::Stderr => Ok(Handle::Stderr),
+            Handle::File(file) => Ok(Handle::File(file.try_clone().map_err(errno)?)),
+        }
+    }
+}
+
+pub fn read_bytes(bus: &mut Bus, addr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    (0..len)
+        .map(|i| {
+            bus.read(addr.wrapping_add(i), 1)
+                .map(|byte| byte as u8)
+                .map_err(|_| EFAULT)
+        })
+        .collect()
+}
+
+pub fn write_bytes(bus: &mut Bus, addr: u32, data: &[u8]) -> Result<(), i32> {
+    for (i, byte) in data.iter().enumerate() {
+        bus.write(addr.wrapping_add(i as u32), 1, *byte as u32)
+            .map_err(|_| EFAULT)?;
+    }
+
+    Ok(())
+}
+
+// Read up to `len` bytes from `handle` (from `stdin` for Handle::Stdin) straight into
+// guest memory at `buf`, READ_CHUNK at a time so a huge `len` costs the host no more
+// than a small one. A fault part way returns what was copied, as Linux does.
+pub fn read_to_guest(
+    bus: &mut Bus,
+    handle: &mut Handle,
+    stdin: &mut dyn Read,
+    buf: u32,
+    len: u32,
+) -> Result<u32, i32> {
+    if let Handle::Stdout | Handle::Stderr = handle {
+        return Err(EBADF);
+    }
+
+    let mut chunk = vec![0; (len as usize).min(READ_CHUNK)];
+    let mut total = 0;
+    while total < len {
+        let size = ((len - total) as usize).min(READ_CHUNK);
+        let count = match handle {
+            Handle::File(file) => file.read(&mut chunk[..size]),
+            _ => stdin.read(&mut chunk[..size]),
+        };
+        let count = match count {
+            Ok(count) => count,
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(errno(e)),
+        };
+
+        for (i, byte) in chunk[..count].iter().enumerate() {
+            let copied = total + i as u32;
+            if bus
+                .write(buf.wrapping_add(copied), 1, *byte as u32)
+                .is_err()
+            {
+                // Leave the bytes the guest could not take unread, where the file can
+                // seek; a failed seek only loses them, as it would on a pipe.
+                if let Handle::File(file) = handle {
+                    let unread = (count - i) as i64;
+                    file.seek(SeekFrom::Current(-unread)).ok();
+                }
+                return if copied > 0 { Ok(copied) } else { Err(EFAULT) };
+            }
+        }
+
+        total += count as u32;
+        // A short read means nothing more is ready; don't block waiting for it.
+        if count < size {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
+// Write up to `len` bytes of guest memory at `buf` to `handle` (to `stdout` or `stderr`
+// for those), READ_CHUNK at a time. A fault or a failed write part way returns what was
+// written before it, as Linux does.
+pub fn write_from_guest(
+    bus: &mut Bus,
+    handle: &mut Handle,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+    buf: u32,
+    len: u32,
+) -> Result<u32, i32> {
+    let out: &mut dyn Write = match handle {
+        Handle::Stdin => return Err(EBADF),
+        Handle::Stdout => stdout,
+        Handle::Stderr => stderr,
+        Handle::File(file) => file,
+    };
+
+    let mut chunk = Vec::with_capacity((len as usize).min(READ_CHUNK));
+    let mut total = 0;
+    while total < len {
+        let size = (len - total).min(READ_CHUNK as u32);
+        chunk.clear();
+        for i in 0..size {
+            match bus.read(buf.wrapping_add(total + i), 1) {
+                Ok(byte) => chunk.push(byte as u8),
+                Err(_) => break,
+            }
+        }
+
+        match out.write_all(&chunk).and_then(|_| out.flush()) {
+            Ok(()) => total += chunk.len() as u32,
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(errno(e)),
+        }
+        if chunk.len() < size as usize {
+            return if total > 0 { Ok(total) } else { Err(EFAULT) };
+        }
+    }
+
+    Ok(total)
+}
+
+// A NUL-terminated guest string of at most 4 KiB.
+pub fn read_cstring(bus: &mut Bus, addr: u32) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+    for i in 0..4096 {
+        match bus.read(addr.wrapping_add(i), 1).map_err(|_| EFAULT)? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte as u8),
+        }
+    }
+
+    Err(EFAULT)
+}
+
+// The rv32 `struct kernel_stat` used by newlib and picolibc: 128 bytes.
+fn stat_bytes(mode: u32, size: u64, modified: Option<SystemTime>) -> [u8; 128] {
+    let mut stat = [0u8; 128];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+
+    let time = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    put(16, &mode.to_le_bytes()); // st_mode
+    put(20, &1u32.to_le_bytes()); // st_nlink
+    put(48, &size.to_le_bytes()); // st_size
+    put(56, &4096u32.to_le_bytes()); // st_blksize
+    put(64, &size.div_ceil(512).to_le_bytes()); // st_blocks
+    for offset in [72, 88, 104] {
+        // st_atim, st_mtim, st_ctim
+        put(offset, &time.as_secs().to_le_bytes());
+        put(offset + 8, &time.subsec_nanos().to_le_bytes());
+    }
+
+    stat
+}
+
+fn metadata_stat(metadata: &fs::Metadata) -> [u8; 128] {
+    let kind = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+    let permissions = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+
+    stat_bytes(kind | permissions, metadata.len(), metadata.modified().ok())
+}
+
+// Map a guest path into the sandbox at `root`, which must be canonical. `..` stops at
+// the root, and a path may not lead out of it through a symlink.
+pub fn sandbox_path(root: &Path, path: &str) -> Result<PathBuf, i32> {
+    let mut full = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => full.push(part),
+            Component::ParentDir if full != root => {
+                full.pop();
+            }
+            _ => {}
+        }
+    }
+
+    let mut existing = full.as_path();
+    while fs::symlink_metadata(existing).is_err() {
+        existing = existing.parent().ok_or(EACCES)?;
+    }
+
+    match existing.canonicalize() {
+        Ok(real) if real.starts_with(root) => Ok(full),
+        // A dangling symlink can't be checked, so it's refused too.
+        _ => Err(EACCES),
+    }
+}
+
+// Linux system calls made with ECALL, served from the host. Files are confined to a
+// sandbox directory, which the guest sees as both `/` and its working directory.
+pub struct Syscalls {
+    root: PathBuf,
+    files: Vec<Option<Handle>>,
+    brk_start: u32,
+    brk: u32,
+    started: Instant,
+    pub stdin: Box<dyn Read>,
+    pub stdout: Box<dyn Write>,
+    pub stderr: Box<dyn Write>,
+}
+
+impl Syscalls {
+    // `brk` is the initial program break, normally the end of the loaded image.
+    pub fn new(root: &Path, brk: u32) -> io::Result<Self> {
+        Ok(Syscalls {
+            root: root.canonicalize()?,
+            files: vec![
+                Some(Handle::Stdin),
+                Some(Handle::Stdout),
+                Some(Handle::Stderr),
+            ],
+            brk_start: brk,
+            brk,
+            started: Instant::now(),
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        })
+    }
+
+    // Serve the call in a7 with arguments in a0-a5, leaving the result or -errno in a0.
+    // Returns the exit status if the guest called exit.
+    pub fn handle(&mut self, regs: &mut [u32; 32], bus: &mut Bus) -> Option<u8> {
+        let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+
+        if regs[17] == SYS_EXIT || regs[17] == SYS_EXIT_GROUP {
+            let _ = self.stdout.flush();
+            let _ = self.stderr.flush();
+            return Some(args[0] as u8);
+        }
+
+        regs[10] = match self.dispatch(regs[17], args, bus) {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u32,
+        };
+
+        None
+    }
+
+    fn dispatch(&mut self, number: u32, args: [u32; 6], bus: &mut Bus) -> SysResult {
+        match number {
+            SYS_GETCWD => {
+                if args[1] < 2 {
+                    return Err(EINVAL);
+                }
+                write_bytes(bus, args[0], b"/\0")?;
+                Ok(args[0])
+            }
+            SYS_DUP => {
+                let handle = Self::slot(&mut self.files, args[0])?.duplicate()?;
+                Ok(self.insert(handle))
+            }
+            SYS_IOCTL => {
+                Self::slot(&mut self.files, args[0])?;
+                Err(ENOTTY)
+            }
+            SYS_MKDIRAT => {
+                let path = self.path_at(bus, args[0], args[1])?;
+                fs::create_dir(path).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_UNLINKAT | SYS_UNLINK => {
+                let (path, flags) = match number {
+                    SYS_UNLINK => (self.path_at(bus, AT_FDCWD as u32, args[0])?, 0),
+                    _ => (self.path_at(bus, args[0], args[1])?, args[2]),
+                };
+                match flags & AT_REMOVEDIR {
+                    0 => fs::remove_file(path).map_err(errno)?,
+                    _ => fs::remove_dir(path).map_err(errno)?,
+                }
+                Ok(0)
+            }
+            SYS_OPENAT => self.openat(bus, args[0], args[1], args[2]),
+            SYS_OPEN => self.openat(bus, AT_FDCWD as u32, args[0], args[1]),
+            SYS_CLOSE => {
+                let slot = self.files.get_mut(args[0] as usize).ok_or(EBADF)?;
+                slot.take().ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let pos = match args[2] {
+                    0 => SeekFrom::Start(args[1] as u64),
+                    1 => SeekFrom::Current(args[1] as i32 as i64),
+                    2 => SeekFrom::End(args[1] as i32 as i64),
+                    _ => return Err(EINVAL),
+                };
+                match Self::slot(&mut self.files, args[0])? {
+                    Handle::File(file) => Ok(file.seek(pos).map_err(errno)? as u32),
+                    _ => Err(ESPIPE),
+                }
+            }
+            SYS_READ => self.read(bus, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(bus, args[0], args[1], args[2]),
+            SYS_READV | SYS_WRITEV => {
+                // Check every iovec before moving any data. The total must fit the
+                // ssize_t the call returns.
+                if args[2] > IOV_MAX {
+                    return Err(EINVAL);
+                }
+                let mut iovs = Vec::new();
+                let mut size = 0u32;
+                for i in 0..args[2] {
+                    let addr = args[1].checked_add(i * 8).ok_or(EFAULT)?;
+                    let iov = read_bytes(bus, addr, 8)?;
+                    let base = u32::from_le_bytes(iov[0..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(iov[4..8].try_into().unwrap());
+                    size = size
+                        .checked_add(len)
+                        .filter(|size| *size <= i32::MAX as u32)
+                        .ok_or(EINVAL)?;
+                    iovs.push((base, len));
+                }
+
+                let mut total = 0;
+                for (base, len) in iovs {
+                    let done = match number {
+                        SYS_READV => self.read(bus, args[0], base, len),
+                        _ => self.write(bus, args[0], base, len),
+                    };
+                    let done = match done {
+                        Ok(done) => done,
+                        Err(_) if total > 0 => break,
+                        Err(errno) => return Err(errno),
+                    };
+                    total += done;
+                    if done < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_NEWFSTATAT | SYS_STAT => {
+                let (path, buf) = match number {
+                    SYS_STAT => (self.path_at(bus, AT_FDCWD as u32, args[0])?, args[1]),
+                    _ => (self.path_at(bus, args[0], args[1])?, args[2]),
+                };
+                let metadata = fs::metadata(path).map_err(errno)?;
+                write_bytes(bus, buf, &metadata_stat(&metadata))?;
+                Ok(0)
+            }
+            SYS_FSTAT => {
+                let stat = match Self::slot(&mut self.files, args[0])? {
+                    Handle::File(file) => metadata_stat(&file.metadata().map_err(errno)?),
+                    _ => stat_bytes(S_IFCHR | 0o620, 0, None),
+                };
+                write_bytes(bus, args[1], &stat)?;
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_CLOCK_GETTIME | SYS_CLOCK_GETTIME64 => {
+                let time = match args[0] {
+                    CLOCK_REALTIME => SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default(),
+                    _ => self.started.elapsed(),
+                };
+
+                // The time64 call has a 64-bit tv_nsec; the old one pads a 32-bit one.
+                let mut ts = time.as_secs().to_le_bytes().to_vec();
+                ts.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+                if number == SYS_CLOCK_GETTIME {
+                    ts[12..].fill(0);
+                }
+                write_bytes(bus, args[1], &ts)?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                if args[0] != 0 {
+                    let mut tv = time.as_secs().to_le_bytes().to_vec();
+                    tv.extend_from_slice(&(time.subsec_micros() as u64).to_le_bytes());
+                    write_bytes(bus, args[0], &tv)?;
+                }
+                Ok(0)
+            }
+            SYS_UNAME => {
+                let mut uts = [0u8; 65 * 6];
+                let fields = ["Linux", "rv801", "6.0.0", "#1", "riscv32", ""];
+                for (i, field) in fields.iter().enumerate() {
+                    uts[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_bytes(bus, args[0], &uts)?;
+                Ok(0)
+            }
+            SYS_BRK => {
+                // Move the break anywhere from its start up to the end of mapped memory;
+                // an impossible request leaves it where it is.
+                let addr = args[0];
+                if addr == self.brk_start
+                    || (addr > self.brk_start && bus.read(addr - 1, 1).is_ok())
+                {
+                    self.brk = addr;
+                }
+                Ok(self.brk)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    // Store a handle in the lowest free descriptor.
+    fn insert(&mut self, handle: Handle) -> u32 {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(handle);
+                fd as u32
+            }
+            None => {
+                self.files.push(Some(handle));
+                (self.files.len() - 1) as u32
+            }
+        }
+    }
+
+    // Directory descriptors aren't tracked, so relative paths must be relative to the
+    // working directory (AT_FDCWD).
+    fn path_at(&self, bus: &mut Bus, dirfd: u32, path: u32) -> Result<PathBuf, i32> {
+        let path = read_cstring(bus, path)?;
+        if dirfd as i32 != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+
+        sandbox_path(&self.root, &path)
+    }
+
+    fn openat(&mut self, bus: &mut Bus, dirfd: u32, path: u32, flags: u32) -> SysResult {
+        let path = self.path_at(bus, dirfd, path)?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            match flags & O_EXCL {
+                0 => options.create(true),
+                _ => options.create_new(true),
+            };
+        }
+
+        let file = options.open(path).map_err(errno)?;
+        Ok(self.insert(Handle::File(file)))
+    }
+
+    fn read(&mut self, bus: &mut Bus, fd: u32, buf: u32, len: u32) -> SysResult {
+        let handle = Self::slot(&mut self.files, fd)?;
+        read_to_guest(bus, handle, &mut self.stdin, buf, len)
+    }
+
+    fn write(&mut self, bus: &mut Bus, fd: u32, buf: u32, len: u32) -> SysResult {
+        let handle = Self::slot(&mut self.files, fd)?;
+        write_from_guest(bus, handle, &mut self.stdout, &mut self.stderr, buf, len)
+    }
+
+    // Takes the table rather than `self` so the stdio streams can be borrowed alongside.
+    fn slot(files: &mut [Option<Handle>], fd: u32) -> Result<&mut Handle, i32> {
+        files
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+}
+
+// Lay out argc, argv, an empty environment and an empty auxiliary vector below `top` the
+// way the Linux loader does, and point sp at argc.
+pub fn setup_stack(cpu: &mut CPU, top: u32, args: &[String]) -> Result<(), BusError> {
+    let mut addr = top;
+    let mut argv = Vec::new();
+    for arg in args.iter().rev() {
+        addr -= arg.len() as u32 + 1;
+        for (i, byte) in arg.bytes().chain([0]).enumerate() {
+            cpu.bus.write(addr + i as u32, 1, byte as u32)?;
+        }
+        argv.push(addr);
+    }
+    argv.reverse();
+
+    // argc, argv[], NULL, envp NULL, AT_NULL
+    let mut words = vec![args.len() as u32];
+    words.extend(argv);
+    words.extend([0, 0, 0, 0]);
+
+    let sp = (addr - 4 * words.len() as u32) & !0xF;
+    for (i, word) in words.iter().enumerate() {
+        cpu.bus.write(sp + 4 * i as u32, 4, *word)?;
+    }
+
+    cpu.regs[2] = sp;
+    Ok(())
+}
@@ -1,12 +1,256 @@
-use crate::cpu::{Interface, CPU};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+
+use crate::asm::{self, AsmError, AsmErrorKind};
+use crate::bus::{Bus, BusError, Device, Irq, Lines};
+use crate::cli::{self, Command, Image, Options};
+use crate::commit::{self, CommitLog, Filter};
+use crate::compliance::{self, Outcome};
+use crate::config::Config;
+use crate::cpu::CPU;
+use crate::csr::{self, Privilege};
+use crate::devices::clint::{self, Clint, Timebase};
+use crate::devices::framebuffer::{self, DumpFormat, Framebuffer};
+use crate::devices::plic::{self, Plic};
+use crate::devices::sound::{self, Sound, WavWriter};
+use crate::devices::uart::{self, Uart};
+use crate::devices::{ram::Ram, rom::Rom};
+use crate::difftest;
+use crate::disasm::{self, Syntax};
+use crate::elf::{Elf, ElfError, Symbol, SymbolTable};
+use crate::gdb::{self, GdbStub, Session};
+use crate::isa::{DecodeError, EncodeError, Instruction, InstructionType, B, I, J, R, U};
+use crate::monitor::{Flow, Monitor};
+use crate::semihost::Semihosting;
+use crate::syscall::{self, Syscalls};
+use crate::trap::Exception;
+use crate::tui::Tui;
+
+// Loading and running programs without going through the CLI.
+trait Interface {
+    fn load(&mut self, instructions: &[u8]);
+
+    // Run until the program stops. Returns the guest's status when it calls exit, 0 when
+    // `exit_on_nop` ends it and 1 when it raises an exception with no trap handler
+    // installed; see `last_trap` for the cause.
+    fn run(&mut self) -> u8;
+
+    #[allow(clippy::wrong_self_convention)]
+    fn from_inst(&mut self, instruction: Vec<u32>) {
+        let bytes = instruction
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        self.load(&bytes);
+    }
+}
+
+impl Interface for CPU {
+    fn load(&mut self, instructions: &[u8]) {
+        self.bus
+            .load(self.pc as u32, instructions)
+            .expect("Program does not fit in memory");
+    }
+
+    fn run(&mut self) -> u8 {
+        loop {
+            if self.step().is_err() {
+                return 1;
+            }
+
+            if let Some(code) = self.exit_code {
+                return code;
+            }
+
+            if self.exit_on_nop && self.last_inst.is_some_and(|inst| inst.is_nop()) {
+                return 0;
+            }
+        }
+    }
+}
+
+// A CPU with the default memory map: 64 KiB of RAM at 0.
+fn new_cpu() -> CPU {
+    CPU::with_config(Config::new()).unwrap()
+}
+
+// Counts up by one on every read; a write sets the count.
+struct CounterDevice {
+    value: u32,
+}
+
+impl Device for CounterDevice {
+    fn read(&mut self, _offset: u32, _size: usize) -> Result<u32, BusError> {
+        let value = self.value;
+        self.value += 1;
+        Ok(value)
+    }
+
+    fn write(&mut self, _offset: u32, _size: usize, value: u32) -> Result<(), BusError> {
+        self.value = value;
+        Ok(())
+    }
+}
+
+// A writer whose output the test can still read after handing it to the CPU.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// An empty scratch directory under the system temp directory.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rv801-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Build a little-endian ELF32 RISC-V executable with one PT_LOAD per segment
+// (address, file bytes, memory size) and a symbol table of (name, address, size).
+fn build_elf(
+    entry: u32,
+    segments: &[(u32, Vec<u8>, u32)],
+    symbols: &[(&str, u32, u32)],
+) -> Vec<u8> {
+    let phoff = 52;
+    let mut data_offset = phoff + 32 * segments.len();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[1, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&2u16.to_le_bytes()); // e_type = ET_EXEC
+    header.extend_from_slice(&243u16.to_le_bytes()); // e_machine = EM_RISCV
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&entry.to_le_bytes());
+    header.extend_from_slice(&(phoff as u32).to_le_bytes());
+    let shoff_pos = header.len();
+    header.extend_from_slice(&0u32.to_le_bytes()); // e_shoff, patched below
+    header.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    header.extend_from_slice(&52u16.to_le_bytes());
+    header.extend_from_slice(&32u16.to_le_bytes());
+    header.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    header.extend_from_slice(&40u16.to_le_bytes());
+    header.extend_from_slice(&3u16.to_le_bytes()); // null, .symtab, .strtab
+    header.extend_from_slice(&0u16.to_le_bytes());
+
+    let mut body = Vec::new();
+    for (addr, bytes, mem_size) in segments {
+        for field in [
+            1,
+            data_offset as u32,
+            *addr,
+            *addr,
+            bytes.len() as u32,
+            *mem_size,
+            7,
+            4,
+        ] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        body.extend_from_slice(bytes);
+        data_offset += bytes.len();
+    }
+
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 16];
+    for (name, addr, size) in symbols {
+        symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+        symtab.extend_from_slice(&addr.to_le_bytes());
+        symtab.extend_from_slice(&size.to_le_bytes());
+        symtab.extend_from_slice(&[0x12, 0, 1, 0]); // STB_GLOBAL | STT_FUNC, shndx 1
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+
+    let symtab_offset = data_offset;
+    let strtab_offset = symtab_offset + symtab.len();
+    let shoff = strtab_offset + strtab.len();
+    header[shoff_pos..shoff_pos + 4].copy_from_slice(&(shoff as u32).to_le_bytes());
+
+    let mut elf = header;
+    elf.extend_from_slice(&body);
+    elf.extend_from_slice(&symtab);
+    elf.extend_from_slice(&strtab);
+    elf.extend_from_slice(&[0; 40]);
+    let sections = [
+        (2u32, symtab_offset, symtab.len(), 2u32), // .symtab, linked to section 2
+        (3u32, strtab_offset, strtab.len(), 0u32), // .strtab
+    ];
+    for (kind, offset, size, link) in sections {
+        for field in [0, kind, 0, 0, offset as u32, size as u32, link, 0, 4, 16] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+    elf
+}
+
+fn words(insts: &[u32]) -> Vec<u8> {
+    insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+
+fn assemble_words(source: &str) -> Vec<u32> {
+    asm::assemble(source, 0)
+        .unwrap()
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect()
+}
+
+// The GDB side of a remote serial protocol connection, for scripting a session.
+struct GdbClient(TcpStream);
+
+impl GdbClient {
+    fn request(&mut self, data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.0, "${}#{:02x}", data, sum).unwrap();
+
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        loop {
+            self.0.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'$' => reply.clear(),
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+
+        let mut checksum = [0; 2];
+        self.0.read_exact(&mut checksum).unwrap();
+        self.0.write_all(b"+").unwrap();
+        // Drop the stub's ack of our packet, which precedes the reply.
+        String::from_utf8(reply)
+            .unwrap()
+            .trim_start_matches('+')
+            .to_string()
+    }
+}
 
 fn init_cpu_test() -> CPU {
-    let mut cpu = CPU::new();
+    let mut cpu = new_cpu();
     cpu.exit_on_nop = true;
     cpu
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use super::*;
 
@@ -22,7 +266,2532 @@ mod tests {
         assert_eq!(cpu.regs[2], 110);
         assert_eq!(cpu.regs[3], 109);
         assert_eq!(cpu.regs[4], 2047);
-        assert_eq!(cpu.regs[5], 0b100000000001); // -2047
-        assert_eq!(cpu.regs[6], 0b011111111111); // 2047
+        assert_eq!(cpu.regs[5], 2048);
+        assert_eq!(cpu.regs[6], -2048i32 as u32);
+    }
+
+    #[test]
+    fn test_lui_auipc() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x123450b7, // lui x1, 0x12345
+            0x00001117, // auipc x2, 1
+            0x00000213, // addi x4, x0, 0
+            0xfffff197, // auipc x3, 0xfffff
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[1], 0x12345000);
+        assert_eq!(cpu.regs[2], 0x00001004);
+        assert_eq!(cpu.regs[3], 0xfffff00c);
+    }
+
+    #[test]
+    fn test_jal() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00c000ef, // jal x1, 12
+            0x00100113, // addi x2, x0, 1
+            0x0100006f, // jal x0, 16
+            0x00300193, // addi x3, x0, 3
+            0xff9ff2ef, // jal x5, -8
+            0x00400213, // addi x4, x0, 4
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[1], 4);
+        assert_eq!(cpu.regs[2], 0);
+        assert_eq!(cpu.regs[3], 3);
+        assert_eq!(cpu.regs[4], 0);
+        assert_eq!(cpu.regs[5], 20);
+    }
+
+    #[test]
+    fn test_jalr() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00d00093, // addi x1, x0, 13
+            0x00308167, // jalr x2, x1, 3
+            0x00100193, // addi x3, x0, 1
+            0x00218193, // addi x3, x3, 2
+            0x00400213, // addi x4, x0, 4
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 8);
+        assert_eq!(cpu.regs[3], 0);
+        assert_eq!(cpu.regs[4], 4);
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xfff00093, // addi x1, x0, -1
+            0x00100113, // addi x2, x0, 1
+            0x00208463, // beq x1, x2, 8
+            0x00150513, // addi x10, x10, 1
+            0x00209463, // bne x1, x2, 8
+            0x00250513, // addi x10, x10, 2
+            0x0020c463, // blt x1, x2, 8
+            0x00450513, // addi x10, x10, 4
+            0x0020d463, // bge x1, x2, 8
+            0x00850513, // addi x10, x10, 8
+            0x0020e463, // bltu x1, x2, 8
+            0x01050513, // addi x10, x10, 16
+            0x0020f463, // bgeu x1, x2, 8
+            0x02050513, // addi x10, x10, 32
+        ]);
+        cpu.run();
+
+        // Only the not-taken branches fall through to their addi.
+        assert_eq!(cpu.regs[10], 1 + 8 + 16);
+    }
+
+    #[test]
+    fn test_backward_branch() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00a00093, // addi x1, x0, 10
+            0x00310113, // addi x2, x2, 3
+            0xfff08093, // addi x1, x1, -1
+            0xfe009ce3, // bne x1, x0, -8
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[1], 0);
+        assert_eq!(cpu.regs[2], 30);
+    }
+
+    #[test]
+    fn test_loads_stores() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x000080b7, // lui x1, 0x8
+            0xffe00113, // addi x2, x0, -2
+            0x0020a023, // sw x2, 0(x1)
+            0x00009223, // sh x0, 4(x1)
+            0x07f00193, // addi x3, x0, 0x7f
+            0x003082a3, // sb x3, 5(x1)
+            0x0000a203, // lw x4, 0(x1)
+            0x00009283, // lh x5, 0(x1)
+            0x0000d303, // lhu x6, 0(x1)
+            0x00108383, // lb x7, 1(x1)
+            0x0010c403, // lbu x8, 1(x1)
+            0x0040a483, // lw x9, 4(x1)
+            0xfe208fa3, // sb x2, -1(x1)
+            0xfff0c503, // lbu x10, -1(x1)
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[4], 0xfffffffe);
+        assert_eq!(cpu.regs[5], 0xfffffffe);
+        assert_eq!(cpu.regs[6], 0x0000fffe);
+        assert_eq!(cpu.regs[7], 0xffffffff);
+        assert_eq!(cpu.regs[8], 0x000000ff);
+        assert_eq!(cpu.regs[9], 0x00007f00);
+        assert_eq!(cpu.regs[10], 0x000000fe);
+    }
+
+    #[test]
+    fn test_slti_sltiu() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xffb00093, // addi x1, x0, -5
+            0xffc0a113, // slti x2, x1, -4
+            0xffb0a193, // slti x3, x1, -5
+            0xffc0b213, // sltiu x4, x1, -4
+            0x00103293, // sltiu x5, x0, 1
+            0x0010b313, // sltiu x6, x1, 1
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 1);
+        assert_eq!(cpu.regs[3], 0);
+        assert_eq!(cpu.regs[4], 1);
+        assert_eq!(cpu.regs[5], 1);
+        assert_eq!(cpu.regs[6], 0);
+    }
+
+    #[test]
+    fn test_logical_immediates() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x05a00093, // addi x1, x0, 0x5a
+            0xfff0c113, // xori x2, x1, -1
+            0x0f00e193, // ori x3, x1, 0x0f0
+            0x0f00f213, // andi x4, x1, 0x0f0
+            0xf0017293, // andi x5, x2, -256
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 0xffffffa5);
+        assert_eq!(cpu.regs[3], 0xfa);
+        assert_eq!(cpu.regs[4], 0x50);
+        assert_eq!(cpu.regs[5], 0xffffff00);
+    }
+
+    #[test]
+    fn test_shift_immediates() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xff000093, // addi x1, x0, -16
+            0x00409113, // slli x2, x1, 4
+            0x01c0d193, // srli x3, x1, 28
+            0x4020d213, // srai x4, x1, 2
+            0x01f09293, // slli x5, x1, 31
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 0xffffff00);
+        assert_eq!(cpu.regs[3], 0xf);
+        assert_eq!(cpu.regs[4], -4i32 as u32);
+        assert_eq!(cpu.regs[5], 0);
+    }
+
+    #[test]
+    fn test_add_sub() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00700093, // addi x1, x0, 7
+            0xffd00113, // addi x2, x0, -3
+            0x002081b3, // add x3, x1, x2
+            0x40208233, // sub x4, x1, x2
+            0x401102b3, // sub x5, x2, x1
+            0x80000337, // lui x6, 0x80000
+            0x006303b3, // add x7, x6, x6
+            0x40600433, // sub x8, x0, x6
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[3], 4);
+        assert_eq!(cpu.regs[4], 10);
+        assert_eq!(cpu.regs[5], -10i32 as u32);
+        assert_eq!(cpu.regs[7], 0);
+        assert_eq!(cpu.regs[8], 0x80000000);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xf0000093, // addi x1, x0, -256
+            0x02400113, // addi x2, x0, 36
+            0x002091b3, // sll x3, x1, x2
+            0x0020d233, // srl x4, x1, x2
+            0x4020d2b3, // sra x5, x1, x2
+            0x01f00313, // addi x6, x0, 31
+            0x0060d3b3, // srl x7, x1, x6
+            0x4060d433, // sra x8, x1, x6
+        ]);
+        cpu.run();
+
+        // Only the low five bits of rs2 count, so 36 shifts by 4.
+        assert_eq!(cpu.regs[3], 0xfffff000);
+        assert_eq!(cpu.regs[4], 0x0ffffff0);
+        assert_eq!(cpu.regs[5], 0xfffffff0);
+        assert_eq!(cpu.regs[7], 1);
+        assert_eq!(cpu.regs[8], 0xffffffff);
+    }
+
+    #[test]
+    fn test_slt_sltu() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xfff00093, // addi x1, x0, -1
+            0x00100113, // addi x2, x0, 1
+            0x0020a1b3, // slt x3, x1, x2
+            0x00112233, // slt x4, x2, x1
+            0x0020b2b3, // sltu x5, x1, x2
+            0x00113333, // sltu x6, x2, x1
+            0x002033b3, // sltu x7, x0, x2
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[3], 1);
+        assert_eq!(cpu.regs[4], 0);
+        assert_eq!(cpu.regs[5], 0);
+        assert_eq!(cpu.regs[6], 1);
+        assert_eq!(cpu.regs[7], 1);
+    }
+
+    #[test]
+    fn test_logical() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x06c00093, // addi x1, x0, 0x6c
+            0x03a00113, // addi x2, x0, 0x3a
+            0x0020c1b3, // xor x3, x1, x2
+            0x0020e233, // or x4, x1, x2
+            0x0020f2b3, // and x5, x1, x2
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[3], 0x56);
+        assert_eq!(cpu.regs[4], 0x7e);
+        assert_eq!(cpu.regs[5], 0x28);
+    }
+
+    #[test]
+    fn test_x0_hardwired() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00500013, // addi x0, x0, 5
+            0x00001037, // lui x0, 1
+            0x000000b3, // add x1, x0, x0
+            0x0040006f, // jal x0, 4
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[0], 0);
+        assert_eq!(cpu.regs[1], 0);
+    }
+
+    #[test]
+    fn test_fence() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00100093, // addi x1, x0, 1
+            0x0ff0000f, // fence
+            0x0000100f, // fence.i
+            0x00200113, // addi x2, x0, 2
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[1], 1);
+        assert_eq!(cpu.regs[2], 2);
+        assert_eq!(cpu.pc, 20);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decode = |raw: u32| Instruction::try_from(raw).map(|inst| inst.inst);
+
+        assert_eq!(
+            decode(0xffffffff).unwrap_err(),
+            DecodeError::UnknownOpcode(0x7f)
+        );
+        assert_eq!(
+            decode(0x00000100).unwrap_err(),
+            DecodeError::UnknownOpcode(0)
+        );
+        assert_eq!(decode(0).unwrap_err(), DecodeError::UnknownOpcode(0));
+        assert_eq!(
+            decode(0x0c2081b3).unwrap_err(),
+            DecodeError::InvalidFunct7(0b0000110)
+        );
+        assert_eq!(
+            decode(0x0000b203).unwrap_err(),
+            DecodeError::InvalidFunct3(0b011)
+        );
+        assert_eq!(
+            decode(0x02009113).unwrap_err(),
+            DecodeError::InvalidFunct7(0b0000001)
+        );
+        assert_eq!(
+            decode(0x000000f3).unwrap_err(),
+            DecodeError::ReservedEncoding
+        );
+        assert_eq!(
+            decode(0x00200073).unwrap_err(),
+            DecodeError::ReservedEncoding
+        );
+    }
+
+    #[test]
+    fn test_illegal_instruction_halts() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00100093, // addi x1, x0, 1
+            0xffffffff, // illegal
+            0x00200113, // addi x2, x0, 2
+        ]);
+
+        assert_ne!(cpu.run(), 0);
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(
+            cpu.last_trap,
+            Some(Exception::IllegalInstruction(0xffffffff))
+        );
+        assert_eq!(cpu.regs[1], 1);
+        assert_eq!(cpu.regs[2], 0);
+    }
+
+    #[test]
+    fn test_mul_div() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xff900093, // addi x1, x0, -7
+            0x00300113, // addi x2, x0, 3
+            0x022081b3, // mul x3, x1, x2
+            0x02209233, // mulh x4, x1, x2
+            0x0220a2b3, // mulhsu x5, x1, x2
+            0x0220b333, // mulhu x6, x1, x2
+            0x0220c3b3, // div x7, x1, x2
+            0x0220d433, // divu x8, x1, x2
+            0x0220e4b3, // rem x9, x1, x2
+            0x0220f533, // remu x10, x1, x2
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[3], -21i32 as u32);
+        assert_eq!(cpu.regs[4], 0xffffffff);
+        assert_eq!(cpu.regs[5], 0xffffffff);
+        assert_eq!(cpu.regs[6], 2);
+        assert_eq!(cpu.regs[7], -2i32 as u32);
+        assert_eq!(cpu.regs[8], 0x55555553);
+        assert_eq!(cpu.regs[9], -1i32 as u32);
+        assert_eq!(cpu.regs[10], 0);
+    }
+
+    #[test]
+    fn test_div_by_zero_and_overflow() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x00700093, // addi x1, x0, 7
+            0x0200c133, // div x2, x1, x0
+            0x0200d1b3, // divu x3, x1, x0
+            0x0200e233, // rem x4, x1, x0
+            0x0200f2b3, // remu x5, x1, x0
+            0x80000337, // lui x6, 0x80000
+            0xfff00393, // addi x7, x0, -1
+            0x02734433, // div x8, x6, x7
+            0x027364b3, // rem x9, x6, x7
+            0x02631533, // mulh x10, x6, x6
+            0x0273b5b3, // mulhu x11, x7, x7
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 0xffffffff);
+        assert_eq!(cpu.regs[3], 0xffffffff);
+        assert_eq!(cpu.regs[4], 7);
+        assert_eq!(cpu.regs[5], 7);
+        assert_eq!(cpu.regs[8], 0x80000000);
+        assert_eq!(cpu.regs[9], 0);
+        assert_eq!(cpu.regs[10], 0x40000000);
+        assert_eq!(cpu.regs[11], 0xfffffffe);
+    }
+
+    #[test]
+    fn test_csr_read_write() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x05500093, // addi x1, x0, 0x55
+            0x34009173, // csrrw x2, mscratch, x1
+            0x340011f3, // csrrw x3, mscratch, x0
+            0x30102273, // csrrs x4, misa, x0
+            0x340fd073, // csrrwi x0, mscratch, 0x1f
+            0x3401f2f3, // csrrci x5, mscratch, 0x3
+            0x34006373, // csrrsi x6, mscratch, 0
+            0x01000393, // addi x7, x0, 0x10
+            0x3403b473, // csrrc x8, mscratch, x7
+            0x340024f3, // csrrs x9, mscratch, x0
+            0xf1402573, // csrrs x10, mhartid, x0
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 0);
+        assert_eq!(cpu.regs[3], 0x55);
+        assert_eq!(cpu.regs[4], 0x40001100);
+        assert_eq!(cpu.regs[5], 0x1f);
+        assert_eq!(cpu.regs[6], 0x1c);
+        assert_eq!(cpu.regs[8], 0x1c);
+        assert_eq!(cpu.regs[9], 0x0c);
+        assert_eq!(cpu.regs[10], 0);
+    }
+
+    #[test]
+    fn test_csr_warl_and_counters() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0xfff00093, // addi x1, x0, -1
+            0x30509073, // csrrw x0, mtvec, x1
+            0x30502173, // csrrs x2, mtvec, x0
+            0x34109073, // csrrw x0, mepc, x1
+            0x341021f3, // csrrs x3, mepc, x0
+            0x30009073, // csrrw x0, mstatus, x1
+            0x30002273, // csrrs x4, mstatus, x0
+            0xc02022f3, // csrrs x5, instret, x0
+            0xc0002373, // csrrs x6, cycle, x0
+            0xc82023f3, // csrrs x7, instreth, x0
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 0xfffffffd);
+        assert_eq!(cpu.regs[3], 0xfffffffc);
+        assert_eq!(
+            cpu.regs[4],
+            csr::MSTATUS_MPP | csr::MSTATUS_MPIE | csr::MSTATUS_MIE
+        );
+        assert_eq!(cpu.regs[5], 7);
+        assert_eq!(cpu.regs[6], 8);
+        assert_eq!(cpu.regs[7], 0);
+    }
+
+    #[test]
+    fn test_csr_illegal_access() {
+        let illegal = [
+            0xf14010f3, // csrrw x1, mhartid, x0
+            0x7ff020f3, // csrrs x1, 0x7ff, x0
+            0xc000e0f3, // csrrsi x1, cycle, 1
+        ];
+
+        for inst in illegal {
+            let mut cpu = init_cpu_test();
+            cpu.from_inst(vec![inst]);
+
+            assert_ne!(cpu.run(), 0);
+            assert_eq!(cpu.last_trap, Some(Exception::IllegalInstruction(inst)));
+        }
+
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![0xc00020f3]); // csrrs x1, cycle, x0
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.last_trap, None);
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut csrs = csr::CsrFile::new();
+
+        assert_eq!(
+            csrs.read(csr::MSCRATCH, csr::Privilege::User),
+            Err(csr::CsrError::Privileged(csr::MSCRATCH))
+        );
+        assert_eq!(csrs.read(csr::CYCLE, csr::Privilege::User), Ok(0));
+        assert_eq!(
+            csrs.write(csr::INSTRET, 1, csr::Privilege::Machine),
+            Err(csr::CsrError::ReadOnly(csr::INSTRET))
+        );
+    }
+
+    #[test]
+    fn test_ecall_trap_and_mret() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x02100093, // addi x1, x0, 33 (vectored mode, base 32)
+            0x30509073, // csrrw x0, mtvec, x1
+            0x30046073, // csrrsi x0, mstatus, 8
+            0x00000073, // ecall
+            0x00100513, // addi x10, x0, 1
+            0x0240006f, // jal x0, 36
+            0x00000013, // nop (unused)
+            0x00000013, // nop (unused)
+            0x34202173, // csrrs x2, mcause, x0
+            0x341021f3, // csrrs x3, mepc, x0
+            0x30002273, // csrrs x4, mstatus, x0
+            0x00418193, // addi x3, x3, 4
+            0x34119073, // csrrw x0, mepc, x3
+            0x30200073, // mret
+        ]);
+
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[2], 11);
+        assert_eq!(cpu.regs[3], 16);
+        assert_eq!(cpu.regs[4], csr::MSTATUS_MPP | csr::MSTATUS_MPIE);
+        assert_eq!(cpu.regs[10], 1);
+        assert_eq!(
+            cpu.csrs.mstatus,
+            csr::MSTATUS_MPP | csr::MSTATUS_MPIE | csr::MSTATUS_MIE
+        );
+        assert_eq!(
+            cpu.last_trap,
+            Some(Exception::EnvironmentCall(Privilege::Machine))
+        );
+    }
+
+    #[test]
+    fn test_trap_csrs() {
+        let mut cpu = init_cpu_test();
+        cpu.from_inst(vec![
+            0x01000093, // addi x1, x0, 16
+            0x30509073, // csrrw x0, mtvec, x1
+            0xffc02103, // lw x2, -4(x0)
+        ]);
+
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.csrs.mepc, 8);
+        assert_eq!(cpu.csrs.mcause, 5);
+        assert_eq!(cpu.csrs.mtval, 0xfffffffc);
+        assert_eq!(cpu.pc, 20);
+    }
+
+    #[test]
+    fn test_unhandled_exceptions() {
+        let cases: [(Vec<u32>, Exception, usize); 8] = [
+            (
+                vec![0x00200093, 0x0000a103], // addi x1, x0, 2; lw x2, 0(x1)
+                Exception::LoadAddressMisaligned(2),
+                4,
+            ),
+            (
+                vec![0x00200093, 0xfe209fa3], // addi x1, x0, 2; sh x2, -1(x1)
+                Exception::StoreAddressMisaligned(1),
+                4,
+            ),
+            (
+                vec![0x000100b7, 0x0000a103], // lui x1, 0x10; lw x2, 0(x1)
+                Exception::LoadAccessFault(0x10000),
+                4,
+            ),
+            (
+                vec![0xfe002e23], // sw x0, -4(x0)
+                Exception::StoreAccessFault(0xfffffffc),
+                0,
+            ),
+            (
+                vec![0x006000e7], // jalr x1, x0, 6
+                Exception::InstructionAddressMisaligned(6),
+                0,
+            ),
+            (
+                vec![0x000100b7, 0x00008067], // lui x1, 0x10; jalr x0, x1, 0
+                Exception::InstructionAccessFault(0x10000),
+                0x10000,
+            ),
+            (vec![0x00100073], Exception::Breakpoint(0), 0), // ebreak
+            (
+                vec![0x00000073], // ecall
+                Exception::EnvironmentCall(Privilege::Machine),
+                0,
+            ),
+        ];
+
+        for (program, exception, pc) in cases {
+            let mut cpu = init_cpu_test();
+            cpu.from_inst(program);
+
+            assert_eq!(cpu.run(), 1);
+            assert_eq!(cpu.last_trap, Some(exception));
+            assert_eq!(cpu.pc, pc);
+        }
+    }
+
+    #[test]
+    fn test_custom_device() {
+        let mut cpu = init_cpu_test();
+        cpu.bus
+            .attach(0x20000, 4, Box::new(CounterDevice { value: 0 }))
+            .unwrap();
+        cpu.from_inst(vec![
+            0x000200b7, // lui x1, 0x20
+            0x00500113, // addi x2, x0, 5
+            0x0020a023, // sw x2, 0(x1)
+            0x0000a183, // lw x3, 0(x1)
+            0x0000a203, // lw x4, 0(x1)
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.regs[3], 5);
+        assert_eq!(cpu.regs[4], 6);
+    }
+
+    #[test]
+    fn test_rom_is_read_only() {
+        let mut cpu = init_cpu_test();
+        cpu.bus
+            .attach(0x30000, 4, Box::new(Rom::new(vec![0x78, 0x56, 0x34, 0x12])))
+            .unwrap();
+        cpu.from_inst(vec![
+            0x000300b7, // lui x1, 0x30
+            0x0000a103, // lw x2, 0(x1)
+            0x0020a023, // sw x2, 0(x1)
+        ]);
+
+        assert_eq!(cpu.run(), 1);
+        assert_eq!(cpu.regs[2], 0x12345678);
+        assert_eq!(cpu.last_trap, Some(Exception::StoreAccessFault(0x30000)));
+    }
+
+    #[test]
+    fn test_bus_mapping() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x100, Box::new(Ram::new(0x100)))
+            .unwrap();
+
+        assert_eq!(
+            bus.attach(0x1080, 0x100, Box::new(Ram::new(0x100))),
+            Err(BusError::Overlap)
+        );
+        assert_eq!(bus.write(0x10fc, 4, 0xdeadbeef), Ok(()));
+        assert_eq!(bus.read(0x10fe, 2), Ok(0xdead));
+        assert_eq!(bus.read(0x10fe, 4), Err(BusError::Unmapped));
+        assert_eq!(bus.read(0x0fff, 1), Err(BusError::Unmapped));
+    }
+
+    #[test]
+    fn test_config_memory_map() {
+        let config = Config::new()
+            .ram(0x8000_0000, 16 << 20)
+            .reset_pc(0x8000_0000)
+            .rom(0x10000, vec![0xef, 0xbe, 0xad, 0xde]);
+        let mut cpu = CPU::with_config(config).unwrap();
+        cpu.exit_on_nop = true;
+        cpu.from_inst(vec![
+            0x00000097, // auipc x1, 0
+            0x01000137, // lui x2, 0x1000
+            0x002081b3, // add x3, x1, x2
+            0x02a00213, // addi x4, x0, 42
+            0xfe41ae23, // sw x4, -4(x3)
+            0xffc1a283, // lw x5, -4(x3)
+            0x00010337, // lui x6, 0x10
+            0x00032383, // lw x7, 0(x6)
+            0x0000a403, // lw x8, 0(x1)
+        ]);
+
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[1], 0x8000_0000);
+        assert_eq!(cpu.regs[5], 42);
+        assert_eq!(cpu.regs[7], 0xdeadbeef);
+        assert_eq!(cpu.regs[8], 0x00000097);
+        assert_eq!(cpu.bus.read(0, 4), Err(BusError::Unmapped));
+    }
+
+    #[test]
+    fn test_config_large_ram() {
+        let config = Config::new().ram(0x8000_0000, 512 << 20);
+        let mut cpu = CPU::with_config(config).unwrap();
+
+        assert_eq!(cpu.bus.write(0x9fff_fffc, 4, 1), Ok(()));
+        assert_eq!(cpu.bus.read(0x9fff_fffc, 4), Ok(1));
+    }
+
+    #[test]
+    fn test_config_overlap() {
+        let config = Config::new().rom(0xff00, vec![0; 0x200]);
+
+        assert!(matches!(CPU::with_config(config), Err(BusError::Overlap)));
+    }
+
+    #[test]
+    fn test_elf_load() {
+        let text = words(&[
+            0x00000097, // auipc x1, 0
+            0x1000a103, // lw x2, 0x100(x1)
+            0x1040a183, // lw x3, 0x104(x1)
+        ]);
+        let elf = build_elf(
+            0x8000_0000,
+            &[
+                (0x8000_0000, text, 12),
+                (0x8000_0100, vec![0x78, 0x56, 0x34, 0x12], 8),
+            ],
+            &[("_start", 0x8000_0000, 12), ("counter", 0x8000_0104, 4)],
+        );
+        let elf = Elf::parse(&elf).unwrap();
+
+        let config = Config::new().ram(0x8000_0000, 0x10000);
+        let mut cpu = CPU::with_config(config).unwrap();
+        cpu.exit_on_nop = true;
+        cpu.bus.write(0x8000_0104, 4, 0xffffffff).unwrap();
+        cpu.load_elf(&elf).unwrap();
+
+        assert_eq!(cpu.pc, 0x8000_0000);
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[2], 0x12345678);
+        assert_eq!(cpu.regs[3], 0); // .bss is zeroed
+
+        let (symbol, offset) = cpu.symbols.lookup(0x8000_0008).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("_start", 8));
+        let (symbol, offset) = cpu.symbols.lookup(0x8000_0107).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("counter", 3));
+        assert!(cpu.symbols.lookup(0x8000_0010).is_none());
+    }
+
+    #[test]
+    fn test_elf_errors() {
+        let elf = build_elf(0, &[], &[]);
+
+        let mut wrong = elf.clone();
+        wrong[4] = 2;
+        assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::WrongClass(2));
+
+        let mut wrong = elf.clone();
+        wrong[5] = 2;
+        assert_eq!(
+            Elf::parse(&wrong).unwrap_err(),
+            ElfError::WrongEndianness(2)
+        );
+
+        let mut wrong = elf.clone();
+        wrong[18] = 62; // EM_X86_64
+        assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::WrongMachine(62));
+
+        let mut wrong = elf.clone();
+        wrong[16] = 3; // ET_DYN
+        assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::NotExecutable(3));
+
+        let mut wrong = elf.clone();
+        wrong[36] = 1; // EF_RISCV_RVC
+        assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::Compressed);
+
+        assert_eq!(Elf::parse(b"3e800093").unwrap_err(), ElfError::BadMagic);
+        assert_eq!(Elf::parse(&elf[..40]).unwrap_err(), ElfError::Truncated);
+
+        let elf = build_elf(0, &[(0x20000, vec![0; 4], 4)], &[]);
+        let mut cpu = init_cpu_test();
+        assert_eq!(
+            cpu.load_elf(&Elf::parse(&elf).unwrap()).unwrap_err(),
+            ElfError::Load(0x20000, BusError::Unmapped)
+        );
+    }
+
+    #[test]
+    fn test_asm_encodings() {
+        let words = assemble_words(
+            "
+            addi ra, zero, 1000
+            addi x2, x1, 99       # numeric register names
+            sub sp, ra, sp
+            lw a0, 8(sp)
+            sw a0, -4(s0)
+            srai t0, t1, 3
+            lui a0, 0x12345
+            mul a0, a1, a2
+            csrrw t0, mscratch, t1
+            ecall
+            ebreak
+            mret
+            fence
+            ",
+        );
+
+        assert_eq!(
+            words,
+            vec![
+                0x3e800093, 0x06308113, 0x40208133, 0x00812503, 0xfea42e23, 0x40335293, 0x12345537,
+                0x02c58533, 0x340312f3, 0x00000073, 0x00100073, 0x30200073, 0x0ff0000f,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asm_labels() {
+        let source = "
+                li t0, 10
+                li a0, 0
+            loop:
+                beqz t0, done       # forward reference
+                add a0, a0, t0
+                addi t0, t0, -1
+                j loop
+            done:
+                la t1, value
+                lw a1, 0(t1)
+                lui t2, %hi(value)
+                lw a2, %lo(value)(t2)
+                li a3, 0x12345678
+                call func
+                nop
+            func:
+                li a4, -1
+                ret
+
+                .zero 0x800         # push value far enough for a negative %lo
+                .align 2
+            value:
+                .word 0xdeadbeef
+        ";
+
+        let config = Config::new()
+            .ram(0x8000_0000, 0x10000)
+            .reset_pc(0x8000_0000);
+        let mut cpu = CPU::with_config(config).unwrap();
+        cpu.exit_on_nop = true;
+        cpu.load(&asm::assemble(source, 0x8000_0000).unwrap());
+
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[10], 55);
+        assert_eq!(cpu.regs[11], 0xdeadbeef);
+        assert_eq!(cpu.regs[12], 0xdeadbeef);
+        assert_eq!(cpu.regs[13], 0x12345678);
+        assert_eq!(cpu.regs[14], 0xffffffff);
+    }
+
+    #[test]
+    fn test_asm_errors() {
+        let error = |source| asm::assemble(source, 0).unwrap_err();
+        let at = |line, column, kind| AsmError { line, column, kind };
+
+        assert_eq!(
+            error("addi a0, a0, 5000"),
+            at(1, 14, AsmErrorKind::OutOfRange(5000))
+        );
+        assert_eq!(
+            error("nop\n  add a0, q1, a2"),
+            at(2, 11, AsmErrorKind::UnknownRegister("q1".to_string()))
+        );
+        assert_eq!(
+            error("j nowhere"),
+            at(1, 3, AsmErrorKind::UndefinedSymbol("nowhere".to_string()))
+        );
+        assert_eq!(
+            error("  frob a0"),
+            at(1, 3, AsmErrorKind::UnknownMnemonic("frob".to_string()))
+        );
+        assert_eq!(
+            error("a: nop\na: nop"),
+            at(2, 1, AsmErrorKind::DuplicateLabel("a".to_string()))
+        );
+        assert_eq!(
+            error("add a0, a1"),
+            at(1, 1, AsmErrorKind::OperandCount(3, 2))
+        );
+        assert_eq!(
+            error("beq a0, a1, 3"),
+            at(1, 13, AsmErrorKind::MisalignedOffset(3))
+        );
+        assert_eq!(
+            error("add a0, , a1"),
+            at(1, 9, AsmErrorKind::MissingOperand)
+        );
+
+        assert_eq!(
+            error("addi a0, a0, 5000").to_string(),
+            "1:14: Value out of range: 5000"
+        );
+    }
+
+    #[test]
+    fn test_disasm_display() {
+        let source = "
+            addi a0, zero, 1000
+            sub sp, ra, sp
+            lw a0, -8(sp)
+            sw a0, 12(s0)
+            srai t0, t1, 3
+            lui a0, 0x12345
+            csrrs t0, mstatus, zero
+            csrrwi zero, 0x7c0, 5
+            fence rw, w
+            ecall
+        ";
+
+        let listing: Vec<String> = assemble_words(source)
+            .into_iter()
+            .map(|raw| Instruction::try_from(raw).unwrap().to_string())
+            .collect();
+
+        assert_eq!(
+            listing,
+            vec![
+                "addi a0, zero, 1000",
+                "sub sp, ra, sp",
+                "lw a0, -8(sp)",
+                "sw a0, 12(s0)",
+                "srai t0, t1, 3",
+                "lui a0, 0x12345",
+                "csrrs t0, mstatus, zero",
+                "csrrwi zero, 0x7c0, 5",
+                "fence rw, w",
+                "ecall",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disasm_syntax() {
+        let source = "
+            addi zero, zero, 0
+            addi a0, zero, -5
+            addi s1, a1, 0
+            jal zero, 4             # targets are addresses
+            jalr zero, 0(ra)
+            beq a0, a1, 36
+        ";
+        let words = assemble_words(source);
+        let format =
+            |raw, pc, syntax| disasm::format(&Instruction::try_from(raw).unwrap(), pc, syntax);
+
+        let pseudo = Syntax {
+            pseudo: true,
+            abi_names: true,
+        };
+        let numeric = Syntax {
+            pseudo: false,
+            abi_names: false,
+        };
+
+        let listing: Vec<String> = words.iter().map(|raw| format(*raw, None, pseudo)).collect();
+        assert_eq!(
+            listing,
+            vec![
+                "nop",
+                "li a0, -5",
+                "mv s1, a1",
+                "j -8",
+                "ret",
+                "beq a0, a1, 16"
+            ]
+        );
+
+        assert_eq!(format(words[2], None, numeric), "addi x9, x11, 0");
+        assert_eq!(
+            format(words[5], Some(0x100), numeric),
+            "beq x10, x11, 0x110"
+        );
+    }
+
+    #[test]
+    fn test_disasm_range() {
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble("li a0, 1\nloop: j loop\n.word 0xffffffff", 0).unwrap());
+
+        let lines = disasm::disassemble_range(&mut cpu.bus, 0, 12, Syntax::default()).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "00000000:  00100513  addi a0, zero, 1",
+                "00000004:  0000006f  jal zero, 0x4",
+                "00000008:  ffffffff  .word 0xffffffff",
+            ]
+        );
+
+        assert_eq!(
+            disasm::disassemble_range(&mut cpu.bus, 0xfffc, 0x10004, Syntax::default()),
+            Err(BusError::Unmapped)
+        );
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let mut words = assemble_words(
+            "
+            lui a0, 0xfffff
+            auipc t0, 1
+            jal ra, 0xffff0
+            jalr zero, -2048(t6)
+            bgeu s11, t5, 0x800
+            lhu a5, 2047(gp)
+            sh a4, -1(sp)
+            sltiu s1, s2, -1
+            srai t3, t4, 31
+            remu a0, a1, a2
+            csrrci zero, mhartid, 31
+            fence i, orw
+            fence.i
+            mret
+            wfi
+            ",
+        );
+        assert_eq!(words.last(), Some(&0x10500073));
+        assert_eq!(
+            Instruction::try_from(0x10500073).unwrap().to_string(),
+            "wfi"
+        );
+        assert_eq!(
+            Instruction::try_from(0x0000100f).unwrap().to_string(),
+            "fence.i"
+        );
+        words.push(0x00000013); // nop
+
+        // Sweep pseudo-random words as well; every one that decodes must re-encode as is.
+        let mut seed = 0x1234_5678u32;
+        for _ in 0..100_000 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            words.push(seed);
+        }
+
+        for raw in words {
+            if let Ok(inst) = Instruction::try_from(raw) {
+                assert_eq!(inst.inst_type.encode(), Ok(raw), "{:#010x} ({})", raw, inst);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_programmatically() {
+        let add = InstructionType::R(R {
+            funct7: 0,
+            rs2: 2,
+            rs1: 1,
+            funct3: 0,
+            rd: 3,
+            opcode: 0b0110011,
+        });
+        assert_eq!(add.encode(), Ok(0x002081b3));
+
+        let addi = |rd, rs1, imm| {
+            InstructionType::I(I {
+                imm,
+                rs1,
+                funct3: 0,
+                rd,
+                opcode: 0b0010011,
+            })
+        };
+        assert_eq!(addi(1, 0, 1000).encode(), Ok(0x3e800093));
+        assert_eq!(
+            addi(32, 0, 0).encode(),
+            Err(EncodeError::InvalidRegister(32))
+        );
+        assert_eq!(
+            addi(1, 0, 2048).encode(),
+            Err(EncodeError::ImmediateOutOfRange(2048))
+        );
+
+        let beq = |imm| {
+            InstructionType::B(B {
+                imm,
+                rs2: 0,
+                rs1: 0,
+                funct3: 0,
+                opcode: 0b1100011,
+            })
+        };
+        assert_eq!(beq(-4096).encode(), Ok(0x80000063));
+        assert_eq!(beq(3).encode(), Err(EncodeError::MisalignedImmediate(3)));
+        assert_eq!(
+            beq(4096).encode(),
+            Err(EncodeError::ImmediateOutOfRange(4096))
+        );
+
+        let lui = InstructionType::U(U {
+            imm: 0x100000,
+            rd: 1,
+            opcode: 0b0110111,
+        });
+        assert_eq!(
+            lui.encode(),
+            Err(EncodeError::ImmediateOutOfRange(0x100000))
+        );
+
+        let jal = InstructionType::J(J {
+            imm: 8,
+            rd: 1,
+            opcode: 0b1101111,
+        });
+        assert_eq!(jal.encode(), Ok(0x008000ef));
+    }
+
+    #[test]
+    fn test_cli_parse() {
+        let parse = |line: &str| {
+            let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+            cli::parse(&args)
+        };
+
+        assert_eq!(
+            parse("run --memory 16M --entry 0x100 --max-instructions 1000 --exit-on-nop a.elf"),
+            Ok(Command::Run(Options {
+                file: "a.elf".to_string(),
+                memory: Some(16 << 20),
+                entry: Some(0x100),
+                max_instructions: Some(1000),
+                exit_on_nop: true,
+                ..Options::default()
+            }))
+        );
+
+        let Ok(Command::Disasm(options)) =
+            parse("disasm --numeric --pseudo --base 0x8000_0000 a.bin")
+        else {
+            panic!("Expected disasm");
+        };
+        assert_eq!(options.base, Some(0x8000_0000));
+        assert!(options.syntax.pseudo && !options.syntax.abi_names);
+
+        assert_eq!(parse(""), Ok(Command::Help));
+        assert!(parse("run").is_err());
+        assert!(parse("run --memory 0 a.bin").is_err());
+        assert!(parse("run --memory 8G a.bin").is_err());
+        assert!(parse("run --entry").is_err());
+        assert!(parse("run --fast a.bin").is_err());
+        assert!(parse("--entry 0 run a.bin").is_err());
+
+        // Everything after the file is passed to the guest.
+        let Ok(Command::Run(options)) = parse("run --syscalls --sandbox /tmp a.elf -v --entry 0")
+        else {
+            panic!("Expected run");
+        };
+        assert!(options.syscalls && !options.semihosting);
+        assert_eq!(options.sandbox.as_deref(), Some("/tmp"));
+        assert_eq!(options.args, vec!["-v", "--entry", "0"]);
+        assert_eq!(options.entry, None);
+        assert!(parse("launch a.bin").is_err());
+
+        let Ok(Command::Run(options)) =
+            parse("run --log-commits --log-range 0x80:0x100 --log-window 10: a.elf")
+        else {
+            panic!("Expected run");
+        };
+        assert!(options.log_commits);
+        assert_eq!(
+            options.log_filter,
+            Filter {
+                start: 0x80,
+                end: Some(0x100),
+                skip: 10,
+                count: None
+            }
+        );
+        assert!(parse("run --log-window 10 a.elf").is_err());
+
+        let Ok(Command::Run(options)) = parse("run --uart stdio --clint wall a.elf") else {
+            panic!("Expected run");
+        };
+        assert_eq!(options.uart.as_deref(), Some("stdio"));
+        assert_eq!(options.clint, Some(Timebase::WallClock));
+        assert!(parse("run --clint cycles a.elf").is_err());
+        assert!(parse("run --plic 0 a.elf").is_err());
+        assert!(parse("run --plic 1024 a.elf").is_err());
+
+        let Ok(Command::Run(options)) = parse("run --rom 0x1000=boot.bin --rom 0x2000=a=b a.elf")
+        else {
+            panic!("Expected run");
+        };
+        assert_eq!(
+            options.roms,
+            vec![
+                (0x1000, "boot.bin".to_string()),
+                (0x2000, "a=b".to_string())
+            ]
+        );
+        assert!(parse("run --rom boot.bin a.elf").is_err());
+    }
+
+    #[test]
+    fn test_cli_run() {
+        let program =
+            asm::assemble("li a0, 3\nloop: addi a0, a0, -1\nbnez a0, loop\nnop", 0).unwrap();
+        let hex: String = program
+            .chunks(4)
+            .map(|word| format!("{:08x}\n", u32::from_le_bytes(word.try_into().unwrap())))
+            .collect();
+
+        // Hex text and the raw binary it describes load the same image.
+        for data in [hex.as_bytes(), &program[..]] {
+            let Ok(Image::Binary(bytes)) = Image::detect(data) else {
+                panic!("Expected a binary image");
+            };
+            assert_eq!(bytes, program);
+        }
+
+        let mut options = Options {
+            exit_on_nop: true,
+            ..Options::default()
+        };
+        let image = Image::Binary(program.clone());
+        let mut cpu = cli::machine(&options, &image).unwrap();
+        assert_eq!(cli::simulate(&mut cpu, &options, false), 0);
+        assert_eq!(cpu.regs[10], 0);
+
+        options.max_instructions = Some(4);
+        let mut cpu = cli::machine(&options, &image).unwrap();
+        assert_eq!(cli::simulate(&mut cpu, &options, false), cli::EXIT_LIMIT);
+
+        // ELF images get RAM at their lowest segment unless told otherwise.
+        let elf = build_elf(0x8000_0000, &[(0x8000_0000, program, 16)], &[]);
+        let image = Image::detect(&elf).unwrap();
+        assert!(matches!(image, Image::Elf(_)));
+        options.max_instructions = None;
+        let mut cpu = cli::machine(&options, &image).unwrap();
+        assert_eq!(cpu.pc, 0x8000_0000);
+        assert_eq!(cli::simulate(&mut cpu, &options, false), 0);
+
+        options.entry = Some(0x8001_0000);
+        let mut cpu = cli::machine(&options, &image).unwrap();
+        assert_eq!(cli::simulate(&mut cpu, &options, false), 1);
+        assert_eq!(
+            cpu.last_trap,
+            Some(Exception::InstructionAccessFault(0x8001_0000))
+        );
+
+        // A boot ROM beside RAM that jumps into the image.
+        let dir = scratch_dir("cli-rom");
+        let rom = dir.join("boot.bin");
+        fs::write(&rom, asm::assemble("li t0, 0x80000000\njr t0", 0).unwrap()).unwrap();
+        options.entry = Some(0x1000);
+        options.roms = vec![(0x1000, rom.to_str().unwrap().to_string())];
+        let mut cpu = cli::machine(&options, &image).unwrap();
+        assert_eq!(cpu.bus.write(0x1000, 4, 0), Err(BusError::ReadOnly));
+        assert_eq!(cli::simulate(&mut cpu, &options, false), 0);
+        assert_eq!(cpu.regs[5], 0x8000_0000);
+
+        options.roms = vec![(0x8000_0000, rom.to_str().unwrap().to_string())];
+        assert!(cli::machine(&options, &image).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_syscalls_files_and_exit() {
+        let source = "
+                li a7, 64           # write(1, msg, 6)
+                li a0, 1
+                la a1, msg
+                li a2, 6
+                ecall
+
+                li a7, 64           # write(7, msg, -1) checks the fd before copying
+                li a0, 7
+                la a1, msg
+                li a2, -1
+                ecall
+                mv s6, a0
+                li t0, 0xfffe       # write(1, end of RAM - 2, 16) writes the 2 mapped bytes
+                li t1, 0x6b6f
+                sh t1, 0(t0)
+                li a7, 64
+                li a0, 1
+                mv a1, t0
+                li a2, 16
+                ecall
+                mv s7, a0
+                li a7, 66           # writev(1, msg, IOV_MAX + 1)
+                li a0, 1
+                la a1, msg
+                li a2, 1025
+                ecall
+                mv s8, a0
+
+                li a7, 56           # openat(AT_FDCWD, path, O_WRONLY | O_CREAT | O_TRUNC)
+                li a0, -100
+                la a1, path
+                li a2, 0x241
+                ecall
+                mv s0, a0
+
+                li a7, 64
+                mv a0, s0
+                la a1, msg
+                li a2, 6
+                ecall
+                li a7, 57           # close
+                mv a0, s0
+                ecall
+
+                li a7, 56           # openat(AT_FDCWD, path, O_RDONLY)
+                li a0, -100
+                la a1, path
+                li a2, 0
+                ecall
+                mv s0, a0
+                li a7, 63           # read(fd, unmapped, -1) faults and consumes nothing
+                mv a0, s0
+                li a1, -16
+                li a2, -1
+                ecall
+                mv s4, a0
+                li a7, 63           # read(fd, buf, -1) stops at the end of the file
+                mv a0, s0
+                la a1, buf
+                li a2, -1
+                ecall
+                mv s5, a0
+
+                li a7, 56           # .. stops at the sandbox root
+                li a0, -100
+                la a1, escape
+                li a2, 0
+                ecall
+                mv s1, a0
+
+                li a7, 214          # brk(0)
+                li a0, 0
+                ecall
+                mv s2, a0
+                li a7, 999
+                ecall
+                mv s3, a0
+
+                li a7, 93           # exit(42)
+                li a0, 42
+                ecall
+                nop
+            msg:
+                .ascii \"hello\\n\"
+            path:
+                .asciz \"/out.txt\"
+            escape:
+                .asciz \"../../out.txt/../../missing\"
+            buf:
+                .zero 8
+        ";
+
+        let sandbox = scratch_dir("syscalls");
+        let stdout = SharedBuffer::default();
+        let mut syscalls = Syscalls::new(&sandbox, 0x4000).unwrap();
+        syscalls.stdout = Box::new(stdout.clone());
+
+        let mut cpu = init_cpu_test();
+        cpu.syscalls = Some(syscalls);
+        cpu.load(&asm::assemble(source, 0).unwrap());
+
+        assert_eq!(cpu.run(), 42);
+        assert_eq!(cpu.exit_code, Some(42));
+        assert_eq!(stdout.0.borrow().as_slice(), b"hello\nok");
+        assert_eq!(fs::read(sandbox.join("out.txt")).unwrap(), b"hello\n");
+        assert_eq!(cpu.regs[9], -2i32 as u32); // ENOENT
+        assert_eq!(cpu.regs[18], 0x4000);
+        assert_eq!(cpu.regs[19], -38i32 as u32); // ENOSYS
+        assert_eq!(cpu.regs[20], -14i32 as u32); // EFAULT
+        assert_eq!(cpu.regs[21], 6);
+        assert_eq!(cpu.regs[22], -9i32 as u32); // EBADF
+        assert_eq!(cpu.regs[23], 2);
+        assert_eq!(cpu.regs[24], -22i32 as u32); // EINVAL
+
+        fs::remove_dir_all(sandbox).unwrap();
+    }
+
+    #[test]
+    fn test_syscalls_stdin_and_stack() {
+        let source = "
+                lw s0, 0(sp)        # argc
+                lw t0, 8(sp)        # argv[1]
+                lbu s1, 0(t0)
+
+                li a7, 63           # read(0, buf, -1)
+                li a0, 0
+                la a1, buf
+                li a2, -1
+                ecall
+                mv s2, a0
+                la t0, buf
+                lbu s3, 0(t0)
+
+                li a7, 80           # fstat(1, stat)
+                li a0, 1
+                la a1, stat
+                ecall
+                la t0, stat
+                lw s4, 16(t0)       # st_mode
+
+                li a7, 62           # lseek on a terminal
+                li a0, 1
+                li a1, 0
+                li a2, 0
+                ecall
+                mv s5, a0
+
+                li a7, 94           # exit_group(3)
+                li a0, 3
+                ecall
+            buf:
+                .zero 16
+            stat:
+                .zero 128
+        ";
+
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        let mut syscalls = Syscalls::new(&std::env::temp_dir(), 0x1000).unwrap();
+        syscalls.stdin = Box::new(Cursor::new(b"input".to_vec()));
+        cpu.syscalls = Some(syscalls);
+
+        let args = ["prog".to_string(), "xyz".to_string()];
+        syscall::setup_stack(&mut cpu, 0x10000, &args).unwrap();
+        assert_eq!(cpu.regs[2] % 16, 0);
+
+        assert_eq!(cpu.run(), 3);
+        assert_eq!(cpu.regs[8], 2);
+        assert_eq!(cpu.regs[9], b'x' as u32);
+        assert_eq!(cpu.regs[18], 5);
+        assert_eq!(cpu.regs[19], b'i' as u32);
+        assert_eq!(cpu.regs[20] & 0o170000, 0o020000); // S_IFCHR
+        assert_eq!(cpu.regs[21], -29i32 as u32); // ESPIPE
+    }
+
+    #[test]
+    fn test_semihosting() {
+        let source = "
+                li a0, 0x04         # SYS_WRITE0
+                la a1, greeting
+                call semihost
+
+                li a0, 0x01         # SYS_OPEN(\"out.txt\", \"w\")
+                la a1, open
+                call semihost
+                mv s0, a0
+                la t0, write
+                sw s0, 0(t0)
+                li a0, 0x05         # SYS_WRITE
+                la a1, write
+                call semihost
+                mv s1, a0
+                li t0, 0xfffe       # SYS_WRITE of 16 bytes, 2 before the end of RAM
+                li t1, 0x6b6f
+                sh t1, 0(t0)
+                la t0, tail
+                sw s0, 0(t0)
+                li a0, 0x05
+                la a1, tail
+                call semihost
+                mv s7, a0
+                li a0, 0x05         # SYS_WRITE to a handle that isn't open
+                la a1, unopened
+                call semihost
+                mv s8, a0
+                li a0, 0x02         # SYS_CLOSE
+                la a1, write
+                call semihost
+
+                li a0, 0x01         # SYS_OPEN(\"out.txt\", \"r\")
+                la a1, reopen
+                call semihost
+                la t0, read
+                sw a0, 0(t0)
+                li a0, 0x06         # SYS_READ of as much as there is
+                la a1, read
+                call semihost
+                mv s6, a0
+
+                li a0, 0x15         # SYS_GET_CMDLINE
+                la a1, cmdline
+                call semihost
+                la t0, cmdline
+                lw s2, 4(t0)
+                lw t0, 0(t0)
+                lbu s3, 0(t0)
+
+                li a0, 0x0E         # SYS_REMOVE of a missing file
+                la a1, remove
+                call semihost
+                mv s4, a0
+                li a0, 0x13         # SYS_ERRNO
+                call semihost
+                mv s5, a0
+
+                li a0, 0x20         # SYS_EXIT_EXTENDED(ApplicationExit, 7)
+                la a1, exit
+                call semihost
+
+            semihost:
+                slli x0, x0, 0x1f
+                ebreak
+                srai x0, x0, 7
+                ret
+
+            greeting:
+                .asciz \"hi\\n\"
+            name:
+                .asciz \"out.txt\"
+            missing:
+                .asciz \"missing\"
+            buffer:
+                .zero 32
+                .p2align 2
+            open:
+                .word name, 4, 7
+            write:
+                .word 0, greeting, 3
+            tail:
+                .word 0, 0xfffe, 16
+            unopened:
+                .word 9, greeting, -1
+            reopen:
+                .word name, 0, 7
+            read:
+                .word 0, buffer, -1
+            cmdline:
+                .word buffer, 32
+            remove:
+                .word missing, 7
+            exit:
+                .word 0x20026, 7
+        ";
+
+        let sandbox = scratch_dir("semihosting");
+        let stdout = SharedBuffer::default();
+        let mut semihosting = Semihosting::new(&sandbox, "prog arg").unwrap();
+        semihosting.stdout = Box::new(stdout.clone());
+
+        let mut cpu = init_cpu_test();
+        cpu.semihosting = Some(semihosting);
+        cpu.load(&asm::assemble(source, 0).unwrap());
+
+        assert_eq!(cpu.run(), 7);
+        assert_eq!(stdout.0.borrow().as_slice(), b"hi\n");
+        assert_eq!(fs::read(sandbox.join("out.txt")).unwrap(), b"hi\nok");
+        assert_eq!(cpu.regs[8], 1); // Handles are nonzero
+        assert_eq!(cpu.regs[9], 0); // Nothing left unwritten
+        assert_eq!(cpu.regs[18], 8);
+        assert_eq!(cpu.regs[19], b'p' as u32);
+        assert_eq!(cpu.regs[20], u32::MAX);
+        assert_eq!(cpu.regs[21], 2); // ENOENT
+        assert_eq!(cpu.regs[22], u32::MAX - 5); // Bytes not read
+        assert_eq!(cpu.regs[23], 14); // Bytes not written
+        assert_eq!(cpu.regs[24], u32::MAX); // EBADF
+
+        // Without the surrounding shifts, EBREAK is still a breakpoint.
+        let mut cpu = init_cpu_test();
+        cpu.semihosting = Some(Semihosting::new(&sandbox, "").unwrap());
+        cpu.load(&asm::assemble("ebreak", 0).unwrap());
+        assert_eq!(cpu.step(), Err(Exception::Breakpoint(0)));
+
+        fs::remove_dir_all(sandbox).unwrap();
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let source = "
+                li t0, 0x100
+                li a0, 1
+            loop:
+                addi a0, a0, 1
+                sw a0, 0(t0)
+                li t1, 5
+                bne a0, t1, loop
+                nop
+        ";
+
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut gdb = GdbClient(stream);
+            assert!(gdb
+                .request("qSupported:swbreak+")
+                .contains("qXfer:features:read+"));
+            let xml = gdb.request("qXfer:features:read:target.xml:0,ffff");
+            assert!(xml.starts_with('l') && xml.contains("<architecture>riscv:rv32"));
+            assert_eq!(gdb.request("g").len(), 33 * 8);
+
+            let script = [
+                ("?", "S05"),
+                ("s", "S05"),
+                ("p20", "04000000"),  // pc
+                ("p5", "00010000"),   // t0
+                ("p382", "00000000"), // mepc
+                ("Z0,c,4", "OK"),
+                ("mc,4", "23a0a200"), // sw a0, 0(t0), not the EBREAK
+                ("c", "T05swbreak:;"),
+                ("p20", "0c000000"),
+                ("z0,c,4", "OK"),
+                ("Z2,100,4", "OK"),
+                ("c", "T05watch:100;"),
+                ("p20", "10000000"),
+                ("m100,4", "02000000"),
+                ("z2,100,4", "OK"),
+                ("M100,4:78563412", "OK"),
+                ("m100,4", "78563412"),
+                ("Pa=03000000", "OK"),
+                ("Z1,14,4", "OK"),
+                ("c", "T05hwbreak:;"),
+                ("pa", "03000000"),
+                ("z1,14,4", "OK"),
+                ("mffff0000,4", "E01"),
+                ("vMustReplyEmpty", ""),
+                ("c", "W00"),
+            ];
+            for (request, reply) in script {
+                assert_eq!(gdb.request(request), reply, "reply to {}", request);
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let session = GdbStub::new(&mut cpu).serve(stream).unwrap();
+        client.join().unwrap();
+
+        assert_eq!(session, Session::Exited(0));
+        assert_eq!(cpu.bus.read(0x100, 4), Ok(5));
+        assert_eq!(cpu.bus.read(0xc, 4), Ok(0x00a2a023));
+        assert!(!cpu.ebreak_halts);
+        assert!(gdb::target_xml()
+            .contains("<reg name=\"mstatus\" bitsize=\"32\" type=\"int\" regnum=\"833\""));
+    }
+
+    #[test]
+    fn test_monitor() {
+        let source = "
+                li a0, 0
+                li t0, 0x100
+            loop:
+                addi a0, a0, 1
+                sw a0, 0(t0)
+                li t1, 3
+                bne a0, t1, loop
+                nop
+        ";
+
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        cpu.symbols = SymbolTable::new(vec![Symbol {
+            name: "loop".to_string(),
+            addr: 8,
+            size: 16,
+        }]);
+
+        let script = scratch_dir("monitor").join("script.txt");
+        fs::write(&script, "# Stop in the loop\nbreak loop+4\ncontinue\n").unwrap();
+
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(&mut cpu);
+        let script = script.to_str().unwrap();
+        assert_eq!(monitor.source(script, &mut output).unwrap(), Flow::Continue);
+
+        let input = "step 2\n\nreg a0\nreg a1 -2\nreg mscratch 0x55\nbreak\n!2\nmem 0x100 4\n\
+                     poke 0x100 0x41 1\nmem 0x100 4\ndelete loop+4\ndelete 0x30\nlast\n\
+                     disas loop 1\ndisas 0 0x40000000\nhistory\nbogus\nc\nstep\nquit\nreg pc\n";
+        monitor
+            .repl(&mut Cursor::new(input), &mut output, false)
+            .unwrap();
+
+        let expected = "\
+Breakpoint at 0x0000000c <loop+4>
+Stopped at breakpoint 0x0000000c <loop+4>
+0000000c:  00a2a023  sw a0, 0(t0)
+0000000c:  00a2a023  sw a0, 0(t0)
+00000010:  00300313  addi t1, zero, 3
+00000014:  fe651ae3  bne a0, t1, 0x8
+loop:
+00000008:  00150513  addi a0, a0, 1
+a0 = 0x00000002 (2)
+a1 = 0xfffffffe (-2)
+mscratch = 0x00000055 (85)
+0x0000000c <loop+4>
+a0 = 0x00000002 (2)
+00000100:  01 00 00 00                                      ....
+00000100:  41 00 00 00                                      A...
+Deleted breakpoint at 0x0000000c <loop+4>
+No breakpoint at 0x00000030
+Last instruction: addi a0, a0, 1 (00150513)
+   00000004:  10000293  addi t0, zero, 256
+loop:
+   00000008:  00150513  addi a0, a0, 1
+=> 0000000c:  00a2a023  sw a0, 0(t0)
+Invalid count: 0x40000000
+   1  step 2
+   2  reg a0
+   3  reg a1 -2
+   4  reg mscratch 0x55
+   5  break
+   6  reg a0
+   7  mem 0x100 4
+   8  poke 0x100 0x41 1
+   9  mem 0x100 4
+  10  delete loop+4
+  11  delete 0x30
+  12  last
+  13  disas loop 1
+  14  disas 0 0x40000000
+  15  history
+Unknown command: bogus (try help)
+Program exited with status 0
+The program has exited
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        assert_eq!(monitor.history.len(), 19);
+        assert_eq!(cpu.bus.read(0x100, 4), Ok(3));
+        assert_eq!(cpu.exit_code, None);
+
+        fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_tui() {
+        let source = "
+                li a0, 1
+                la a1, msg
+                li a2, 3
+                li a7, 64
+                ecall
+                li a0, 0
+                li a7, 93
+                ecall
+            msg:
+                .ascii \"hi\\n\"
+        ";
+
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        cpu.syscalls = Some(Syscalls::new(&std::env::temp_dir(), 0x1000).unwrap());
+        let mut tui = Tui::new(&mut cpu);
+
+        assert!(tui.key(b's'));
+        let screen = tui.render(24, 80);
+        assert!(screen.starts_with("\x1b[H"));
+        assert_eq!(screen.matches("\r\n").count(), 23);
+        assert!(screen.contains("\x1b[1;33ma0   00000001\x1b[0m")); // Changed
+        assert!(screen.contains("a1   00000000")); // Unchanged, so not highlighted
+        assert!(screen.contains("\x1b[1;32m> 00000004:  00000597  auipc a1, 0x0"));
+
+        // Run to a breakpoint, then on to the end.
+        assert!(tui.key(b's'));
+        assert!(tui.key(b'b'));
+        assert!(tui.breakpoints.contains(&8));
+        tui.key(b'k');
+        tui.key(b'+');
+        tui.key(b'+');
+        for _ in 0..2 {
+            tui.key(b'r');
+            tui.tick();
+        }
+        let screen = tui.render(30, 100);
+        assert!(screen.contains("Program exited with status 0"));
+        assert!(screen.contains("\nhi "));
+        assert!(screen.contains("fffffff8 -- -- -- -- -- -- -- --"));
+        assert!(tui.render(20, 80).contains("at least 80x24"));
+        assert!(!tui.key(b'q'));
+    }
+
+    #[test]
+    fn test_commit_log() {
+        let source = "
+                li t0, 0x100
+                li a0, -2
+                sw a0, 0(t0)
+                sb a0, 5(t0)
+                lw a1, 0(t0)
+                csrw mscratch, a0
+                csrr a2, mscratch
+                beq a0, a1, done
+                addi zero, zero, 1
+            done:
+                li t1, 0x30
+                csrw mepc, t1
+                mret
+                nop
+        ";
+
+        let log = SharedBuffer::default();
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        cpu.commit_log = Some(CommitLog::new(Box::new(log.clone()), Filter::default()));
+        assert_eq!(cpu.run(), 0);
+
+        let expected = "\
+core   0: 3 0x00000000 (0x10000293) x5  0x00000100
+core   0: 3 0x00000004 (0xffe00513) x10 0xfffffffe
+core   0: 3 0x00000008 (0x00a2a023) mem 0x00000100 0xfffffffe
+core   0: 3 0x0000000c (0x00a282a3) mem 0x00000105 0xfe
+core   0: 3 0x00000010 (0x0002a583) x11 0xfffffffe mem 0x00000100
+core   0: 3 0x00000014 (0x34051073) c832_mscratch 0xfffffffe
+core   0: 3 0x00000018 (0x34002673) x12 0xfffffffe
+core   0: 3 0x0000001c (0x00b50463)
+core   0: 3 0x00000024 (0x03000313) x6  0x00000030
+core   0: 3 0x00000028 (0x34131073) c833_mepc 0x00000030
+core   0: 3 0x0000002c (0x30200073) c768_mstatus 0x00001880
+core   0: 3 0x00000030 (0x00000013)
+";
+        assert_eq!(String::from_utf8(log.0.borrow().clone()).unwrap(), expected);
+
+        // Only the loads and stores, and only two of them.
+        let log = SharedBuffer::default();
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        let filter = Filter {
+            start: 0x8,
+            end: Some(0x14),
+            skip: 3,
+            count: Some(2),
+        };
+        let mut commits = CommitLog::new(Box::new(log.clone()), filter);
+        commits.disasm = true;
+        cpu.commit_log = Some(commits);
+        cpu.run();
+
+        let expected = "\
+core   0: 0x0000000c (0x00a282a3) sb      a0, 5(t0)
+core   0: 3 0x0000000c (0x00a282a3) mem 0x00000105 0xfe
+core   0: 0x00000010 (0x0002a583) lw      a1, 0(t0)
+core   0: 3 0x00000010 (0x0002a583) x11 0xfffffffe mem 0x00000100
+";
+        assert_eq!(String::from_utf8(log.0.borrow().clone()).unwrap(), expected);
+        assert_eq!(
+            commit::format_disasm(0x24, 0x00000013),
+            "core   0: 0x00000024 (0x00000013) nop"
+        );
+    }
+
+    #[test]
+    fn test_difftest() {
+        let source = "
+                li t0, 0x100
+                li a0, -2
+                sw a0, 0(t0)
+                lw a1, 0(t0)
+                csrw mscratch, a0
+                addi a1, a1, 3
+                li t1, 0x24
+                csrw mepc, t1
+                mret
+                nop
+        ";
+        // Spike with -l, widened to RV64 as Spike prints it.
+        let reference = "\
+core   0: 0x0000000000000000 (0x10000293) li      t0, 256
+core   0: 3 0x0000000000000000 (0x10000293) x5  0x0000000000000100
+core   0: 3 0x0000000000000004 (0xffe00513) x10 0xfffffffffffffffe
+core   0: 3 0x0000000000000008 (0x00a2a023) mem 0x0000000000000100 0xfffffffe
+core   0: 3 0x000000000000000c (0x0002a583) x11 0xfffffffffffffffe mem 0x0000000000000100
+core   0: 3 0x0000000000000010 (0x34051073) c832_mscratch 0xfffffffffffffffe
+core   0: 3 0x0000000000000014 (0x00358593) x11 0x0000000000000001
+core   0: 3 0x0000000000000018 (0x02400313) x6  0x0000000000000024
+core   0: 3 0x000000000000001c (0x34131073) c833_mepc 0x0000000000000024
+core   0: 3 0x0000000000000020 (0x30200073) c768_mstatus 0x0000000000001880
+";
+        let commits = difftest::parse_trace(reference).unwrap();
+        assert_eq!(commits.len(), 9);
+        assert_eq!(commits[2].store, Some((0x100, 0xfffffffe, 4)));
+
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        assert_eq!(difftest::compare(&mut cpu, &commits), Ok(9));
+
+        // The reference computes a different sum in the last instruction.
+        let tampered = reference.replace("x11 0x0000000000000001", "x11 0x0000000000000002");
+        let commits = difftest::parse_trace(&tampered).unwrap();
+        let mut cpu = init_cpu_test();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        let divergence = difftest::compare(&mut cpu, &commits).unwrap_err();
+        assert_eq!(divergence.index, 5);
+        assert_eq!(divergence.expected.pc, 0x14);
+        assert_eq!(divergence.actual.as_ref().unwrap().rd, Some((11, 1)));
+        let report = divergence.to_string();
+        assert!(report.starts_with(
+            "Divergence after 5 matching instructions at pc 0x00000014: addi a1, a1, 3\n"
+        ));
+        assert!(report.contains("expected x11 (a1) = 0x00000002, actual x11 (a1) = 0x00000001"));
+        assert!(report.contains("core   0: 3 0x00000010 (0x34051073) c832_mscratch 0xfffffffe"));
+
+        // The reference runs on past the end of the program, where zeroed memory is illegal.
+        let longer = format!(
+            "{}core   0: 3 0x00000024 (0x00000013)\ncore   0: 3 0x00000028 (0x00000013)\n",
+            reference
+        );
+        let commits = difftest::parse_trace(&longer).unwrap();
+        let mut cpu = new_cpu();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        let divergence = difftest::compare(&mut cpu, &commits).unwrap_err();
+        assert_eq!(divergence.index, 10);
+        assert!(divergence
+            .actual
+            .unwrap_err()
+            .contains("Illegal instruction"));
+
+        // Sail puts each effect on a line of its own.
+        let sail = "\
+[1] [M]: 0x80000000 (0x10000293) addi t0, zero, 256
+x5 <- 0x0000000000000100
+[2] [M]: 0x80000004 (0x00a2a023) sw a0, 0(t0)
+mem[0x0000000000000100] <- 0xfffffffe
+[3] [M]: 0x80000008 (0x34051073) csrrw zero, mscratch, a0
+CSR mscratch <- 0x00000000fffffffe
+[4] [U]: 0x8000000c (0x0002a583) lw a1, 0(t0)
+mem[X,0x0000000000000100] -> 0xfffffffe
+x11 <- 0xfffffffffffffffe
+";
+        let commits = difftest::parse_trace(sail).unwrap();
+        assert_eq!(commits.len(), 4);
+        assert_eq!(commits[0].rd, Some((5, 0x100)));
+        assert_eq!(commits[1].store, Some((0x100, 0xfffffffe, 4)));
+        assert_eq!(commits[2].csr, Some((0x340, 0xfffffffe)));
+        assert_eq!(commits[3].privilege, Privilege::User);
+        assert_eq!(commits[3].load, Some(0x100));
+        assert_eq!(commits[3].rd, Some((11, 0xfffffffe)));
+
+        assert_eq!(
+            difftest::parse_trace("core   0: 3 0x0 (0x13) x5 zero")
+                .unwrap_err()
+                .line,
+            1
+        );
+    }
+
+    #[test]
+    fn test_compliance() {
+        // Shaped like riscv-tests' env/p: TESTNUM in gp, and a trap handler that reports
+        // it to tohost when the test ends with ECALL.
+        let test = |sum: u32| {
+            let source = format!(
+                "
+                    la t0, trap
+                    csrw mtvec, t0
+                    li gp, 2
+                    li a0, 5
+                    addi a0, a0, 1
+                    li t1, 0x80001010   # begin_signature
+                    sw a0, 0(t1)
+                    li t2, {}
+                    sw t2, 4(t1)
+                    bne a0, t2, fail
+                    li gp, 1
+                    ecall
+                fail:
+                    slli gp, gp, 1
+                    ori gp, gp, 1
+                    ecall
+                trap:
+                    li t5, 0x80001000   # tohost
+                    sw gp, 0(t5)
+                    j trap
+                ",
+                sum
+            );
+            let code = asm::assemble(&source, 0x8000_0000).unwrap();
+            build_elf(
+                0x8000_0000,
+                &[
+                    (0x8000_0000, code, 0x100),
+                    (0x8000_1000, vec![0; 0x18], 0x18),
+                ],
+                &[
+                    ("tohost", 0x8000_1000, 8),
+                    ("begin_signature", 0x8000_1010, 0),
+                    ("end_signature", 0x8000_1018, 0),
+                ],
+            )
+        };
+
+        let run = |elf: &[u8], max: u64| {
+            let Ok(Image::Elf(elf)) = Image::detect(elf) else {
+                panic!("not an ELF file");
+            };
+            let image = Image::Elf(elf.clone());
+            let mut cpu = cli::machine(&Options::default(), &image).unwrap();
+            compliance::run(&mut cpu, &elf, max)
+        };
+
+        let report = run(&test(6), compliance::MAX_INSTRUCTIONS);
+        assert_eq!(report.outcome, Outcome::Pass);
+        assert_eq!(report.instructions, 16);
+        assert_eq!(report.signature, Some(vec![6, 6]));
+        assert_eq!(
+            compliance::format_signature(&[6, 0xdeadbeef]),
+            "00000006\ndeadbeef\n"
+        );
+
+        let report = run(&test(7), compliance::MAX_INSTRUCTIONS);
+        assert_eq!(report.outcome, Outcome::Fail(2));
+        assert_eq!(report.signature, Some(vec![6, 7]));
+        assert_eq!(run(&test(6), 10).outcome, Outcome::Timeout);
+
+        let no_tohost = build_elf(0x8000_0000, &[(0x8000_0000, vec![0x13; 4], 4)], &[]);
+        assert!(matches!(run(&no_tohost, 10).outcome, Outcome::Error(_)));
+
+        // A directory of tests, with non-ELF files skipped and a signature per test.
+        let dir = scratch_dir("compliance");
+        let signatures = dir.join("signatures");
+        fs::create_dir(&signatures).unwrap();
+        fs::write(dir.join("rv32ui-p-add"), test(6)).unwrap();
+        fs::write(dir.join("rv32ui-p-sub"), test(7)).unwrap();
+        fs::write(dir.join("rv32ui-p-add.dump"), "not a test").unwrap();
+
+        let args =
+            |line: String| -> Vec<String> { line.split_whitespace().map(String::from).collect() };
+        let command = format!(
+            "test --signature {} {}",
+            signatures.display(),
+            dir.display()
+        );
+        assert_eq!(cli::main(&args(command)), 1);
+        assert_eq!(
+            fs::read_to_string(signatures.join("rv32ui-p-sub.signature")).unwrap(),
+            "00000006\n00000007\n"
+        );
+        assert_eq!(fs::read_dir(&signatures).unwrap().count(), 2);
+
+        let signature = dir.join("add.signature");
+        let command = format!(
+            "test --signature {} {}",
+            signature.display(),
+            dir.join("rv32ui-p-add").display()
+        );
+        assert_eq!(cli::main(&args(command)), 0);
+        assert_eq!(
+            fs::read_to_string(signature).unwrap(),
+            "00000006\n00000006\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_uart() {
+        // Print a greeting the way bare-metal code does, waiting on LSR.THRE, then echo
+        // received bytes in upper case until a newline.
+        let source = "
+                li s0, 0x10000000
+                la s1, msg
+            print:
+                lbu t0, 0(s1)
+                beqz t0, echo
+            wait_tx:
+                lbu t1, 5(s0)
+                andi t1, t1, 0x20
+                beqz t1, wait_tx
+                sb t0, 0(s0)
+                addi s1, s1, 1
+                j print
+            echo:
+                lbu t1, 5(s0)
+                andi t1, t1, 1
+                beqz t1, echo
+                lbu t0, 0(s0)
+                li t2, 10
+                beq t0, t2, done
+                addi t0, t0, -32
+                sb t0, 0(s0)
+                j echo
+            done:
+                nop
+            msg:
+                .string \"hi \"
+        ";
+
+        let out = SharedBuffer::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for byte in b"abc\n" {
+            tx.send(*byte).unwrap();
+        }
+        let mut cpu = init_cpu_test();
+        let uart = Uart::new(Box::new(out.clone()), Some(rx));
+        cpu.bus
+            .attach(uart::BASE, uart::SIZE, Box::new(uart))
+            .unwrap();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(out.0.borrow().as_slice(), b"hi ABC");
+
+        // Registers, interrupt identification and loopback, straight from the device.
+        let out = SharedBuffer::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut uart = Uart::new(Box::new(out.clone()), Some(rx));
+        assert_eq!(uart.read(5, 1), Ok(0x60)); // THRE | TEMT
+        assert_eq!(uart.read(2, 1), Ok(0x01)); // No interrupt
+        assert!(!uart.interrupt());
+
+        uart.write(2, 1, 0x07).unwrap(); // Enable and clear the FIFOs
+        uart.write(1, 1, 0x03).unwrap(); // RDI and THRI
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(2, 1), Ok(0xC2)); // THRE, acknowledged by the read
+        assert_eq!(uart.read(2, 1), Ok(0xC1));
+
+        tx.send(b'x').unwrap();
+        assert_eq!(uart.read(5, 1), Ok(0x61));
+        assert_eq!(uart.read(2, 1), Ok(0xC4));
+        assert_eq!(uart.read(0, 1), Ok(b'x' as u32));
+        assert_eq!(uart.read(5, 1), Ok(0x60));
+        assert!(!uart.interrupt());
+
+        uart.write(0, 1, b'!' as u32).unwrap();
+        assert_eq!(uart.read(2, 1), Ok(0xC2));
+        assert_eq!(out.0.borrow().as_slice(), b"!");
+
+        // The divisor latch shadows RBR and IER while LCR.DLAB is set.
+        uart.write(3, 1, 0x83).unwrap();
+        uart.write(0, 1, 0x01).unwrap();
+        uart.write(1, 1, 0x00).unwrap();
+        assert_eq!(uart.read(0, 1), Ok(0x01));
+        uart.write(3, 1, 0x03).unwrap();
+        assert_eq!(uart.read(1, 1), Ok(0x03));
+
+        // In loopback, output comes back in and MCR drives the modem status.
+        uart.write(4, 1, 0x1B).unwrap();
+        uart.write(0, 1, b'L' as u32).unwrap();
+        assert_eq!(uart.read(6, 1), Ok(0xB0));
+        assert_eq!(uart.read(0, 1), Ok(b'L' as u32));
+        assert_eq!(out.0.borrow().as_slice(), b"!");
+
+        uart.write(7, 1, 0x5A).unwrap();
+        assert_eq!(uart.read(7, 1), Ok(0x5A));
+        assert_eq!(uart.read(8, 1), Err(BusError::BadAccess));
+
+        // On the terminal, Ctrl-A Ctrl-A sends one Ctrl-A and other escapes are dropped.
+        let mut keys = String::new();
+        cli::ConsoleInput::new(&b"a\x01\x01b\x01zc\x03"[..])
+            .read_to_string(&mut keys)
+            .unwrap();
+        assert_eq!(keys, "a\x01bc\x03");
+    }
+
+    #[test]
+    fn test_clint() {
+        // A software interrupt first, then three timer interrupts 100 ticks apart, through
+        // a vectored mtvec. s0 counts trips around the idle loop while the timer runs.
+        let source = "
+                li s2, 0x02000000       # msip
+                li s3, 0x02004000       # mtimecmp
+                la t0, vectors
+                ori t0, t0, 1
+                csrw mtvec, t0
+                li t0, 0x88             # MSIE | MTIE
+                csrw mie, t0
+                csrsi mstatus, 8
+                li t0, 1
+                sw t0, 0(s2)
+                mv s4, a1               # a1 is set by the software interrupt handler
+                csrr t0, time
+                addi t0, t0, 100
+                sw zero, 4(s3)
+                sw t0, 0(s3)
+            idle:
+                wfi
+                addi s0, s0, 1
+                li t0, 3
+                bne s1, t0, idle
+                nop
+                .p2align 4
+            vectors:
+                j unexpected
+                j unexpected
+                j unexpected
+                j software
+                j unexpected
+                j unexpected
+                j unexpected
+                j timer
+            unexpected:
+                ebreak
+            software:
+                sw zero, 0(s2)
+                csrr a1, mcause
+                mret
+            timer:
+                addi s1, s1, 1
+                lw t0, 0(s3)
+                addi t0, t0, 100
+                sw t0, 0(s3)
+                mret
+        ";
+
+        let mut cpu = init_cpu_test();
+        let clint = Clint::new(cpu.lines.clone(), Timebase::Instructions);
+        cpu.bus
+            .attach(clint::BASE, clint::SIZE, Box::new(clint))
+            .unwrap();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        assert_eq!(cpu.run(), 0);
+
+        assert_eq!(cpu.regs[20], 0x8000_0003); // s4: mcause of the software interrupt
+        assert_eq!(cpu.regs[9], 3); // s1
+        assert_eq!(cpu.csrs.mcause, 0x8000_0007);
+        assert_eq!(cpu.csrs.mip & csr::MIP_MSIP, 0);
+        assert!(cpu.csrs.time > 300);
+        // mtime counts retired instructions, not the steps that take an interrupt.
+        assert_eq!(cpu.csrs.time, cpu.csrs.minstret);
+        // Three 100-tick periods, less the handlers, at four instructions a trip.
+        assert!((65..=75).contains(&cpu.regs[8]), "s0 = {}", cpu.regs[8]);
+
+        // With MIE clear, interrupts stay pending in mip but are not taken.
+        let mut cpu = init_cpu_test();
+        let mut clint = Clint::new(cpu.lines.clone(), Timebase::Instructions);
+        clint.write(0x4000, 4, 2).unwrap();
+        clint.write(0x4004, 4, 0).unwrap();
+        cpu.bus
+            .attach(clint::BASE, clint::SIZE, Box::new(clint))
+            .unwrap();
+        cpu.csrs.mtvec = 0x100;
+        cpu.csrs.mie = csr::MIP_MTIP;
+        cpu.load(&[0x13, 0, 0, 0].repeat(4));
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.csrs.mip, csr::MIP_MTIP);
+        assert_eq!(cpu.csrs.time, 4);
+        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.bus.read(0x0200_BFF8, 4), Ok(4));
+        assert_eq!(cpu.bus.read(0x0200_BFF8, 2), Err(BusError::BadAccess));
+
+        // Writing mtime moves the timer; the wall clock keeps counting from there.
+        let lines = Lines::default();
+        let mut clint = Clint::new(lines.clone(), Timebase::WallClock);
+        clint.write(0xBFFC, 4, 1).unwrap();
+        clint.tick();
+        assert!(lines.mtime().unwrap() >= 1 << 32);
+        assert_eq!(lines.mip(), 0);
+    }
+
+    #[test]
+    fn test_plic() {
+        // Interrupt-driven echo: every received byte interrupts through the PLIC, and the
+        // handler claims it, copies the byte to the UART and completes the claim.
+        let source = "
+                li s0, 0x10000000       # UART
+                li s2, 0x0C000000       # PLIC
+                li t0, 1
+                sw t0, 40(s2)           # priority[10] = 1
+                li t1, 0x2000
+                add t1, s2, t1
+                li t0, 0x400
+                sw t0, 0(t1)            # context 0 enables source 10
+                li t1, 0x200000
+                add s3, s2, t1          # context 0 threshold and claim
+                sw zero, 0(s3)
+                li t0, 1
+                sb t0, 1(s0)            # IER = received data
+                la t0, handler
+                csrw mtvec, t0
+                li t0, 0x800            # MEIE
+                csrw mie, t0
+                csrsi mstatus, 8
+            spin:
+                li t0, 3
+                bne s1, t0, spin
+                nop
+            handler:
+                lw t0, 4(s3)            # claim
+                mv s4, t0
+                lbu t1, 0(s0)
+                sb t1, 0(s0)
+                addi s1, s1, 1
+                sw t0, 4(s3)            # complete
+                mret
+        ";
+
+        let out = SharedBuffer::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut cpu = init_cpu_test();
+        let plic = Plic::new(cpu.lines.clone(), 32);
+        let mut uart = Uart::new(Box::new(out.clone()), Some(rx));
+        uart.connect(plic.source(uart::IRQ).unwrap());
+        cpu.bus
+            .attach(uart::BASE, uart::SIZE, Box::new(uart))
+            .unwrap();
+        cpu.bus
+            .attach(plic::BASE, plic::SIZE, Box::new(plic))
+            .unwrap();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+
+        for _ in 0..200 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.regs[9], 0);
+        for byte in b"xyz" {
+            tx.send(*byte).unwrap();
+        }
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(out.0.borrow().as_slice(), b"xyz");
+        assert_eq!(cpu.regs[20], uart::IRQ);
+        assert_eq!(cpu.csrs.mcause, 0x8000_000B);
+        assert_eq!(cpu.csrs.mip, 0);
+
+        // Priority, threshold and the claim/complete handshake, straight from the device.
+        let lines = Lines::default();
+        let mut plic = Plic::new(lines.clone(), 40);
+        let (a, b, c): (Irq, Irq, Irq) = (
+            plic.source(3).unwrap(),
+            plic.source(5).unwrap(),
+            plic.source(33).unwrap(),
+        );
+        assert!(plic.source(0).is_none() && plic.source(41).is_none());
+        for (source, priority) in [(3, 2), (5, 2), (33, 6)] {
+            plic.write(source * 4, 4, priority).unwrap();
+        }
+        plic.write(0x2000, 4, u32::MAX).unwrap();
+        plic.write(0x2004, 4, u32::MAX).unwrap();
+        assert_eq!(plic.read(0x2000, 4), Ok(!1));
+        assert_eq!(plic.read(0x2004, 4), Ok(0x1FF)); // Sources 32 to 40
+
+        a.set(true);
+        b.set(true);
+        c.set(true);
+        plic.tick();
+        assert_eq!(plic.read(0x1000, 4), Ok(0b101000));
+        assert_eq!(lines.mip(), csr::MIP_MEIP);
+
+        // Above the threshold of 5 only source 33 interrupts.
+        plic.write(0x20_0000, 4, 5).unwrap();
+        assert_eq!(plic.read(0x20_0004, 4), Ok(33));
+        assert_eq!(lines.mip(), 0);
+        assert_eq!(plic.read(0x20_0004, 4), Ok(0));
+
+        // Equal priorities go to the lower source, and a line still raised at completion
+        // is pending again.
+        plic.write(0x20_0000, 4, 0).unwrap();
+        assert_eq!(plic.read(0x20_0004, 4), Ok(3));
+        assert_eq!(plic.read(0x20_0004, 4), Ok(5));
+        b.set(false);
+        plic.write(0x20_0004, 4, 3).unwrap();
+        plic.write(0x20_0004, 4, 5).unwrap();
+        plic.write(0x20_0004, 4, 33).unwrap();
+        assert_eq!(plic.read(0x1000, 4), Ok(0b1000));
+        assert_eq!(plic.read(0x1004, 4), Ok(0b10));
+        assert_eq!(plic.read(0x20_0004, 4), Ok(33));
+        assert_eq!(plic.read(0x20_0000, 2), Err(BusError::BadAccess));
+    }
+
+    #[test]
+    fn test_framebuffer() {
+        // Draw red, green, blue and white in RGB565, present, then wait for a vertical
+        // blank and present a palette image.
+        let source = "
+                li s0, 0x30000000
+                li s1, 0x30100000
+                li t0, 0xF800
+                sh t0, 0(s1)
+                li t0, 0x07E0
+                sh t0, 2(s1)
+                li t0, 0x001F
+                sh t0, 4(s1)
+                li t0, 0xFFFF
+                sh t0, 6(s1)
+                li t0, 1
+                sw t0, 16(s0)           # present
+                li t0, 2
+                sw t0, 8(s0)            # indexed
+                lw s2, 12(s0)           # stride
+                li t0, 0x123456
+                sw t0, 0x404(s0)        # palette[1]
+                li t0, 0x01000100
+                sw t0, 0(s1)
+            wait:
+                lw t0, 20(s0)
+                beqz t0, wait
+                li t0, 1
+                sw t0, 16(s0)
+                lw s3, 24(s0)           # frames presented
+                nop
+        ";
+
+        let dir = scratch_dir("framebuffer");
+        let view = SharedBuffer::default();
+        let mut cpu = init_cpu_test();
+        let mut fb = Framebuffer::new(2, 2);
+        fb.dump = Some((dir.clone(), DumpFormat::Ppm));
+        fb.view = Some(Box::new(view.clone()));
+        cpu.bus
+            .attach(framebuffer::BASE, framebuffer::SIZE, Box::new(fb))
+            .unwrap();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[18], 2); // s2: one byte a pixel
+        assert_eq!(cpu.regs[19], 2); // s3
+        assert!(cpu.csrs.minstret > framebuffer::TICKS_PER_FRAME);
+
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let mut ppm = b"P6\n2 2\n255\n".to_vec();
+        ppm.extend_from_slice(&rgb);
+        assert_eq!(fs::read(dir.join("frame-00001.ppm")).unwrap(), ppm);
+
+        let indexed = [0, 0, 0, 0x12, 0x34, 0x56, 0, 0, 0, 0x12, 0x34, 0x56];
+        let mut ppm = b"P6\n2 2\n255\n".to_vec();
+        ppm.extend_from_slice(&indexed);
+        assert_eq!(fs::read(dir.join("frame-00002.ppm")).unwrap(), ppm);
+
+        // The terminal view draws two pixel rows in each line of half blocks.
+        let view = String::from_utf8(view.0.borrow().clone()).unwrap();
+        assert!(view.starts_with("\x1b[H\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}"));
+        assert_eq!(view.matches("\r\n").count(), 2);
+
+        // PNGs hold the rows uncompressed in stored deflate blocks.
+        let png = framebuffer::encode_png(2, 2, &rgb);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x02\x08\x02"));
+        let scanlines = [&[0][..], &rgb[..6], &[0], &rgb[6..]].concat();
+        let idat = png.windows(scanlines.len()).position(|w| w == scanlines);
+        assert_eq!(idat, Some(8 + 25 + 8 + 2 + 5));
+        assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+
+        let mut fb = Framebuffer::new(2, 2);
+        assert_eq!(fb.write(8, 4, 3), Err(BusError::BadAccess));
+        assert_eq!(fb.read(framebuffer::VRAM + 16, 1), Err(BusError::BadAccess));
+        assert!(!Framebuffer::fits(0, 10) && !Framebuffer::fits(4096, 4096));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sound() {
+        // Queue two PCM samples, then play a 441 Hz square wave, one period every 100
+        // samples, until 200 samples have gone out.
+        let source = "
+                li s0, 0x31000000
+                li s1, 0x31001000
+                li t0, 0x4000
+                sh t0, 0(s1)
+                li t0, -0x4000
+                sh t0, 2(s1)
+                li t0, 2
+                sw t0, 0x44(s0)         # PCM_WRITE
+                li t0, 441
+                sw t0, 0(s0)            # square FREQ
+                li t0, 255
+                sw t0, 4(s0)            # VOLUME
+                li t0, 0x201            # 50% duty, enabled
+                sw t0, 12(s0)
+            wait:
+                lw t0, 0x4C(s0)
+                li t1, 200
+                blt t0, t1, wait
+                lw s2, 0x40(s0)         # PCM_READ
+                nop
+        ";
+
+        let dir = scratch_dir("sound");
+        let path = dir.join("square.wav");
+        let wav = WavWriter::new(Box::new(fs::File::create(&path).unwrap())).unwrap();
+        let mut cpu = init_cpu_test();
+        cpu.bus
+            .attach(sound::BASE, sound::SIZE, Box::new(Sound::new(Some(wav))))
+            .unwrap();
+        cpu.load(&asm::assemble(source, 0).unwrap());
+        assert_eq!(cpu.run(), 0);
+        assert_eq!(cpu.regs[18], 2); // s2: the ring has drained
+        assert!(cpu.bus.finish().is_empty());
+
+        let read_wav = |path: &PathBuf| {
+            let data = fs::read(path).unwrap();
+            assert_eq!(&data[..4], b"RIFF");
+            assert_eq!(&data[8..16], b"WAVEfmt ");
+            assert_eq!(
+                u32::from_le_bytes(data[24..28].try_into().unwrap()),
+                sound::SAMPLE_RATE
+            );
+            let length = u32::from_le_bytes(data[40..44].try_into().unwrap()) as usize;
+            assert_eq!(data.len(), 44 + length);
+            assert_eq!(
+                u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize,
+                36 + length
+            );
+            data[44..]
+                .chunks(2)
+                .map(|s| i16::from_le_bytes([s[0], s[1]]))
+                .collect::<Vec<i16>>()
+        };
+
+        let samples = read_wav(&path);
+        assert!(samples.len() >= 200);
+        // A full-scale source is a quarter of the output range.
+        assert_eq!(&samples[..4], &[12287, 4095, 8191, 8191]);
+        assert_eq!(samples[75], -8191);
+        assert_eq!(samples[125], 8191);
+
+        // A triangle with a 10 ms envelope fades out after 441 samples; noise covers both
+        // halves of the range.
+        let path = dir.join("tones.wav");
+        let wav = WavWriter::new(Box::new(fs::File::create(&path).unwrap())).unwrap();
+        let mut sound = Sound::new(Some(wav));
+        for (reg, value) in [(0x10, 441), (0x14, 255), (0x18, 10), (0x1C, 1)] {
+            sound.write(reg, 4, value).unwrap();
+        }
+        for _ in 0..500 * sound::TICKS_PER_SAMPLE {
+            sound.tick();
+        }
+        for (reg, value) in [(0x10, 0), (0x20, 20000), (0x24, 255), (0x2C, 1)] {
+            sound.write(reg, 4, value).unwrap();
+        }
+        for _ in 0..100 * sound::TICKS_PER_SAMPLE {
+            sound.tick();
+        }
+        assert_eq!(sound.read(0x4C, 4), Ok(600));
+        assert_eq!(sound.read(0x30, 4), Err(BusError::BadAccess));
+        assert_eq!(sound.read(0x1000, 4), Err(BusError::BadAccess));
+        drop(sound);
+
+        let samples = read_wav(&path);
+        assert_eq!(samples.len(), 600);
+        assert_eq!(samples[0], -8191);
+        assert!(samples[25] > -100 && samples[25] < 100);
+        assert!(samples[50] > 7000);
+        assert!(samples[150] < samples[50]);
+        assert!(samples[441..500].iter().all(|s| *s == 0));
+        assert!(samples[500..].iter().any(|s| *s > 0) && samples[500..].iter().any(|s| *s < 0));
+
+        // A recording that runs out of room stops, and `finish` says why.
+        let full = Cursor::new(vec![0u8; 48].into_boxed_slice());
+        let mut sound = Sound::new(Some(WavWriter::new(Box::new(full)).unwrap()));
+        for _ in 0..3 * sound::TICKS_PER_SAMPLE {
+            sound.tick();
+        }
+        assert_eq!(sound.read(0x4C, 4), Ok(3));
+        assert!(sound.finish().unwrap_err().contains("sound output"));
+        assert_eq!(sound.finish(), Ok(()));
+
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use crate::csr::Privilege;
+
+// Synchronous exceptions raised by the hart while executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u32), // Misaligned jump or branch target
+    InstructionAccessFault(u32),       // Fetch address outside of memory
+    IllegalInstruction(u32),           // Raw instruction word that failed to decode or execute
+    Breakpoint(u32),                   // Address of the EBREAK
+    LoadAddressMisaligned(u32),        // Misaligned load address
+    LoadAccessFault(u32),              // Load address outside of memory
+    StoreAddressMisaligned(u32),       // Misaligned store address
+    StoreAccessFault(u32),             // Store address outside of memory
+    EnvironmentCall(Privilege),        // Privilege level the ECALL was made from
+}
+
+impl Exception {
+    // Exception code as it would appear in mcause.
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCall(privilege) => 8 + *privilege as u32,
+        }
+    }
+
+    // Trap value as it would appear in mtval.
+    pub fn tval(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::Breakpoint(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAddressMisaligned(addr)
+            | Exception::StoreAccessFault(addr) => *addr,
+            Exception::IllegalInstruction(inst) => *inst,
+            Exception::EnvironmentCall(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exception::InstructionAddressMisaligned(addr) => {
+                write!(f, "Instruction address misaligned: 0x{:08X}", addr)
+            }
+            Exception::InstructionAccessFault(addr) => {
+                write!(f, "Instruction access fault: 0x{:08X}", addr)
+            }
+            Exception::IllegalInstruction(inst) => write!(f, "Illegal instruction: 0x{:08X}", inst),
+            Exception::Breakpoint(addr) => write!(f, "Breakpoint: 0x{:08X}", addr),
+            Exception::LoadAddressMisaligned(addr) => {
+                write!(f, "Load address misaligned: 0x{:08X}", addr)
+            }
+            Exception::LoadAccessFault(addr) => write!(f, "Load access fault: 0x{:08X}", addr),
+            Exception::StoreAddressMisaligned(addr) => {
+                write!(f, "Store address misaligned: 0x{:08X}", addr)
+            }
+            Exception::StoreAccessFault(addr) => write!(f, "Store access fault: 0x{:08X}", addr),
+            Exception::EnvironmentCall(privilege) => {
+                write!(f, "Environment call from {:?} mode", privilege)
+            }
+        }
+    }
+}
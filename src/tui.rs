@@ -0,0 +1,455 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cpu::CPU;
+use crate::disasm::{self, Syntax};
+use crate::isa::REGISTER_NAMES;
+
+// ANSI styles for the panes.
+const RESET: &str = "\x1b[0m";
+const HEADER: &str = "\x1b[7m";
+const CHANGED: &str = "\x1b[1;33m";
+const CURRENT: &str = "\x1b[1;32m";
+const BREAKPOINT: &str = "\x1b[31m";
+
+// The left column holds registers and memory; the right one disassembly and the stack.
+const LEFT_WIDTH: usize = 41;
+const MIN_ROWS: usize = 24;
+const MIN_COLS: usize = 80;
+
+const REGISTER_ROWS: usize = 11;
+const MEMORY_ROWS: usize = 5;
+const BYTES_PER_ROW: u32 = 8;
+
+const FRAME: Duration = Duration::from_millis(30);
+// Frames between size checks; each one starts an stty process.
+const RESIZE_FRAMES: u32 = 33;
+const MAX_SPEED: u32 = 100_000;
+
+const KEYS: &str = "s step  r run/pause  b breakpoint  +/- speed  j/k J/K memory  q quit";
+
+// Guest output shown in the console pane.
+#[derive(Clone, Default)]
+pub struct Console(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Console {
+    // The last `count` lines, with tabs expanded and other control characters dropped.
+    fn tail(&self, count: usize) -> Vec<String> {
+        let text = String::from_utf8_lossy(&self.0.borrow()).into_owned();
+        let lines: Vec<String> = text
+            .lines()
+            .map(|line| {
+                line.replace('\t', "    ")
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .collect()
+            })
+            .collect();
+
+        lines[lines.len().saturating_sub(count)..].to_vec()
+    }
+}
+
+// `text` cut or padded to exactly `width` columns, in `style` if given.
+fn cell(text: &str, width: usize, style: Option<&str>) -> String {
+    let text: String = text.chars().take(width).collect();
+    let padding = " ".repeat(width - text.chars().count());
+
+    match style {
+        Some(style) => format!("{}{}{}{}", style, text, RESET, padding),
+        None => text + &padding,
+    }
+}
+
+fn header(title: &str, width: usize) -> String {
+    cell(&format!(" {}", title), width, Some(HEADER))
+}
+
+// A full-screen view of the CPU that steps and runs on hotkeys.
+pub struct Tui<'a> {
+    cpu: &'a mut CPU,
+    pub console: Console,
+    pub breakpoints: BTreeSet<u32>,
+    pub memory: u32, // First address in the memory pane
+    pub syntax: Syntax,
+    previous: [u32; 32], // Registers before the last step, to highlight what changed
+    running: bool,
+    resumed: bool, // The next instruction runs even if it has a breakpoint
+    speed: u32,    // Instructions per frame while running
+    status: String,
+}
+
+impl<'a> Tui<'a> {
+    // Guest output from system calls and semihosting goes to the console pane; the
+    // terminal's input belongs to the hotkeys, so the guest reads an empty stdin.
+    pub fn new(cpu: &'a mut CPU) -> Self {
+        let console = Console::default();
+
+        if let Some(syscalls) = &mut cpu.syscalls {
+            syscalls.stdin = Box::new(io::empty());
+            syscalls.stdout = Box::new(console.clone());
+            syscalls.stderr = Box::new(console.clone());
+        }
+        if let Some(semihosting) = &mut cpu.semihosting {
+            semihosting.stdin = Box::new(io::empty());
+            semihosting.stdout = Box::new(console.clone());
+            semihosting.stderr = Box::new(console.clone());
+        }
+
+        Tui {
+            previous: cpu.regs,
+            memory: cpu.pc as u32 & !0xF,
+            cpu,
+            console,
+            breakpoints: BTreeSet::new(),
+            syntax: Syntax::default(),
+            running: false,
+            resumed: false,
+            speed: 1,
+            status: "Ready".to_string(),
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.cpu.exit_code.is_some()
+            || (self.cpu.exit_on_nop && self.cpu.last_inst.is_some_and(|inst| inst.is_nop()))
+    }
+
+    fn exit_status(&self) -> String {
+        match self.cpu.exit_code {
+            Some(code) => format!("Program exited with status {}", code),
+            None => "Program finished".to_string(),
+        }
+    }
+
+    // Execute one instruction. Returns false, with the reason in the status line, if
+    // the program cannot go on.
+    fn execute(&mut self) -> bool {
+        if self.finished() {
+            self.status = self.exit_status();
+            return false;
+        }
+
+        let pc = self.cpu.pc as u32;
+        if let Err(e) = self.cpu.step() {
+            self.status = format!("Unhandled exception at 0x{:08x}: {}", pc, e);
+            return false;
+        }
+
+        if self.finished() {
+            self.status = self.exit_status();
+            return false;
+        }
+
+        self.status = format!("Executed 0x{:08x}", pc);
+        true
+    }
+
+    // Handle a key press. Returns false when the user quits.
+    pub fn key(&mut self, key: u8) -> bool {
+        match key {
+            b's' | b' ' => {
+                self.running = false;
+                self.previous = self.cpu.regs;
+                self.execute();
+            }
+            b'r' => {
+                self.running = !self.running;
+                self.resumed = true;
+                self.status = if self.running { "Running" } else { "Paused" }.to_string();
+            }
+            b'b' => {
+                let pc = self.cpu.pc as u32;
+                if !self.breakpoints.remove(&pc) {
+                    self.breakpoints.insert(pc);
+                }
+            }
+            b'+' => self.speed = (self.speed * 10).min(MAX_SPEED),
+            b'-' => self.speed = (self.speed / 10).max(1),
+            b'j' => self.memory = self.memory.wrapping_add(BYTES_PER_ROW),
+            b'k' => self.memory = self.memory.wrapping_sub(BYTES_PER_ROW),
+            b'J' => self.memory = self.memory.wrapping_add(0x100),
+            b'K' => self.memory = self.memory.wrapping_sub(0x100),
+            b'q' | 0x03 => return false,
+            _ => {}
+        }
+
+        true
+    }
+
+    // While running, execute the next frame's worth of instructions.
+    pub fn tick(&mut self) {
+        if !self.running {
+            return;
+        }
+
+        self.previous = self.cpu.regs;
+        for _ in 0..self.speed {
+            let pc = self.cpu.pc as u32;
+            if !self.resumed && self.breakpoints.contains(&pc) {
+                self.status = format!("Stopped at breakpoint 0x{:08x}", pc);
+                self.running = false;
+                return;
+            }
+
+            self.resumed = false;
+            if !self.execute() {
+                self.running = false;
+                return;
+            }
+        }
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let reg = |slot: usize| {
+            if slot == 0 {
+                return cell(&format!("pc   {:08x}", self.cpu.pc), 13, Some(CURRENT));
+            }
+
+            let index = slot - 1;
+            let value = self.cpu.regs[index];
+            let style = (value != self.previous[index]).then_some(CHANGED);
+            cell(
+                &format!("{:<4} {:08x}", REGISTER_NAMES[index], value),
+                13,
+                style,
+            )
+        };
+
+        (0..REGISTER_ROWS)
+            .map(|row| {
+                let slots: Vec<String> = (0..3).map(|col| reg(col * REGISTER_ROWS + row)).collect();
+                slots.join(" ")
+            })
+            .collect()
+    }
+
+    // Instructions around pc, with a few already executed above it.
+    fn disassembly(&mut self, rows: usize, width: usize) -> Vec<String> {
+        let pc = self.cpu.pc as u32;
+        let start = pc.saturating_sub(4 * (rows as u32 / 3));
+
+        (0..rows as u32)
+            .map(|i| {
+                let addr = start.wrapping_add(i * 4);
+                let marker = match (addr == pc, self.breakpoints.contains(&addr)) {
+                    (true, true) => ">*",
+                    (true, false) => "> ",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+                let text = match self.cpu.bus.read(addr, 4) {
+                    Ok(raw) => disasm::line(addr, raw, self.syntax),
+                    Err(_) => format!("{:08x}:  <unmapped>", addr),
+                };
+
+                let style = if addr == pc {
+                    Some(CURRENT)
+                } else if self.breakpoints.contains(&addr) {
+                    Some(BREAKPOINT)
+                } else {
+                    None
+                };
+                cell(&format!("{}{}", marker, text), width, style)
+            })
+            .collect()
+    }
+
+    fn memory(&mut self) -> Vec<String> {
+        (0..MEMORY_ROWS as u32)
+            .map(|row| {
+                let addr = self.memory.wrapping_add(row * BYTES_PER_ROW);
+                let bytes: Vec<Option<u8>> = (0..BYTES_PER_ROW)
+                    .map(|i| {
+                        self.cpu
+                            .bus
+                            .read(addr.wrapping_add(i), 1)
+                            .ok()
+                            .map(|b| b as u8)
+                    })
+                    .collect();
+
+                let hex: Vec<String> = bytes
+                    .iter()
+                    .map(|b| b.map_or("--".to_string(), |b| format!("{:02x}", b)))
+                    .collect();
+                let ascii: String = bytes
+                    .iter()
+                    .map(|b| match b {
+                        Some(b @ 0x20..=0x7e) => *b as char,
+                        _ => '.',
+                    })
+                    .collect();
+                cell(
+                    &format!("{:08x} {} {}", addr, hex.join(" "), ascii),
+                    LEFT_WIDTH,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    fn stack(&mut self, width: usize) -> Vec<String> {
+        let sp = self.cpu.regs[2];
+
+        (0..MEMORY_ROWS as u32)
+            .map(|row| {
+                let addr = sp.wrapping_add(row * 4);
+                let value = match self.cpu.bus.read(addr, 4) {
+                    Ok(value) => format!("{:08x}", value),
+                    Err(_) => "--------".to_string(),
+                };
+                cell(
+                    &format!("sp+{:<3} {:08x}: {}", row * 4, addr, value),
+                    width,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    // The whole screen for a terminal of `rows` by `cols`, drawn from the top left.
+    pub fn render(&mut self, rows: usize, cols: usize) -> String {
+        let mut lines = Vec::new();
+
+        if rows < MIN_ROWS || cols < MIN_COLS {
+            lines.push(format!(
+                "The terminal must be at least {}x{}",
+                MIN_COLS, MIN_ROWS
+            ));
+        } else {
+            let right = cols - LEFT_WIDTH - 1;
+            let title = format!(
+                "RV-801  {}  speed {}/frame",
+                if self.running { "running" } else { "paused" },
+                self.speed
+            );
+            lines.push(cell(&title, cols, Some(HEADER)));
+
+            lines.push(format!(
+                "{} {}",
+                header("Registers", LEFT_WIDTH),
+                header("Disassembly", right)
+            ));
+            let disassembly = self.disassembly(REGISTER_ROWS, right);
+            for (regs, code) in self.registers().into_iter().zip(disassembly) {
+                lines.push(format!("{} {}", regs, code));
+            }
+
+            lines.push(format!(
+                "{} {}",
+                header("Memory", LEFT_WIDTH),
+                header("Stack", right)
+            ));
+            let stack = self.stack(right);
+            for (memory, stack) in self.memory().into_iter().zip(stack) {
+                lines.push(format!("{} {}", memory, stack));
+            }
+
+            // The console takes whatever rows are left above the status and key lines.
+            let console_rows = rows - lines.len() - 3;
+            lines.push(header("Console", cols));
+            let mut console = self.console.tail(console_rows);
+            console.resize(console_rows, String::new());
+            lines.extend(console.iter().map(|line| cell(line, cols, None)));
+
+            lines.push(cell(&self.status, cols, None));
+            lines.push(cell(KEYS, cols, Some(HEADER)));
+        }
+
+        format!("\x1b[H{}\x1b[J", lines.join("\r\n"))
+    }
+
+    // Take over the terminal until the user quits.
+    pub fn run(mut self) -> io::Result<()> {
+        let _terminal = RawTerminal::enter()?;
+        let mut stdin = io::stdin();
+        let mut stdout = io::stdout();
+        let (mut rows, mut cols) = terminal_size();
+        let mut frames = 0u32;
+
+        loop {
+            stdout.write_all(self.render(rows, cols).as_bytes())?;
+            stdout.flush()?;
+
+            let mut keys = [0; 16];
+            let count = stdin.read(&mut keys)?;
+            for key in &keys[..count] {
+                if !self.key(*key) {
+                    return Ok(());
+                }
+            }
+
+            // Pick up a resize about once a second, or at once when a key is pressed.
+            frames = frames.wrapping_add(1);
+            if count > 0 || frames.is_multiple_of(RESIZE_FRAMES) {
+                (rows, cols) = terminal_size();
+            }
+
+            self.tick();
+            thread::sleep(FRAME);
+        }
+    }
+}
+
+pub fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Rows and columns of the terminal, or the minimum if it cannot be asked.
+fn terminal_size() -> (usize, usize) {
+    let size = stty(&["size"]).unwrap_or_default();
+    let mut numbers = size.split_whitespace().filter_map(|n| n.parse().ok());
+
+    match (numbers.next(), numbers.next()) {
+        (Some(rows), Some(cols)) => (rows, cols),
+        _ => (MIN_ROWS, MIN_COLS),
+    }
+}
+
+// Raw, non-blocking input on the alternate screen, put back as it was on drop.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo", "min", "0", "time", "0"])?;
+        print!("\x1b[?1049h\x1b[?25l");
+        Ok(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}